@@ -0,0 +1,2 @@
+ALTER TABLE Item ADD COLUMN attributes TEXT NOT NULL DEFAULT '{}'
+CHECK (json_valid(attributes));
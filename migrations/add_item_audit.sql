@@ -0,0 +1,6 @@
+CREATE TABLE Item_Audit (id INTEGER PRIMARY KEY AUTOINCREMENT,
+item_id INTEGER NOT NULL, action TEXT NOT NULL, actor TEXT NOT NULL,
+changed_at TEXT NOT NULL DEFAULT (datetime('now')),
+old_values TEXT CHECK (old_values IS NULL OR json_valid(old_values)),
+new_values TEXT CHECK (new_values IS NULL OR json_valid(new_values)));
+CREATE INDEX idx_item_audit_item_id ON Item_Audit(item_id);
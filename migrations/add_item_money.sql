@@ -0,0 +1,6 @@
+ALTER TABLE Item ADD COLUMN amount_minor INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE Item ADD COLUMN currency TEXT NOT NULL DEFAULT 'USD';
+-- Rounds half away from zero to the cent. The product is first rounded to 6
+-- decimal places so that binary values just below a half cent, like 1.005,
+-- still round up, the same way as Money::from_f64.
+UPDATE Item SET amount_minor = CAST(round(round(amount * 100, 6)) AS INTEGER) WHERE amount IS NOT NULL;
@@ -0,0 +1,2 @@
+ALTER TABLE Item ADD COLUMN deleted_at TEXT;
+CREATE INDEX idx_item_deleted_at ON Item(deleted_at);
@@ -0,0 +1 @@
+ALTER TABLE Item ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
@@ -0,0 +1,4 @@
+ALTER TABLE Picture ADD COLUMN mime_type TEXT;
+ALTER TABLE Picture ADD COLUMN width INTEGER;
+ALTER TABLE Picture ADD COLUMN height INTEGER;
+ALTER TABLE Picture ADD COLUMN thumbnail BLOB;
@@ -0,0 +1,4 @@
+CREATE TABLE Picture (hash TEXT PRIMARY KEY, data BLOB NOT NULL,
+ref_count INTEGER NOT NULL DEFAULT 0);
+ALTER TABLE Item ADD COLUMN picture_hash TEXT REFERENCES Picture(hash);
+CREATE INDEX idx_item_picture_hash ON Item(picture_hash);
@@ -0,0 +1,4 @@
+CREATE TABLE Slow_Query (id INTEGER PRIMARY KEY AUTOINCREMENT,
+recorded_at TEXT NOT NULL DEFAULT (datetime('now')), operation TEXT NOT NULL,
+statement TEXT NOT NULL, duration_ms REAL NOT NULL, in_transaction BOOLEAN NOT NULL,
+query_plan TEXT NOT NULL, full_scans TEXT NOT NULL);
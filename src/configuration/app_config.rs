@@ -0,0 +1,435 @@
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+use crate::data_services::database_options::{DatabaseOptions, JournalMode, SynchronousLevel};
+use crate::data_services::database_service_error::{DatabaseServiceError, FieldError};
+use crate::data_services::item_validation::ItemValidator;
+use crate::data_services::retry_policy::RetryPolicy;
+
+pub const DEFAULT_CONFIG_FILE: &str = "sqlxusage.toml";
+pub const DEFAULT_PROFILE: &str = "dev";
+const PROFILES: [&str; 3] = ["dev", "test", "prod"];
+// Prefix of the environment variables, e.g. APP_MAX_CONNECTIONS for the
+// max_connections key
+const ENV_PREFIX: &str = "APP_";
+const KEYS: [&str; 22] = [
+    "database_url",
+    "actor",
+    "trash_retention_days",
+    "item_validation_rules",
+    "min_connections",
+    "max_connections",
+    "acquire_timeout_secs",
+    "idle_timeout_secs",
+    "journal_mode",
+    "synchronous",
+    "busy_timeout_ms",
+    "foreign_keys",
+    "create_if_missing",
+    "read_only",
+    "reader_pool",
+    "retry_max_attempts",
+    "retry_initial_backoff_ms",
+    "retry_max_backoff_ms",
+    "retry_deadline_ms",
+    "trace_values",
+    "slow_query_threshold_ms",
+    "slow_query_table"
+];
+
+// Name, without the leading dashes, and value of each flag
+pub type Flags = Vec<(String, String)>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AppConfig {
+    pub profile: String,
+    pub database_url: String,
+    pub database_options: DatabaseOptions,
+    // None for the ones of ItemStorageService
+    pub actor: Option<String>,
+    pub trash_retention: Option<Duration>,
+    pub item_validator: Option<ItemValidator>
+}
+
+// A raw value and where it comes from, for the error messages
+struct ConfigValue {
+    value: String,
+    source: String
+}
+
+impl AppConfig {
+    // Loads the configuration from the file, the environment and the flags
+    // of the command line, and returns it with the arguments that are not
+    // flags. The file is given by --config or APP_CONFIG, DEFAULT_CONFIG_FILE
+    // being optional. It also loads .env into the environment, the variables
+    // it adds being kept apart from the ones set before.
+    pub fn load(args: &[String]) -> Result<(AppConfig, Vec<String>), DatabaseServiceError> {
+        let (flags, arguments) = parse_flags(args)?;
+        let env_vars: HashMap<String, String> = env::vars().collect();
+        dotenv::dotenv().ok();
+        let dotenv_vars: HashMap<String, String> = env::vars().filter(|(name, _)| !env_vars.contains_key(name)).collect();
+        let file_name = flags.iter()
+            .rev()
+            .find(|(name, _)| name == "config")
+            .map(|(_, value)| value.to_owned())
+            .or_else(|| env_vars.get("APP_CONFIG").cloned())
+            .or_else(|| dotenv_vars.get("APP_CONFIG").cloned());
+        let file = match file_name {
+            Some(file_name) => Some((std::fs::read_to_string(&file_name)
+                .map_err(|e| DatabaseServiceError::new(format!("The configuration file {} cannot be read: {}", file_name, e).as_str()))?,
+                                     file_name)),
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => Some((std::fs::read_to_string(DEFAULT_CONFIG_FILE)
+                .map_err(|e| DatabaseServiceError::new(format!("The configuration file {} cannot be read: {}", DEFAULT_CONFIG_FILE, e).as_str()))?,
+                                                                     DEFAULT_CONFIG_FILE.to_owned())),
+            None => None
+        };
+        let config = AppConfig::from_sources(file.as_ref().map(|(contents, name)| (name.as_str(), contents.as_str())),
+                                             &env_vars,
+                                             &dotenv_vars,
+                                             &flags)?;
+        Ok((config, arguments))
+    }
+
+    // Merges the layers, each one overriding the previous ones: the variables
+    // of .env, the top level of the TOML file, its [profiles.<profile>] table,
+    // the environment variables and the flags. .env comes first so that its
+    // DATABASE_URL, meant for development, does not hide the one of the
+    // selected profile. All the missing or invalid keys are reported at once.
+    pub fn from_sources(file: Option<(&str, &str)>,
+                        env_vars: &HashMap<String, String>,
+                        dotenv_vars: &HashMap<String, String>,
+                        flags: &[(String, String)]) -> Result<AppConfig, DatabaseServiceError> {
+        let mut errors = vec![];
+        let table = match file {
+            Some((file_name, contents)) => contents.parse::<toml::Table>()
+                .map_err(|e| DatabaseServiceError::new(format!("The configuration file {} is not valid TOML: {}", file_name, e).as_str()))?,
+            None => toml::Table::new()
+        };
+        let file_name = file.map(|(file_name, _)| file_name).unwrap_or(DEFAULT_CONFIG_FILE);
+        let profile = flags.iter().rev().find(|(name, _)| name == "profile").map(|(_, value)| value.to_owned())
+            .or_else(|| env_vars.get("APP_PROFILE").cloned())
+            .or_else(|| table.get("profile").and_then(|value| value.as_str()).map(|value| value.to_owned()))
+            .or_else(|| dotenv_vars.get("APP_PROFILE").cloned())
+            .unwrap_or_else(|| DEFAULT_PROFILE.to_owned());
+
+        let mut values = BTreeMap::new();
+        add_env_values(&mut values, dotenv_vars, ".env variable");
+        add_table_values(&mut values, &table, file_name, &mut errors);
+        let profiles = match table.get("profiles") {
+            Some(toml::Value::Table(profiles)) => profiles.clone(),
+            Some(_) => {
+                errors.push(config_error("profiles", format!("must be a table in {}", file_name)));
+                toml::Table::new()
+            },
+            None => toml::Table::new()
+        };
+        match profiles.get(profile.as_str()) {
+            Some(toml::Value::Table(profile_table)) =>
+                add_table_values(&mut values, profile_table, format!("{} [profiles.{}]", file_name, profile).as_str(), &mut errors),
+            Some(_) => errors.push(config_error("profiles", format!("{} must be a table in {}", profile, file_name))),
+            None if PROFILES.contains(&profile.as_str()) => (),
+            None => {
+                let mut known_profiles: Vec<&str> = PROFILES.to_vec();
+                known_profiles.extend(profiles.keys().map(|name| name.as_str()).filter(|name| !PROFILES.contains(name)));
+                errors.push(config_error("profile", format!("unknown profile '{}', expected one of {}", profile, known_profiles.join(", "))));
+            }
+        }
+        add_env_values(&mut values, env_vars, "environment variable");
+        for (name, value) in flags.iter().filter(|(name, _)| name != "profile" && name != "config") {
+            let key = name.replace('-', "_");
+            if KEYS.contains(&key.as_str()) {
+                values.insert(key, ConfigValue { value: value.to_owned(), source: format!("flag --{}", name) });
+            } else {
+                errors.push(config_error(format!("--{}", name).as_str(), "is not a known flag".to_owned()));
+            }
+        }
+
+        let database_url = values.get("database_url").map(|value| value.value.to_owned());
+        if database_url.is_none() {
+            errors.push(config_error("database_url",
+                                     format!("is missing, set it in {}, with DATABASE_URL or with --database-url", file_name)));
+        }
+        let defaults = DatabaseOptions::default();
+        let database_options = DatabaseOptions {
+            min_connections: parse_value(&values, "min_connections", "a number", &mut errors).unwrap_or(defaults.min_connections),
+            max_connections: parse_value(&values, "max_connections", "a number", &mut errors).unwrap_or(defaults.max_connections),
+            acquire_timeout: parse_value(&values, "acquire_timeout_secs", "a number of seconds", &mut errors)
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.acquire_timeout),
+            // 0 keeps idle connections open
+            idle_timeout: parse_value(&values, "idle_timeout_secs", "a number of seconds", &mut errors)
+                .map(|secs: u64| (secs > 0).then(|| Duration::from_secs(secs)))
+                .unwrap_or(defaults.idle_timeout),
+            journal_mode: parse_with(&values, "journal_mode", "delete, truncate, persist, memory, wal or off", JournalMode::parse, &mut errors)
+                .or(defaults.journal_mode),
+            synchronous: parse_with(&values, "synchronous", "off, normal, full or extra", SynchronousLevel::parse, &mut errors)
+                .or(defaults.synchronous),
+            busy_timeout: parse_value(&values, "busy_timeout_ms", "a number of milliseconds", &mut errors)
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.busy_timeout),
+            foreign_keys: parse_with(&values, "foreign_keys", "true or false", parse_bool, &mut errors).unwrap_or(defaults.foreign_keys),
+            create_if_missing: parse_with(&values, "create_if_missing", "true or false", parse_bool, &mut errors)
+                .unwrap_or(defaults.create_if_missing),
+            read_only: parse_with(&values, "read_only", "true or false", parse_bool, &mut errors).unwrap_or(defaults.read_only),
+            reader_pool: parse_with(&values, "reader_pool", "true or false", parse_bool, &mut errors).unwrap_or(defaults.reader_pool),
+            retry_policy: RetryPolicy {
+                max_attempts: parse_value(&values, "retry_max_attempts", "a number", &mut errors)
+                    .unwrap_or(defaults.retry_policy.max_attempts),
+                initial_backoff: parse_value(&values, "retry_initial_backoff_ms", "a number of milliseconds", &mut errors)
+                    .map(Duration::from_millis)
+                    .unwrap_or(defaults.retry_policy.initial_backoff),
+                max_backoff: parse_value(&values, "retry_max_backoff_ms", "a number of milliseconds", &mut errors)
+                    .map(Duration::from_millis)
+                    .unwrap_or(defaults.retry_policy.max_backoff),
+                // 0 retries without time limit
+                deadline: parse_value(&values, "retry_deadline_ms", "a number of milliseconds", &mut errors)
+                    .map(|millis: u64| (millis > 0).then(|| Duration::from_millis(millis)))
+                    .unwrap_or(defaults.retry_policy.deadline),
+                ..defaults.retry_policy
+            },
+            trace_values: parse_with(&values, "trace_values", "true or false", parse_bool, &mut errors).unwrap_or(defaults.trace_values),
+            // 0 logs every statement, off disables the slow query log
+            slow_query_threshold: parse_with(&values, "slow_query_threshold_ms", "a number of milliseconds or off", parse_threshold, &mut errors)
+                .unwrap_or(defaults.slow_query_threshold),
+            slow_query_table: parse_with(&values, "slow_query_table", "true or false", parse_bool, &mut errors)
+                .unwrap_or(defaults.slow_query_table)
+        };
+        if let Err(e) = database_options.validate() {
+            errors.push(config_error("database options", e.to_string()));
+        }
+        let trash_retention = parse_with(&values, "trash_retention_days", "a number of days", parse_days, &mut errors);
+        let item_validator = values.get("item_validation_rules").and_then(|value| {
+            ItemValidator::from_json(value.value.as_str())
+                .map_err(|e| errors.push(config_error("item_validation_rules", format!("{} (from {})", e, value.source))))
+                .ok()
+        });
+        if !errors.is_empty() {
+            return Err(DatabaseServiceError::invalid_config(errors));
+        }
+        Ok(AppConfig {
+            profile,
+            database_url: database_url.unwrap_or_default(),
+            database_options,
+            actor: values.get("actor").map(|value| value.value.to_owned()),
+            trash_retention,
+            item_validator
+        })
+    }
+}
+
+// Splits --name=value and --name value flags from the other arguments.
+pub fn parse_flags(args: &[String]) -> Result<(Flags, Vec<String>), DatabaseServiceError> {
+    let mut flags = vec![];
+    let mut arguments = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let name = match arg.strip_prefix("--") {
+            Some(name) => name,
+            None => {
+                arguments.push(arg.to_owned());
+                continue;
+            }
+        };
+        match name.split_once('=') {
+            Some((name, value)) => flags.push((name.to_owned(), value.to_owned())),
+            None => {
+                let value = args.next()
+                    .ok_or_else(|| DatabaseServiceError::invalid_config(vec![config_error(arg, "has no value".to_owned())]))?;
+                flags.push((name.to_owned(), value.to_owned()));
+            }
+        }
+    }
+    Ok((flags, arguments))
+}
+
+// source tells where the variables come from, e.g. ".env variable"
+fn add_env_values(values: &mut BTreeMap<String, ConfigValue>, vars: &HashMap<String, String>, source: &str) {
+    for key in KEYS {
+        // Unprefixed names come first: DATABASE_URL is the sqlx convention,
+        // also used by .env, and ITEM_VALIDATION_RULES predates the
+        // configuration file
+        let mut names = vec![];
+        if key == "database_url" || key == "item_validation_rules" {
+            names.push(key.to_uppercase());
+        }
+        names.push(format!("{}{}", ENV_PREFIX, key.to_uppercase()));
+        for name in names {
+            if let Some(value) = vars.get(&name) {
+                values.insert(key.to_owned(), ConfigValue { value: value.to_owned(), source: format!("{} {}", source, name) });
+            }
+        }
+    }
+}
+
+fn add_table_values(values: &mut BTreeMap<String, ConfigValue>, table: &toml::Table, source: &str, errors: &mut Vec<FieldError>) {
+    for (key, value) in table.iter().filter(|(key, _)| *key != "profile" && *key != "profiles") {
+        if !KEYS.contains(&key.as_str()) {
+            errors.push(config_error(key, format!("is not a known key (in {})", source)));
+            continue;
+        }
+        let value = match value {
+            toml::Value::String(value) => value.to_owned(),
+            // Tables and arrays, e.g. item_validation_rules, are read as JSON
+            toml::Value::Array(_) | toml::Value::Table(_) => serde_json::to_string(value).unwrap_or_default(),
+            value => value.to_string()
+        };
+        values.insert(key.to_owned(), ConfigValue { value, source: source.to_owned() });
+    }
+}
+
+fn parse_value<T: FromStr>(values: &BTreeMap<String, ConfigValue>, key: &str, expected: &str, errors: &mut Vec<FieldError>) -> Option<T> {
+    parse_with(values, key, expected, |value| value.parse().ok(), errors)
+}
+
+fn parse_with<T>(values: &BTreeMap<String, ConfigValue>,
+                 key: &str,
+                 expected: &str,
+                 parse: impl Fn(&str) -> Option<T>,
+                 errors: &mut Vec<FieldError>) -> Option<T> {
+    let value = values.get(key)?;
+    let retval = parse(value.value.trim());
+    if retval.is_none() {
+        errors.push(config_error(key, format!("'{}' from {} is not valid, expected {}", value.value, value.source, expected)));
+    }
+    retval
+}
+
+fn parse_threshold(value: &str) -> Option<Option<Duration>> {
+    if value.eq_ignore_ascii_case("off") {
+        return Some(None);
+    }
+    value.parse().ok().map(|millis| Some(Duration::from_millis(millis)))
+}
+
+// Days so many that their seconds overflow are not valid
+fn parse_days(value: &str) -> Option<Duration> {
+    value.parse::<u64>().ok()?.checked_mul(24 * 60 * 60).map(Duration::from_secs)
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_lowercase().as_str() {
+        "true" | "yes" | "on" | "1" => Some(true),
+        "false" | "no" | "off" | "0" => Some(false),
+        _ => None
+    }
+}
+
+fn config_error(field: &str, message: String) -> FieldError {
+    FieldError {
+        field: field.to_owned(),
+        message
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_services::database_service_error::DatabaseServiceErrorKind;
+
+    const FILE: &str = r#"
+        database_url = "sqlite://dev.db"
+        max_connections = 4
+
+        [profiles.prod]
+        database_url = "sqlite://prod.db"
+        journal_mode = "wal"
+        item_validation_rules = [{ rule = "descr_not_empty" }]
+    "#;
+
+    fn flags(args: &[&str]) -> Flags {
+        parse_flags(&args.iter().map(|arg| arg.to_string()).collect::<Vec<String>>()).unwrap().0
+    }
+
+    fn error_fields(err: DatabaseServiceError) -> Vec<String> {
+        assert_eq!(DatabaseServiceErrorKind::InvalidConfig, err.kind());
+        err.field_errors().iter().map(|e| e.field.to_owned()).collect()
+    }
+
+    #[test]
+    fn from_sources_with_profile_override_file_values() {
+        let config = AppConfig::from_sources(Some(("app.toml", FILE)), &HashMap::new(), &HashMap::new(), &flags(&["--profile", "prod"])).unwrap();
+        assert_eq!("prod", config.profile);
+        assert_eq!("sqlite://prod.db", config.database_url);
+        assert_eq!(4, config.database_options.max_connections);
+        assert_eq!(Some(JournalMode::Wal), config.database_options.journal_mode);
+        assert_eq!(1, config.item_validator.unwrap().get_rules().len());
+    }
+
+    #[test]
+    fn from_sources_with_env_and_flags_override_file() {
+        let env_vars = HashMap::from([
+            ("DATABASE_URL".to_owned(), "sqlite://env.db".to_owned()),
+            ("APP_MAX_CONNECTIONS".to_owned(), "6".to_owned())
+        ]);
+        let config = AppConfig::from_sources(Some(("app.toml", FILE)), &env_vars, &HashMap::new(), &flags(&["--max-connections=8"])).unwrap();
+        assert_eq!("dev", config.profile);
+        assert_eq!("sqlite://env.db", config.database_url);
+        assert_eq!(8, config.database_options.max_connections);
+    }
+
+    #[test]
+    fn from_sources_with_dotenv_give_way_to_file_and_profile() {
+        let dotenv_vars = HashMap::from([
+            ("DATABASE_URL".to_owned(), "sqlite://test.db".to_owned()),
+            ("APP_ACTOR".to_owned(), "dotenv".to_owned())
+        ]);
+        let config = AppConfig::from_sources(Some(("app.toml", FILE)), &HashMap::new(), &dotenv_vars, &flags(&["--profile", "prod"])).unwrap();
+        assert_eq!("sqlite://prod.db", config.database_url);
+        assert_eq!(Some("dotenv".to_owned()), config.actor);
+        let env_vars = HashMap::from([("DATABASE_URL".to_owned(), "sqlite://env.db".to_owned())]);
+        let config = AppConfig::from_sources(None, &env_vars, &dotenv_vars, &[]).unwrap();
+        assert_eq!("sqlite://env.db", config.database_url);
+    }
+
+    #[test]
+    fn from_sources_without_database_url_return_err() {
+        let err = AppConfig::from_sources(None, &HashMap::new(), &HashMap::new(), &[]).unwrap_err();
+        assert_eq!(vec!["database_url"], error_fields(err));
+    }
+
+    #[test]
+    fn from_sources_with_invalid_values_list_all_errors() {
+        let env_vars = HashMap::from([("APP_READ_ONLY".to_owned(), "maybe".to_owned())]);
+        let err = AppConfig::from_sources(Some(("app.toml", "database_url = 'x'\nmax_connection = 3\nsynchronous = 'fast'")),
+                                          &env_vars,
+                                          &HashMap::new(),
+                                          &flags(&["--busy-timeout-ms", "soon", "--colour", "red"])).unwrap_err();
+        assert_eq!("The configuration is invalid: max_connection: is not a known key (in app.toml), \
+                    --colour: is not a known flag, \
+                    synchronous: 'fast' from app.toml is not valid, expected off, normal, full or extra, \
+                    busy_timeout_ms: 'soon' from flag --busy-timeout-ms is not valid, expected a number of milliseconds, \
+                    read_only: 'maybe' from environment variable APP_READ_ONLY is not valid, expected true or false",
+                   err.to_string());
+    }
+
+    #[test]
+    fn from_sources_with_trash_retention_days_return_duration() {
+        let env_vars = HashMap::from([("APP_TRASH_RETENTION_DAYS".to_owned(), "2".to_owned())]);
+        let config = AppConfig::from_sources(Some(("app.toml", FILE)), &env_vars, &HashMap::new(), &[]).unwrap();
+        assert_eq!(Some(Duration::from_secs(2 * 24 * 60 * 60)), config.trash_retention);
+    }
+
+    #[test]
+    fn from_sources_with_overflowing_trash_retention_days_return_err() {
+        let env_vars = HashMap::from([("APP_TRASH_RETENTION_DAYS".to_owned(), u64::MAX.to_string())]);
+        let err = AppConfig::from_sources(Some(("app.toml", FILE)), &env_vars, &HashMap::new(), &[]).unwrap_err();
+        assert_eq!(vec!["trash_retention_days"], error_fields(err));
+    }
+
+    #[test]
+    fn from_sources_with_unknown_profile_return_err() {
+        let err = AppConfig::from_sources(Some(("app.toml", FILE)), &HashMap::new(), &HashMap::new(), &flags(&["--profile=staging"])).unwrap_err();
+        assert_eq!(vec!["profile"], error_fields(err));
+    }
+
+    #[test]
+    fn parse_flags_keep_other_arguments() {
+        let args: Vec<String> = ["show-picture", "--profile", "test", "abc", "--read-only=true"].iter().map(|arg| arg.to_string()).collect();
+        let (flags, arguments) = parse_flags(&args).unwrap();
+        assert_eq!(vec![("profile".to_owned(), "test".to_owned()), ("read-only".to_owned(), "true".to_owned())], flags);
+        assert_eq!(vec!["show-picture", "abc"], arguments);
+        assert!(parse_flags(&["--profile".to_owned()]).is_err());
+    }
+}
@@ -0,0 +1,271 @@
+// Conversions between DatabaseRowValue and types that SQLite has no native
+// storage class for. Apart from JSON, each set is behind the optional
+// feature of the same name. All of them use a representation that stays
+// readable and sortable from the sqlite3 shell:
+//
+// - chrono/time date-times: RFC 3339 TEXT in UTC (unix seconds INTEGER are
+//   also accepted when decoding), dates as 'YYYY-MM-DD' TEXT
+// - uuid: hyphenated lowercase TEXT (16 bytes BLOB also accepted)
+// - rust_decimal: TEXT, so no precision is lost to REAL
+// - serde_json: TEXT holding the serialized document
+use crate::data_services::database_service_base::{conversion_error, DatabaseRowValue, FromDatabaseValue, ToDatabaseValue};
+use crate::data_services::database_service_error::{DatabaseServiceError, DatabaseServiceErrorKind};
+
+fn parse_error(value: &str, target: &str, error: impl std::fmt::Display) -> DatabaseServiceError {
+    DatabaseServiceError::with_kind(DatabaseServiceErrorKind::InvalidConversion,
+                                    format!("the text '{}' cannot be converted to {}: {}", value, target, error).as_str())
+}
+
+#[cfg(feature = "chrono")]
+mod chrono_conversions {
+    use super::*;
+    use chrono::{DateTime, NaiveDate, NaiveDateTime, SecondsFormat, Utc};
+
+    const NAIVE_DATE_TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.f";
+    const NAIVE_DATE_FORMAT: &str = "%Y-%m-%d";
+
+    impl ToDatabaseValue for DateTime<Utc> {
+        fn to_database_value(&self) -> Result<DatabaseRowValue, DatabaseServiceError> {
+            Ok(DatabaseRowValue::Text(self.to_rfc3339_opts(SecondsFormat::AutoSi, true)))
+        }
+    }
+
+    impl FromDatabaseValue for DateTime<Utc> {
+        fn from_database_value(value: &DatabaseRowValue) -> Result<Self, DatabaseServiceError> {
+            match value {
+                DatabaseRowValue::Text(t) => DateTime::parse_from_rfc3339(t)
+                    .map(|d| d.with_timezone(&Utc))
+                    .map_err(|e| parse_error(t, "DateTime<Utc>", e)),
+                DatabaseRowValue::Integer(i) => DateTime::from_timestamp(*i, 0)
+                    .ok_or_else(|| conversion_error(value, "DateTime<Utc>")),
+                _ => Err(conversion_error(value, "DateTime<Utc>"))
+            }
+        }
+    }
+
+    impl ToDatabaseValue for NaiveDateTime {
+        fn to_database_value(&self) -> Result<DatabaseRowValue, DatabaseServiceError> {
+            Ok(DatabaseRowValue::Text(self.format(NAIVE_DATE_TIME_FORMAT).to_string()))
+        }
+    }
+
+    impl FromDatabaseValue for NaiveDateTime {
+        fn from_database_value(value: &DatabaseRowValue) -> Result<Self, DatabaseServiceError> {
+            match value {
+                DatabaseRowValue::Text(t) => NaiveDateTime::parse_from_str(t, NAIVE_DATE_TIME_FORMAT)
+                    .or_else(|_| NaiveDateTime::parse_from_str(t, "%Y-%m-%dT%H:%M:%S%.f"))
+                    .map_err(|e| parse_error(t, "NaiveDateTime", e)),
+                DatabaseRowValue::Integer(i) => DateTime::from_timestamp(*i, 0)
+                    .map(|d| d.naive_utc())
+                    .ok_or_else(|| conversion_error(value, "NaiveDateTime")),
+                _ => Err(conversion_error(value, "NaiveDateTime"))
+            }
+        }
+    }
+
+    impl ToDatabaseValue for NaiveDate {
+        fn to_database_value(&self) -> Result<DatabaseRowValue, DatabaseServiceError> {
+            Ok(DatabaseRowValue::Text(self.format(NAIVE_DATE_FORMAT).to_string()))
+        }
+    }
+
+    impl FromDatabaseValue for NaiveDate {
+        fn from_database_value(value: &DatabaseRowValue) -> Result<Self, DatabaseServiceError> {
+            match value {
+                DatabaseRowValue::Text(t) => NaiveDate::parse_from_str(t, NAIVE_DATE_FORMAT)
+                    .map_err(|e| parse_error(t, "NaiveDate", e)),
+                _ => Err(conversion_error(value, "NaiveDate"))
+            }
+        }
+    }
+}
+
+#[cfg(feature = "time")]
+mod time_conversions {
+    use super::*;
+    use time::format_description::well_known::Rfc3339;
+    use time::macros::format_description;
+    use time::{Date, OffsetDateTime, UtcOffset};
+
+    impl ToDatabaseValue for OffsetDateTime {
+        fn to_database_value(&self) -> Result<DatabaseRowValue, DatabaseServiceError> {
+            self.to_offset(UtcOffset::UTC).format(&Rfc3339)
+                .map(DatabaseRowValue::Text)
+                .map_err(|e| DatabaseServiceError::with_kind(DatabaseServiceErrorKind::InvalidConversion,
+                                                             e.to_string().as_str()))
+        }
+    }
+
+    impl FromDatabaseValue for OffsetDateTime {
+        fn from_database_value(value: &DatabaseRowValue) -> Result<Self, DatabaseServiceError> {
+            match value {
+                DatabaseRowValue::Text(t) => OffsetDateTime::parse(t, &Rfc3339)
+                    .map_err(|e| parse_error(t, "OffsetDateTime", e)),
+                DatabaseRowValue::Integer(i) => OffsetDateTime::from_unix_timestamp(*i)
+                    .map_err(|_| conversion_error(value, "OffsetDateTime")),
+                _ => Err(conversion_error(value, "OffsetDateTime"))
+            }
+        }
+    }
+
+    impl ToDatabaseValue for Date {
+        fn to_database_value(&self) -> Result<DatabaseRowValue, DatabaseServiceError> {
+            self.format(format_description!("[year]-[month]-[day]"))
+                .map(DatabaseRowValue::Text)
+                .map_err(|e| DatabaseServiceError::with_kind(DatabaseServiceErrorKind::InvalidConversion,
+                                                             e.to_string().as_str()))
+        }
+    }
+
+    impl FromDatabaseValue for Date {
+        fn from_database_value(value: &DatabaseRowValue) -> Result<Self, DatabaseServiceError> {
+            match value {
+                DatabaseRowValue::Text(t) => Date::parse(t, format_description!("[year]-[month]-[day]"))
+                    .map_err(|e| parse_error(t, "Date", e)),
+                _ => Err(conversion_error(value, "Date"))
+            }
+        }
+    }
+}
+
+#[cfg(feature = "uuid")]
+mod uuid_conversions {
+    use super::*;
+    use uuid::Uuid;
+
+    impl ToDatabaseValue for Uuid {
+        fn to_database_value(&self) -> Result<DatabaseRowValue, DatabaseServiceError> {
+            Ok(DatabaseRowValue::Text(self.hyphenated().to_string()))
+        }
+    }
+
+    impl FromDatabaseValue for Uuid {
+        fn from_database_value(value: &DatabaseRowValue) -> Result<Self, DatabaseServiceError> {
+            match value {
+                DatabaseRowValue::Text(t) => Uuid::parse_str(t)
+                    .map_err(|e| parse_error(t, "Uuid", e)),
+                DatabaseRowValue::Blob(b) => Uuid::from_slice(b)
+                    .map_err(|_| conversion_error(value, "Uuid")),
+                _ => Err(conversion_error(value, "Uuid"))
+            }
+        }
+    }
+}
+
+#[cfg(feature = "rust_decimal")]
+mod decimal_conversions {
+    use super::*;
+    use rust_decimal::Decimal;
+
+    impl ToDatabaseValue for Decimal {
+        fn to_database_value(&self) -> Result<DatabaseRowValue, DatabaseServiceError> {
+            Ok(DatabaseRowValue::Text(self.to_string()))
+        }
+    }
+
+    impl FromDatabaseValue for Decimal {
+        fn from_database_value(value: &DatabaseRowValue) -> Result<Self, DatabaseServiceError> {
+            match value {
+                DatabaseRowValue::Text(t) => t.parse::<Decimal>()
+                    .map_err(|e| parse_error(t, "Decimal", e)),
+                DatabaseRowValue::Integer(i) => Ok(Decimal::from(*i)),
+                _ => Err(conversion_error(value, "Decimal"))
+            }
+        }
+    }
+}
+
+pub use json_conversions::Json;
+
+mod json_conversions {
+    use super::*;
+    use serde::de::DeserializeOwned;
+    use serde::Serialize;
+
+    impl ToDatabaseValue for serde_json::Value {
+        fn to_database_value(&self) -> Result<DatabaseRowValue, DatabaseServiceError> {
+            Ok(DatabaseRowValue::Text(self.to_string()))
+        }
+    }
+
+    impl FromDatabaseValue for serde_json::Value {
+        fn from_database_value(value: &DatabaseRowValue) -> Result<Self, DatabaseServiceError> {
+            match value {
+                DatabaseRowValue::Text(t) => serde_json::from_str(t)
+                    .map_err(|e| parse_error(t, "JSON", e)),
+                _ => Err(conversion_error(value, "JSON"))
+            }
+        }
+    }
+
+    // Stores any serializable type as a JSON document.
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct Json<T>(pub T);
+
+    impl<T: Serialize> ToDatabaseValue for Json<T> {
+        fn to_database_value(&self) -> Result<DatabaseRowValue, DatabaseServiceError> {
+            serde_json::to_string(&self.0)
+                .map(DatabaseRowValue::Text)
+                .map_err(|e| DatabaseServiceError::with_kind(DatabaseServiceErrorKind::InvalidConversion,
+                                                             e.to_string().as_str()))
+        }
+    }
+
+    impl<T: DeserializeOwned> FromDatabaseValue for Json<T> {
+        fn from_database_value(value: &DatabaseRowValue) -> Result<Self, DatabaseServiceError> {
+            match value {
+                DatabaseRowValue::Text(t) => serde_json::from_str(t)
+                    .map(Json)
+                    .map_err(|e| parse_error(t, "JSON", e)),
+                _ => Err(conversion_error(value, "JSON"))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn chrono_datetime_roundtrip_return_same_value() {
+        let value = chrono::DateTime::parse_from_rfc3339("2024-03-01T12:30:45Z").unwrap().with_timezone(&chrono::Utc);
+        let encoded = value.to_database_value().unwrap();
+        assert_eq!(DatabaseRowValue::Text("2024-03-01T12:30:45Z".to_owned()), encoded);
+        assert_eq!(value, chrono::DateTime::<chrono::Utc>::from_database_value(&encoded).unwrap());
+    }
+
+    #[cfg(feature = "time")]
+    #[test]
+    fn time_offsetdatetime_from_unix_timestamp_return_value() {
+        let value = time::OffsetDateTime::from_database_value(&DatabaseRowValue::Integer(0)).unwrap();
+        assert_eq!(time::OffsetDateTime::UNIX_EPOCH, value);
+    }
+
+    #[cfg(feature = "uuid")]
+    #[test]
+    fn uuid_roundtrip_return_same_value() {
+        let value = uuid::Uuid::parse_str("67e55044-10b1-426f-9247-bb680e5fe0c8").unwrap();
+        let encoded = value.to_database_value().unwrap();
+        assert_eq!(value, uuid::Uuid::from_database_value(&encoded).unwrap());
+        assert_eq!(value, uuid::Uuid::from_database_value(&DatabaseRowValue::Blob(value.as_bytes().to_vec())).unwrap());
+    }
+
+    #[cfg(feature = "rust_decimal")]
+    #[test]
+    fn decimal_roundtrip_keep_precision() {
+        let value: rust_decimal::Decimal = "12345678901234567.89".parse().unwrap();
+        let encoded = value.to_database_value().unwrap();
+        assert_eq!(value, rust_decimal::Decimal::from_database_value(&encoded).unwrap());
+        assert!(rust_decimal::Decimal::from_database_value(&DatabaseRowValue::Real(1.5)).is_err());
+    }
+
+    #[test]
+    fn json_roundtrip_return_same_value() {
+        let value = Json(vec!["red".to_owned(), "blue".to_owned()]);
+        let encoded = value.to_database_value().unwrap();
+        assert_eq!(DatabaseRowValue::Text("[\"red\",\"blue\"]".to_owned()), encoded);
+        assert_eq!(value, Json::<Vec<String>>::from_database_value(&encoded).unwrap());
+    }
+}
@@ -0,0 +1,181 @@
+use std::ffi::CString;
+use std::io;
+use std::os::raw::{c_int, c_void};
+use std::pin::Pin;
+use std::ptr;
+use std::task::{Context, Poll};
+use libsqlite3_sys::{sqlite3_blob, sqlite3_blob_bytes, sqlite3_blob_close, sqlite3_blob_open,
+                     sqlite3_blob_read, sqlite3_blob_write, sqlite3_errmsg, sqlite3_errstr, SQLITE_OK};
+use sqlx::pool::PoolConnection;
+use sqlx::sqlite::Sqlite;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use crate::data_services::database_service_base::DatabaseBlobTrait;
+use crate::data_services::database_service_error::DatabaseServiceError;
+
+// A BLOB value opened with the SQLite incremental blob API. Reads and writes
+// go straight to the database file in chunks instead of loading the whole
+// value in memory. The size of the value is fixed when it is opened: to
+// write a new value, first insert a zeroblob(size) placeholder.
+//
+// The blob owns the pooled connection it was opened on. sqlx only calls into
+// a connection when a query is sent to it, and nothing can be sent to this
+// one while the blob holds it, so the raw handle is never used concurrently.
+//
+// sqlite3_blob_read and sqlite3_blob_write run synchronously in poll_read
+// and poll_write, blocking the executor thread while SQLite does the I/O.
+// Keep the buffers passed to them reasonably small, e.g. the 8 KiB chunks
+// of tokio::io::copy, so that other tasks are not held up for long.
+pub struct DatabaseBlob {
+    blob: *mut sqlite3_blob,
+    size: u64,
+    position: u64,
+    // Kept alive until the blob is closed
+    _connection: PoolConnection<Sqlite>
+}
+
+// The blob handle is only used through &mut self and its connection is not
+// shared, so moving it to another thread is sound.
+unsafe impl Send for DatabaseBlob {}
+
+impl DatabaseBlob {
+    pub async fn open(mut connection: PoolConnection<Sqlite>,
+                      table: &str,
+                      column: &str,
+                      row_id: i64,
+                      writable: bool) -> Result<Self, DatabaseServiceError> {
+        let table_name = CString::new(table)
+            .map_err(|e| DatabaseServiceError::new(e.to_string().as_str()))?;
+        let column_name = CString::new(column)
+            .map_err(|e| DatabaseServiceError::new(e.to_string().as_str()))?;
+        let mut handle = connection.lock_handle().await
+            .map_err(|e| DatabaseServiceError::new(e.to_string().as_str()))?;
+        let mut blob: *mut sqlite3_blob = ptr::null_mut();
+        let db = handle.as_raw_handle().as_ptr();
+        // SAFETY: the handle is locked for the duration of the call and the
+        // names are valid NUL terminated strings.
+        let result = unsafe {
+            sqlite3_blob_open(db,
+                              c"main".as_ptr(),
+                              table_name.as_ptr(),
+                              column_name.as_ptr(),
+                              row_id,
+                              c_int::from(writable),
+                              &mut blob)
+        };
+        if result != SQLITE_OK {
+            // SAFETY: sqlite3_errmsg returns a valid string owned by the
+            // connection, copied before the handle is unlocked.
+            let message = unsafe { std::ffi::CStr::from_ptr(sqlite3_errmsg(db)) }.to_string_lossy().into_owned();
+            // SAFETY: closing a null handle is a no-op, a handle returned
+            // with an error must still be closed.
+            unsafe { sqlite3_blob_close(blob) };
+            return Err(DatabaseServiceError::new(format!("Unable to open the blob {}.{} of row {}: {}",
+                                                         table, column, row_id, message).as_str()));
+        }
+        // SAFETY: blob was successfully opened above.
+        let size = unsafe { sqlite3_blob_bytes(blob) } as u64;
+        drop(handle);
+        Ok(DatabaseBlob {
+            blob,
+            size,
+            position: 0,
+            _connection: connection
+        })
+    }
+
+    fn remaining(&self) -> u64 {
+        self.size - self.position
+    }
+}
+
+impl DatabaseBlobTrait for DatabaseBlob {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn close(mut self: Box<Self>) -> Result<(), DatabaseServiceError> {
+        let blob = std::mem::replace(&mut self.blob, ptr::null_mut());
+        // SAFETY: the blob was opened in open and is not used after this
+        // call, drop skipping the null handle left in its place.
+        let result = unsafe { sqlite3_blob_close(blob) };
+        if result != SQLITE_OK {
+            // SAFETY: sqlite3_errstr returns a static string.
+            let message = unsafe { std::ffi::CStr::from_ptr(sqlite3_errstr(result)) }.to_string_lossy().into_owned();
+            return Err(DatabaseServiceError::new(format!("Unable to close the blob: {}", message).as_str()));
+        }
+        Ok(())
+    }
+}
+
+impl AsyncRead for DatabaseBlob {
+    fn poll_read(self: Pin<&mut Self>,
+                 _cx: &mut Context<'_>,
+                 buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let count = (buf.remaining() as u64).min(this.remaining()) as usize;
+        if count == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        let target = buf.initialize_unfilled_to(count);
+        // SAFETY: target holds count bytes and the range read is inside the
+        // blob since count is bounded by the remaining size.
+        let result = unsafe {
+            sqlite3_blob_read(this.blob,
+                              target.as_mut_ptr() as *mut c_void,
+                              count as c_int,
+                              this.position as c_int)
+        };
+        if result != SQLITE_OK {
+            return Poll::Ready(Err(io::Error::other(format!("Blob read failed with code {}", result))));
+        }
+        buf.advance(count);
+        this.position += count as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for DatabaseBlob {
+    fn poll_write(self: Pin<&mut Self>,
+                  _cx: &mut Context<'_>,
+                  buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let count = (buf.len() as u64).min(this.remaining()) as usize;
+        if count == 0 {
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::WriteZero,
+                                                  format!("The blob is limited to {} bytes", this.size))));
+        }
+        // SAFETY: buf holds at least count bytes and the range written is
+        // inside the blob since count is bounded by the remaining size.
+        let result = unsafe {
+            sqlite3_blob_write(this.blob,
+                               buf.as_ptr() as *const c_void,
+                               count as c_int,
+                               this.position as c_int)
+        };
+        if result != SQLITE_OK {
+            return Poll::Ready(Err(io::Error::other(format!("Blob write failed with code {}", result))));
+        }
+        this.position += count as u64;
+        Poll::Ready(Ok(count))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for DatabaseBlob {
+    fn drop(&mut self) {
+        // SAFETY: the blob was opened in open and is closed only here or in
+        // close, which leaves a null handle, before its connection goes back
+        // to the pool. Closing a null handle is a no-op.
+        unsafe { sqlite3_blob_close(self.blob) };
+    }
+}
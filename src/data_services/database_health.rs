@@ -0,0 +1,92 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use crate::data_services::database_service_error::DatabaseServiceError;
+
+// State of the database as seen by DatabaseService::health, e.g. for the
+// health check of a load balancer
+#[derive(Clone, Debug, PartialEq)]
+pub struct DatabaseHealth {
+    pub reachable: bool,
+    // Round trip of a trivial query, None when unreachable
+    pub ping_duration: Option<Duration>,
+    // Connections currently open by the pool, idle or in use
+    pub pool_size: u32,
+    pub idle_connections: usize,
+    pub in_use_connections: usize,
+    pub max_connections: u32,
+    // None for in-memory databases or when the file cannot be read
+    pub database_file_size: Option<u64>,
+    pub journal_mode: Option<String>,
+    // Most recent error of an operation of the service or of its transactions
+    pub last_error: Option<String>
+}
+
+impl DatabaseHealth {
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "reachable": self.reachable,
+            "ping_ms": self.ping_duration.map(|duration| duration.as_secs_f64() * 1000.0),
+            "pool": {
+                "size": self.pool_size,
+                "idle": self.idle_connections,
+                "in_use": self.in_use_connections,
+                "max": self.max_connections
+            },
+            "database_file_size": self.database_file_size,
+            "journal_mode": self.journal_mode,
+            "last_error": self.last_error
+        })
+    }
+}
+
+impl fmt::Display for DatabaseHealth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.ping_duration {
+            Some(ping_duration) if self.reachable => writeln!(f, "database: reachable ({:.1} ms)", ping_duration.as_secs_f64() * 1000.0)?,
+            _ => writeln!(f, "database: unreachable")?
+        }
+        writeln!(f, "pool: {} open of {} max, {} idle, {} in use",
+                 self.pool_size, self.max_connections, self.idle_connections, self.in_use_connections)?;
+        match self.database_file_size {
+            Some(size) => writeln!(f, "file size: {} bytes", size)?,
+            None => writeln!(f, "file size: unknown")?
+        }
+        writeln!(f, "journal mode: {}", self.journal_mode.as_deref().unwrap_or("unknown"))?;
+        write!(f, "last error: {}", self.last_error.as_deref().unwrap_or("none"))
+    }
+}
+
+// The last error of a service, shared with the transactions it begins
+#[derive(Clone, Default)]
+pub(crate) struct LastError(Arc<Mutex<Option<String>>>);
+
+impl LastError {
+    pub(crate) fn track<T>(&self, result: Result<T, DatabaseServiceError>) -> Result<T, DatabaseServiceError> {
+        if let Err(e) = &result {
+            if let Ok(mut last_error) = self.0.lock() {
+                *last_error = Some(e.to_string());
+            }
+        }
+        result
+    }
+
+    pub(crate) fn get(&self) -> Option<String> {
+        self.0.lock().ok().and_then(|last_error| last_error.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn last_error_track_keep_most_recent_error() {
+        let last_error = LastError::default();
+        assert!(last_error.track(Ok::<u64, DatabaseServiceError>(1)).is_ok());
+        assert_eq!(None, last_error.get());
+        let _ = last_error.clone().track::<()>(Err(DatabaseServiceError::new("database is locked")));
+        let _ = last_error.track(Ok::<u64, DatabaseServiceError>(1));
+        assert_eq!(Some("database is locked".to_owned()), last_error.get());
+    }
+}
@@ -0,0 +1,181 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+use crate::data_services::database_service_error::DatabaseServiceError;
+
+const METRIC_PREFIX: &str = "sqlxusage_db";
+// Upper bounds in seconds, the Prometheus client defaults
+const DURATION_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Default)]
+struct Histogram {
+    // Observations per bucket, the last one being +Inf
+    bucket_counts: [u64; DURATION_BUCKETS.len() + 1],
+    sum: f64,
+    count: u64
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = DURATION_BUCKETS.iter()
+            .position(|upper_bound| seconds <= *upper_bound)
+            .unwrap_or(DURATION_BUCKETS.len());
+        self.bucket_counts[bucket] += 1;
+        self.sum += seconds;
+        self.count += 1;
+    }
+
+    fn render(&self, output: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        let mut cumulative_count = 0;
+        for (upper_bound, bucket_count) in DURATION_BUCKETS.iter().zip(self.bucket_counts) {
+            cumulative_count += bucket_count;
+            let _ = writeln!(output, "{}_bucket{{{}{}le=\"{}\"}} {}", name, labels, separator, upper_bound, cumulative_count);
+        }
+        let _ = writeln!(output, "{}_bucket{{{}{}le=\"+Inf\"}} {}", name, labels, separator, self.count);
+        let labels = if labels.is_empty() { String::new() } else { format!("{{{}}}", labels) };
+        let _ = writeln!(output, "{}_sum{} {}", name, labels, self.sum);
+        let _ = writeln!(output, "{}_count{} {}", name, labels, self.count);
+    }
+}
+
+#[derive(Default)]
+struct Metrics {
+    // By operation and outcome
+    queries: BTreeMap<(String, String), u64>,
+    query_durations: BTreeMap<String, Histogram>,
+    pool_wait: Histogram,
+    busy_retries: BTreeMap<String, u64>,
+    // Sampled when a connection is acquired
+    pool_idle_connections: usize,
+    pool_in_use_connections: usize
+}
+
+// Counters and histograms of the operations of the DatabaseService instances
+// it is set on, shared with their transactions. Render them with
+// render_prometheus for a metrics endpoint.
+#[derive(Default)]
+pub struct DatabaseMetrics {
+    metrics: Mutex<Metrics>
+}
+
+impl DatabaseMetrics {
+    pub fn new() -> Self {
+        DatabaseMetrics::default()
+    }
+
+    // The outcome is "ok" or the kind of the error, e.g. "busy"
+    pub(crate) fn record_query(&self, operation: &str, duration: Duration, error: Option<&DatabaseServiceError>) {
+        let outcome = error.map(|e| e.kind().as_str()).unwrap_or("ok");
+        if let Ok(mut metrics) = self.metrics.lock() {
+            *metrics.queries.entry((operation.to_owned(), outcome.to_owned())).or_default() += 1;
+            metrics.query_durations.entry(operation.to_owned()).or_default().observe(duration);
+        }
+    }
+
+    pub(crate) fn record_pool_wait(&self, duration: Duration, idle_connections: usize, in_use_connections: usize) {
+        if let Ok(mut metrics) = self.metrics.lock() {
+            metrics.pool_wait.observe(duration);
+            metrics.pool_idle_connections = idle_connections;
+            metrics.pool_in_use_connections = in_use_connections;
+        }
+    }
+
+    pub(crate) fn record_busy_retry(&self, operation: &str) {
+        if let Ok(mut metrics) = self.metrics.lock() {
+            *metrics.busy_retries.entry(operation.to_owned()).or_default() += 1;
+        }
+    }
+
+    // Number of queries of the operation with the outcome so far
+    pub fn query_count(&self, operation: &str, outcome: &str) -> u64 {
+        self.metrics.lock().ok()
+            .and_then(|metrics| metrics.queries.get(&(operation.to_owned(), outcome.to_owned())).copied())
+            .unwrap_or(0)
+    }
+
+    pub fn busy_retry_count(&self, operation: &str) -> u64 {
+        self.metrics.lock().ok()
+            .and_then(|metrics| metrics.busy_retries.get(operation).copied())
+            .unwrap_or(0)
+    }
+
+    // The Prometheus text exposition format, version 0.0.4
+    pub fn render_prometheus(&self) -> String {
+        let metrics = match self.metrics.lock() {
+            Ok(metrics) => metrics,
+            Err(_) => return String::new()
+        };
+        let mut output = String::new();
+
+        let _ = writeln!(output, "# HELP {}_queries_total Database operations by outcome.", METRIC_PREFIX);
+        let _ = writeln!(output, "# TYPE {}_queries_total counter", METRIC_PREFIX);
+        for ((operation, outcome), count) in &metrics.queries {
+            let _ = writeln!(output, "{}_queries_total{{operation=\"{}\",outcome=\"{}\"}} {}",
+                             METRIC_PREFIX, escape_label_value(operation), escape_label_value(outcome), count);
+        }
+
+        let _ = writeln!(output, "# HELP {}_query_duration_seconds Duration of the database operations, retries and pool wait included.", METRIC_PREFIX);
+        let _ = writeln!(output, "# TYPE {}_query_duration_seconds histogram", METRIC_PREFIX);
+        for (operation, histogram) in &metrics.query_durations {
+            histogram.render(&mut output,
+                             format!("{}_query_duration_seconds", METRIC_PREFIX).as_str(),
+                             format!("operation=\"{}\"", escape_label_value(operation)).as_str());
+        }
+
+        let _ = writeln!(output, "# HELP {}_pool_wait_seconds Time waited for a connection of the pool.", METRIC_PREFIX);
+        let _ = writeln!(output, "# TYPE {}_pool_wait_seconds histogram", METRIC_PREFIX);
+        metrics.pool_wait.render(&mut output, format!("{}_pool_wait_seconds", METRIC_PREFIX).as_str(), "");
+
+        let _ = writeln!(output, "# HELP {}_busy_retries_total Statements retried because the database was busy or locked.", METRIC_PREFIX);
+        let _ = writeln!(output, "# TYPE {}_busy_retries_total counter", METRIC_PREFIX);
+        for (operation, count) in &metrics.busy_retries {
+            let _ = writeln!(output, "{}_busy_retries_total{{operation=\"{}\"}} {}", METRIC_PREFIX, escape_label_value(operation), count);
+        }
+
+        let _ = writeln!(output, "# HELP {}_pool_connections Connections of the pools when one was last acquired.", METRIC_PREFIX);
+        let _ = writeln!(output, "# TYPE {}_pool_connections gauge", METRIC_PREFIX);
+        let _ = writeln!(output, "{}_pool_connections{{state=\"idle\"}} {}", METRIC_PREFIX, metrics.pool_idle_connections);
+        let _ = writeln!(output, "{}_pool_connections{{state=\"in_use\"}} {}", METRIC_PREFIX, metrics.pool_in_use_connections);
+        output
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_services::database_service_error::DatabaseServiceErrorKind;
+
+    #[test]
+    fn render_prometheus_return_counters_and_cumulative_buckets() {
+        let metrics = DatabaseMetrics::new();
+        metrics.record_query("select", Duration::from_millis(3), None);
+        metrics.record_query("select", Duration::from_millis(40), None);
+        metrics.record_query("insert", Duration::from_secs(20),
+                             Some(&DatabaseServiceError::with_kind(DatabaseServiceErrorKind::Busy, "database is locked")));
+        metrics.record_busy_retry("insert");
+        metrics.record_pool_wait(Duration::from_millis(1), 2, 1);
+        let output = metrics.render_prometheus();
+        assert!(output.contains("sqlxusage_db_queries_total{operation=\"insert\",outcome=\"busy\"} 1\n"));
+        assert!(output.contains("sqlxusage_db_queries_total{operation=\"select\",outcome=\"ok\"} 2\n"));
+        assert!(output.contains("sqlxusage_db_query_duration_seconds_bucket{operation=\"select\",le=\"0.005\"} 1\n"));
+        assert!(output.contains("sqlxusage_db_query_duration_seconds_bucket{operation=\"select\",le=\"0.05\"} 2\n"));
+        assert!(output.contains("sqlxusage_db_query_duration_seconds_bucket{operation=\"insert\",le=\"10\"} 0\n"));
+        assert!(output.contains("sqlxusage_db_query_duration_seconds_bucket{operation=\"insert\",le=\"+Inf\"} 1\n"));
+        assert!(output.contains("sqlxusage_db_query_duration_seconds_count{operation=\"select\"} 2\n"));
+        assert!(output.contains("sqlxusage_db_pool_wait_seconds_count 1\n"));
+        assert!(output.contains("sqlxusage_db_busy_retries_total{operation=\"insert\"} 1\n"));
+        assert!(output.contains("sqlxusage_db_pool_connections{state=\"in_use\"} 1\n"));
+    }
+
+    #[test]
+    fn escape_label_value_escape_quotes_and_newlines() {
+        assert_eq!(r#"a\"b\\c\nd"#, escape_label_value("a\"b\\c\nd"));
+    }
+}
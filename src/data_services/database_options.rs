@@ -0,0 +1,238 @@
+use std::str::FromStr;
+use std::time::Duration;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous};
+use crate::data_services::database_service_error::DatabaseServiceError;
+use crate::data_services::retry_policy::RetryPolicy;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JournalMode {
+    Delete,
+    Truncate,
+    Persist,
+    Memory,
+    Wal,
+    Off
+}
+
+impl JournalMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            JournalMode::Delete => "delete",
+            JournalMode::Truncate => "truncate",
+            JournalMode::Persist => "persist",
+            JournalMode::Memory => "memory",
+            JournalMode::Wal => "wal",
+            JournalMode::Off => "off"
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        [JournalMode::Delete, JournalMode::Truncate, JournalMode::Persist, JournalMode::Memory, JournalMode::Wal, JournalMode::Off]
+            .into_iter()
+            .find(|mode| mode.as_str().eq_ignore_ascii_case(value))
+    }
+
+    fn to_sqlite(self) -> SqliteJournalMode {
+        match self {
+            JournalMode::Delete => SqliteJournalMode::Delete,
+            JournalMode::Truncate => SqliteJournalMode::Truncate,
+            JournalMode::Persist => SqliteJournalMode::Persist,
+            JournalMode::Memory => SqliteJournalMode::Memory,
+            JournalMode::Wal => SqliteJournalMode::Wal,
+            JournalMode::Off => SqliteJournalMode::Off
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SynchronousLevel {
+    Off,
+    Normal,
+    Full,
+    Extra
+}
+
+impl SynchronousLevel {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SynchronousLevel::Off => "off",
+            SynchronousLevel::Normal => "normal",
+            SynchronousLevel::Full => "full",
+            SynchronousLevel::Extra => "extra"
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        [SynchronousLevel::Off, SynchronousLevel::Normal, SynchronousLevel::Full, SynchronousLevel::Extra]
+            .into_iter()
+            .find(|level| level.as_str().eq_ignore_ascii_case(value))
+    }
+
+    fn to_sqlite(self) -> SqliteSynchronous {
+        match self {
+            SynchronousLevel::Off => SqliteSynchronous::Off,
+            SynchronousLevel::Normal => SqliteSynchronous::Normal,
+            SynchronousLevel::Full => SqliteSynchronous::Full,
+            SynchronousLevel::Extra => SqliteSynchronous::Extra
+        }
+    }
+}
+
+// Options of the connection pool and of every connection it opens. The
+// defaults are the ones of sqlx.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DatabaseOptions {
+    pub min_connections: u32,
+    pub max_connections: u32,
+    // How long to wait for a free connection of the pool
+    pub acquire_timeout: Duration,
+    // Connections idle for longer are closed, None keeps them open
+    pub idle_timeout: Option<Duration>,
+    // None keeps the journal mode of the database, WAL being persistent
+    pub journal_mode: Option<JournalMode>,
+    // None keeps the SQLite default, FULL
+    pub synchronous: Option<SynchronousLevel>,
+    // How long a statement waits for a lock held by another connection
+    pub busy_timeout: Duration,
+    pub foreign_keys: bool,
+    pub create_if_missing: bool,
+    pub read_only: bool,
+    // Opens a pool of one connection for the writes and transactions and a
+    // read-only pool of max_connections connections for the selects, so that
+    // readers never wait for the writer. Needs the WAL journal mode.
+    pub reader_pool: bool,
+    // Retries of the statements failing because the database is busy or
+    // locked, see DatabaseService::set_retry_policy for other policies per
+    // operation
+    pub retry_policy: RetryPolicy,
+    // Adds the values bound to the statements to their tracing spans, which
+    // only count them otherwise
+    pub trace_values: bool,
+    // Statements taking at least this long are logged with their query
+    // plan, None disables the slow query log
+    pub slow_query_threshold: Option<Duration>,
+    // Also records the slow queries in the Slow_Query table
+    pub slow_query_table: bool
+}
+
+impl Default for DatabaseOptions {
+    fn default() -> Self {
+        DatabaseOptions {
+            min_connections: 0,
+            max_connections: 10,
+            acquire_timeout: Duration::from_secs(30),
+            idle_timeout: Some(Duration::from_secs(10 * 60)),
+            journal_mode: None,
+            synchronous: None,
+            busy_timeout: Duration::from_secs(5),
+            foreign_keys: true,
+            create_if_missing: false,
+            read_only: false,
+            reader_pool: false,
+            retry_policy: RetryPolicy::default(),
+            trace_values: false,
+            slow_query_threshold: None,
+            slow_query_table: false
+        }
+    }
+}
+
+impl DatabaseOptions {
+    pub fn validate(&self) -> Result<(), DatabaseServiceError> {
+        if self.max_connections == 0 {
+            return Err(DatabaseServiceError::new("The pool needs at least one connection"));
+        }
+        if self.min_connections > self.max_connections {
+            return Err(DatabaseServiceError::new(format!("The pool minimum of {} connections exceeds its maximum of {}",
+                                                         self.min_connections,
+                                                         self.max_connections).as_str()));
+        }
+        if self.read_only && self.create_if_missing {
+            return Err(DatabaseServiceError::new("A read-only database cannot be created if missing"));
+        }
+        if self.read_only && self.reader_pool {
+            return Err(DatabaseServiceError::new("A read-only database has no writes to split from the reads of a reader pool"));
+        }
+        match self.journal_mode {
+            Some(journal_mode) if self.reader_pool && journal_mode != JournalMode::Wal => {
+                return Err(DatabaseServiceError::new(format!("The reader pool needs the WAL journal mode, not {}", journal_mode.as_str()).as_str()));
+            },
+            _ => ()
+        }
+        self.retry_policy.validate()
+    }
+
+    pub(crate) fn connect_options(&self, url: &str) -> Result<SqliteConnectOptions, DatabaseServiceError> {
+        let mut retval = SqliteConnectOptions::from_str(url)
+            .map_err(|e| DatabaseServiceError::new(e.to_string().as_str()))?
+            .busy_timeout(self.busy_timeout)
+            .foreign_keys(self.foreign_keys)
+            .create_if_missing(self.create_if_missing)
+            .read_only(self.read_only);
+        if let Some(journal_mode) = self.journal_mode {
+            retval = retval.journal_mode(journal_mode.to_sqlite());
+        }
+        if let Some(synchronous) = self.synchronous {
+            retval = retval.synchronous(synchronous.to_sqlite());
+        }
+        Ok(retval)
+    }
+
+    pub(crate) fn pool_options(&self) -> SqlitePoolOptions {
+        SqlitePoolOptions::new()
+            .min_connections(self.min_connections)
+            .max_connections(self.max_connections)
+            .acquire_timeout(self.acquire_timeout)
+            .idle_timeout(self.idle_timeout)
+    }
+
+    // The single connection of the writes when reader_pool is set
+    pub(crate) fn writer_pool_options(&self) -> SqlitePoolOptions {
+        self.pool_options()
+            .min_connections(self.min_connections.min(1))
+            .max_connections(1)
+    }
+
+    // Readers neither create the database nor change its journal mode, which
+    // a read-only connection cannot do
+    pub(crate) fn reader_connect_options(&self, url: &str) -> Result<SqliteConnectOptions, DatabaseServiceError> {
+        DatabaseOptions {
+            journal_mode: None,
+            create_if_missing: false,
+            read_only: true,
+            ..self.clone()
+        }.connect_options(url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_with_default_options_return_ok() {
+        assert!(DatabaseOptions::default().validate().is_ok());
+    }
+
+    #[test]
+    fn validate_with_inconsistent_options_return_err() {
+        assert!(DatabaseOptions { max_connections: 0, ..DatabaseOptions::default() }.validate().is_err());
+        assert!(DatabaseOptions { min_connections: 11, ..DatabaseOptions::default() }.validate().is_err());
+        assert!(DatabaseOptions { read_only: true, create_if_missing: true, ..DatabaseOptions::default() }.validate().is_err());
+        assert!(DatabaseOptions { reader_pool: true, journal_mode: Some(JournalMode::Delete), ..DatabaseOptions::default() }.validate().is_err());
+        assert!(DatabaseOptions { reader_pool: true, read_only: true, ..DatabaseOptions::default() }.validate().is_err());
+        assert!(DatabaseOptions { reader_pool: true, journal_mode: Some(JournalMode::Wal), ..DatabaseOptions::default() }.validate().is_ok());
+    }
+
+    #[test]
+    fn parse_return_case_insensitive_values() {
+        assert_eq!(Some(JournalMode::Wal), JournalMode::parse("WAL"));
+        assert_eq!(Some(SynchronousLevel::Normal), SynchronousLevel::parse("normal"));
+        assert_eq!(None, SynchronousLevel::parse("sometimes"));
+    }
+
+    #[test]
+    fn connect_options_with_invalid_url_return_err() {
+        assert!(DatabaseOptions::default().connect_options("sqlite://test.db?mode=sometimes").is_err());
+    }
+}
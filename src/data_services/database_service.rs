@@ -1,109 +1,836 @@
-use crate::data_services::database_service_base::{BlobList, DatabaseServiceTrait, DatabaseRow};
-use crate::data_services::database_service_error::DatabaseServiceError;
-use sqlx::{sqlite::SqlitePool, Column, Row, TypeInfo};
+use crate::data_services::database_blob::DatabaseBlob;
+use crate::data_services::database_health::{DatabaseHealth, LastError};
+use crate::data_services::database_metrics::DatabaseMetrics;
+use crate::data_services::database_options::{DatabaseOptions, JournalMode};
+use crate::data_services::database_service_base::{DatabaseBlobTrait, DatabaseServiceTrait, DatabaseTransactionTrait, DatabaseRow, ValueList};
+use crate::data_services::database_service_error::{DatabaseServiceError, DatabaseServiceErrorKind};
+use crate::data_services::query_tracing::{query_span, trace_query, QueryOutcome};
+use crate::data_services::retry_policy::{DatabaseOperation, Retry, RetryPolicy};
+use crate::data_services::slow_query_log::SlowQuery;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use sqlx::pool::PoolConnection;
+use sqlx::query::Query;
+use sqlx::sqlite::{Sqlite, SqliteArguments, SqliteColumn, SqliteConnection, SqlitePool, SqliteRow};
+use sqlx::{Column, Row, TypeInfo, ValueRef};
 use async_trait::async_trait;
 
 use super::database_service_base::DatabaseRowValue;
 
+// SQLITE_BUSY and SQLITE_LOCKED, the primary codes of their extended codes
+const BUSY_RESULT_CODES: [i32; 2] = [5, 6];
+
+// Runs a statement again while it fails because the database is busy or
+// locked, as long as the policy of the operation allows it.
+macro_rules! with_retry {
+    ($context:expr, $operation:expr, $statement:expr) => {{
+        let mut retry = Retry::new($context.retry_policies.get($operation));
+        loop {
+            match $statement.await {
+                Err(e) if retry.should_retry(&e) => {
+                    $context.record_busy_retry($operation);
+                    retry.wait().await
+                },
+                result => break retry.finish(result)
+            }
+        }
+    }};
+}
+
+// Runs a statement with its retries, then logs it if it was slow.
+macro_rules! run_statement {
+    ($context:expr, $operation:expr, $in_transaction:expr, $conn:expr, $query:expr, $statement:expr) => {{
+        let started_at = Instant::now();
+        let result = with_retry!($context, $operation, $statement);
+        $context.check_slow_query($conn, $operation, $query, started_at.elapsed(), $in_transaction).await;
+        result
+    }};
+}
+
+// The policy of the options and the ones set for some operations
+#[derive(Clone)]
+struct RetryPolicies {
+    default: RetryPolicy,
+    operations: HashMap<DatabaseOperation, RetryPolicy>
+}
+
+impl RetryPolicies {
+    fn get(&self, operation: DatabaseOperation) -> RetryPolicy {
+        self.operations.get(&operation).copied().unwrap_or(self.default)
+    }
+}
+
+// What a service shares with the transactions it begins: how their
+// statements are retried, traced and measured, and their errors tracked
+#[derive(Clone)]
+struct QueryContext {
+    retry_policies: RetryPolicies,
+    last_error: LastError,
+    trace_values: bool,
+    metrics: Option<Arc<DatabaseMetrics>>,
+    slow_query_threshold: Option<Duration>,
+    slow_query_table: bool
+}
+
+impl QueryContext {
+    async fn run<T, F>(&self,
+                       operation: &str,
+                       query: &str,
+                       values: &[DatabaseRowValue],
+                       in_transaction: bool,
+                       statement: F) -> Result<T, DatabaseServiceError>
+        where T: QueryOutcome,
+              F: Future<Output = Result<T, DatabaseServiceError>> {
+        let span = query_span(operation, query, values, in_transaction, self.trace_values);
+        let started_at = Instant::now();
+        let result = trace_query(span, statement).await;
+        if let Some(metrics) = &self.metrics {
+            metrics.record_query(operation, started_at.elapsed(), result.as_ref().err());
+        }
+        self.last_error.track(result)
+    }
+
+    async fn check_slow_query(&self,
+                              conn: &mut SqliteConnection,
+                              operation: DatabaseOperation,
+                              query: &str,
+                              duration: Duration,
+                              in_transaction: bool) {
+        if let Some(slow_query) = self.slow_query(conn, operation, query, duration, in_transaction).await {
+            slow_query.record(conn, self.slow_query_table).await;
+        }
+    }
+
+    // The explained statement when it took at least the threshold
+    async fn slow_query(&self,
+                        conn: &mut SqliteConnection,
+                        operation: DatabaseOperation,
+                        query: &str,
+                        duration: Duration,
+                        in_transaction: bool) -> Option<SlowQuery> {
+        match self.slow_query_threshold {
+            Some(threshold) if duration >= threshold => Some(SlowQuery::explain(conn, operation.as_str(), query, duration, in_transaction).await),
+            _ => None
+        }
+    }
+
+    fn record_busy_retry(&self, operation: DatabaseOperation) {
+        if let Some(metrics) = &self.metrics {
+            metrics.record_busy_retry(operation.as_str());
+        }
+    }
+}
+
+// Beginning a transaction or opening a blob returns no row
+impl QueryOutcome for DatabaseTransaction {
+    fn rows(&self) -> u64 {
+        0
+    }
+}
+
+impl QueryOutcome for DatabaseBlob {
+    fn rows(&self) -> u64 {
+        0
+    }
+}
+
 pub struct DatabaseService {
     database_file_path: String,
-    pool: Option<SqlitePool>
+    options: DatabaseOptions,
+    context: QueryContext,
+    // The only pool, or the writer one when reader_pool is set
+    pool: Option<SqlitePool>,
+    reader_pool: Option<SqlitePool>
 }
 
 impl DatabaseService {
     #[must_use]
     pub fn new(database_file_path: &str) -> Self {
+        DatabaseService::with_options(database_file_path, DatabaseOptions::default())
+    }
+
+    #[must_use]
+    pub fn with_options(database_file_path: &str, options: DatabaseOptions) -> Self {
         DatabaseService {
             database_file_path: database_file_path.to_string(),
-            pool: None
+            context: QueryContext {
+                retry_policies: RetryPolicies {
+                    default: options.retry_policy,
+                    operations: HashMap::new()
+                },
+                last_error: LastError::default(),
+                trace_values: options.trace_values,
+                metrics: None,
+                slow_query_threshold: options.slow_query_threshold,
+                slow_query_table: options.slow_query_table
+            },
+            options,
+            pool: None,
+            reader_pool: None
         }
     }
 
+    pub fn get_options(&self) -> &DatabaseOptions {
+        &self.options
+    }
+
+    pub fn get_retry_policy(&self, operation: DatabaseOperation) -> RetryPolicy {
+        self.context.retry_policies.get(operation)
+    }
+
+    // Overrides the retry policy of the options for one kind of statement,
+    // e.g. RetryPolicy::none() for selects. Transactions begun afterwards
+    // use it too. Commits are never retried.
+    pub fn set_retry_policy(&mut self, operation: DatabaseOperation, retry_policy: RetryPolicy) {
+        self.context.retry_policies.operations.insert(operation, retry_policy);
+    }
+
+    pub fn get_metrics(&self) -> Option<Arc<DatabaseMetrics>> {
+        self.context.metrics.clone()
+    }
+
+    // Measures the operations of the service and of the transactions begun
+    // afterwards. The same metrics can be set on several services.
+    pub fn set_metrics(&mut self, metrics: Arc<DatabaseMetrics>) {
+        self.context.metrics = Some(metrics);
+    }
+
+    // The options apply to the connections opened from then on.
     pub async fn connect(&mut self) -> Result<(), DatabaseServiceError> {
-        self.pool = SqlitePool::connect(self.database_file_path.as_str()).await
-            .map(|result| Some(result))
-            .map_err(|e| DatabaseServiceError::new(e.to_string().as_str()))?;
+        self.options.validate()?;
+        let connect_options = self.options.connect_options(self.database_file_path.as_str())?;
+        let pool_options = match self.options.reader_pool {
+            true => self.options.writer_pool_options(),
+            false => self.options.pool_options()
+        };
+        let pool = self.context.last_error.track(pool_options.connect_with(connect_options).await
+            .map_err(database_error))?;
+        self.reader_pool = match self.options.reader_pool {
+            true => Some(self.context.last_error.track(self.connect_readers(&pool).await)?),
+            false => None
+        };
+        self.pool = Some(pool);
         Ok(())
     }
 
+    // Readers only stay out of the way of the writer in WAL mode, which the
+    // writer connection has set by now when the options ask for it.
+    async fn connect_readers(&self, writer_pool: &SqlitePool) -> Result<SqlitePool, DatabaseServiceError> {
+        let mut conn = writer_pool.acquire().await.map_err(database_error)?;
+        let journal_mode = fetch_rows(&mut conn, "PRAGMA journal_mode").await?
+            .first()
+            .and_then(|row| row.get::<String>("journal_mode").ok())
+            .unwrap_or_default();
+        if !journal_mode.eq_ignore_ascii_case(JournalMode::Wal.as_str()) {
+            return Err(DatabaseServiceError::new(format!("The reader pool needs the WAL journal mode but the database is in {} mode",
+                                                         journal_mode).as_str()));
+        }
+        let connect_options = self.options.reader_connect_options(self.database_file_path.as_str())?;
+        self.options.pool_options().connect_with(connect_options).await
+            .map_err(database_error)
+    }
+
+    // Runs a trivial query on a pooled connection, a reader one if any, and
+    // returns its round trip.
+    pub async fn ping(&self) -> Result<Duration, DatabaseServiceError> {
+        let started_at = Instant::now();
+        let mut conn = self.acquire_reader().await?;
+        self.context.last_error.track(fetch_rows(&mut conn, "SELECT 1").await)?;
+        Ok(started_at.elapsed())
+    }
+
+    // Never fails: an unreachable database is reported as such, with the
+    // error in last_error.
+    pub async fn health(&self) -> DatabaseHealth {
+        // Not pinging a service that failed to connect keeps the connection
+        // error as the last one
+        let ping_duration = match self.pool {
+            Some(_) => self.ping().await.ok(),
+            None => None
+        };
+        let journal_mode = match ping_duration {
+            Some(_) => self.select("PRAGMA journal_mode").await.ok()
+                .and_then(|rows| rows.first().and_then(|row| row.get::<String>("journal_mode").ok())),
+            None => None
+        };
+        let (pool_size, idle_connections) = self.pool_connections();
+        let database_file_size = self.options.connect_options(self.database_file_path.as_str()).ok()
+            .and_then(|connect_options| std::fs::metadata(connect_options.get_filename()).ok())
+            .map(|metadata| metadata.len());
+        DatabaseHealth {
+            reachable: ping_duration.is_some(),
+            ping_duration,
+            pool_size,
+            idle_connections,
+            in_use_connections: (pool_size as usize).saturating_sub(idle_connections),
+            max_connections: match self.reader_pool {
+                Some(_) => self.options.max_connections + 1,
+                None => self.options.max_connections
+            },
+            database_file_size,
+            journal_mode,
+            last_error: self.context.last_error.get()
+                .or_else(|| self.pool.is_none().then(|| self.get_not_connected_msg()))
+        }
+    }
+
     fn get_not_connected_msg(&self) -> String {
-        return "Database is not connected. Call the connect method first.".to_owned();
+        "Database is not connected. Call the connect method first.".to_owned()
+    }
+
+    // Open and idle connections of all the pools
+    fn pool_connections(&self) -> (u32, usize) {
+        [&self.pool, &self.reader_pool].into_iter()
+            .flatten()
+            .fold((0, 0), |(size, idle), pool| (size + pool.size(), idle + pool.num_idle()))
+    }
+
+    fn writer_pool(&self) -> Option<&SqlitePool> {
+        self.pool.as_ref()
+    }
+
+    fn reader_pool(&self) -> Option<&SqlitePool> {
+        self.reader_pool.as_ref().or(self.pool.as_ref())
+    }
+
+    async fn acquire_writer(&self) -> Result<PoolConnection<Sqlite>, DatabaseServiceError> {
+        self.acquire(self.writer_pool()).await
+    }
+
+    async fn acquire_reader(&self) -> Result<PoolConnection<Sqlite>, DatabaseServiceError> {
+        self.acquire(self.reader_pool()).await
+    }
+
+    async fn acquire(&self, pool: Option<&SqlitePool>) -> Result<PoolConnection<Sqlite>, DatabaseServiceError> {
+        let pool = match pool {
+            Some(pool) => pool,
+            None => return self.context.last_error.track(Err(DatabaseServiceError::new(self.get_not_connected_msg().as_str())))
+        };
+        let started_at = Instant::now();
+        let conn = self.context.last_error.track(pool.acquire().await
+            .map_err(database_error))?;
+        if let Some(metrics) = &self.context.metrics {
+            let (pool_size, idle_connections) = self.pool_connections();
+            metrics.record_pool_wait(started_at.elapsed(), idle_connections, (pool_size as usize).saturating_sub(idle_connections));
+        }
+        Ok(conn)
+    }
+
+    // A reader connection cannot insert in the Slow_Query table, the writer
+    // one does when it is free rather than making the select wait for it.
+    async fn record_slow_read(&self, mut conn: PoolConnection<Sqlite>, slow_query: SlowQuery) {
+        match (&self.reader_pool, self.writer_pool()) {
+            (Some(_), Some(writer_pool)) if self.context.slow_query_table => match writer_pool.try_acquire() {
+                Some(mut writer_conn) => slow_query.record(&mut writer_conn, true).await,
+                None => slow_query.record(&mut conn, false).await
+            },
+            _ => slow_query.record(&mut conn, self.context.slow_query_table).await
+        }
     }
 }
 
 #[async_trait]
 impl DatabaseServiceTrait for DatabaseService {
-    async fn insert(&mut self,
+    async fn insert(&self,
                     query: &str,
-                    blobs: Option<BlobList>) -> Result<i64, DatabaseServiceError> {
-        if self.pool.is_none() {
-            return Err(DatabaseServiceError::new(self.get_not_connected_msg().as_str()));
-        }
-        let mut conn = self.pool.as_mut().unwrap().acquire().await
-            .map_err(|e| DatabaseServiceError::new(e.to_string().as_str()))?;
-        let mut query_builder = sqlx::query(query);
-        for blob in blobs.unwrap_or_else(|| vec![]) {
-            query_builder = query_builder.bind(blob);
+                    values: Option<ValueList>) -> Result<i64, DatabaseServiceError> {
+        let values = values.unwrap_or_default();
+        self.context.run(DatabaseOperation::Insert.as_str(), query, &values, false, async {
+            let mut conn = self.acquire_writer().await?;
+            run_statement!(self.context, DatabaseOperation::Insert, false, &mut conn, query, execute_insert(&mut conn, query, &values))
+        }).await
+    }
+
+    async fn update(&self,
+                    query: &str,
+                    values: Option<ValueList>) -> Result<u64, DatabaseServiceError> {
+        let values = values.unwrap_or_default();
+        self.context.run(DatabaseOperation::Update.as_str(), query, &values, false, async {
+            let mut conn = self.acquire_writer().await?;
+            run_statement!(self.context, DatabaseOperation::Update, false, &mut conn, query, execute_statement(&mut conn, query, &values))
+        }).await
+    }
+
+    async fn delete(&self, query: &str) -> Result<u64, DatabaseServiceError> {
+        self.context.run(DatabaseOperation::Delete.as_str(), query, &[], false, async {
+            let mut conn = self.acquire_writer().await?;
+            run_statement!(self.context, DatabaseOperation::Delete, false, &mut conn, query, execute_statement(&mut conn, query, &[]))
+        }).await
+    }
+
+    async fn select(&self, query: &str) -> Result<Vec<DatabaseRow>, DatabaseServiceError> {
+        self.context.run(DatabaseOperation::Select.as_str(), query, &[], false, async {
+            let mut conn = self.acquire_reader().await?;
+            let started_at = Instant::now();
+            let result = with_retry!(self.context, DatabaseOperation::Select, fetch_rows(&mut conn, query));
+            if let Some(slow_query) = self.context.slow_query(&mut conn, DatabaseOperation::Select, query, started_at.elapsed(), false).await {
+                self.record_slow_read(conn, slow_query).await;
+            }
+            result
+        }).await
+    }
+
+    async fn select_from_writer(&self, query: &str) -> Result<Vec<DatabaseRow>, DatabaseServiceError> {
+        self.context.run(DatabaseOperation::Select.as_str(), query, &[], false, async {
+            let mut conn = self.acquire_writer().await?;
+            run_statement!(self.context, DatabaseOperation::Select, false, &mut conn, query, fetch_rows(&mut conn, query))
+        }).await
+    }
+
+    async fn execute(&self, query: &str) -> Result<u64, DatabaseServiceError> {
+        self.context.run(DatabaseOperation::Execute.as_str(), query, &[], false, async {
+            let mut conn = self.acquire_writer().await?;
+            run_statement!(self.context, DatabaseOperation::Execute, false, &mut conn, query, execute_statement(&mut conn, query, &[]))
+        }).await
+    }
+
+    // Write transactions take the write lock when they begin. In a deferred
+    // transaction, a write following a read fails with SQLITE_BUSY as soon
+    // as another connection committed in between, however often it is
+    // retried, while BEGIN IMMEDIATE waits for the lock like any statement.
+    async fn begin(&self) -> Result<Box<dyn DatabaseTransactionTrait>, DatabaseServiceError> {
+        if self.writer_pool().is_none() {
+            return self.context.last_error.track(Err(DatabaseServiceError::new(self.get_not_connected_msg().as_str())));
         }
-        let last_inserted_id = query_builder.execute(&mut *conn).await
-            .map(|result| result.last_insert_rowid())
-            .map_err(|e| DatabaseServiceError::new(e.to_string().as_str()))?;
-        Ok(last_inserted_id)
+        let statement = match self.options.read_only {
+            true => "BEGIN",
+            false => "BEGIN IMMEDIATE"
+        };
+        let transaction = self.context.run("begin", statement, &[], true, async {
+            let mut conn = self.acquire_writer().await?;
+            with_retry!(self.context, DatabaseOperation::Execute, execute_statement(&mut conn, statement, &[]))?;
+            Ok(DatabaseTransaction {
+                conn: Some(conn),
+                context: self.context.clone()
+            })
+        }).await?;
+        Ok(Box::new(transaction))
+    }
+
+    async fn open_blob(&self,
+                       table: &str,
+                       column: &str,
+                       row_id: i64,
+                       writable: bool) -> Result<Box<dyn DatabaseBlobTrait>, DatabaseServiceError> {
+        let blob = self.context.run("open_blob", format!("{}.{} of row {}", table, column, row_id).as_str(), &[], false, async {
+            let conn = match writable {
+                true => self.acquire_writer().await?,
+                false => self.acquire_reader().await?
+            };
+            DatabaseBlob::open(conn, table, column, row_id, writable).await
+        }).await?;
+        Ok(Box::new(blob))
+    }
+}
+
+// A transaction opened on one pooled connection. Dropping it without calling
+// commit rolls it back.
+pub struct DatabaseTransaction {
+    // Taken once the transaction is committed or rolled back
+    conn: Option<PoolConnection<Sqlite>>,
+    context: QueryContext
+}
+
+impl DatabaseTransaction {
+    fn parts(&mut self) -> (&QueryContext, &mut SqliteConnection) {
+        let conn = self.conn.as_mut().expect("the transaction is finished");
+        (&self.context, conn)
+    }
+}
+
+// The transaction holds the write lock from its BEGIN IMMEDIATE on, so its
+// statements cannot deadlock with another writer. One still failing with
+// SQLITE_BUSY, e.g. while readers keep it from spilling changes outside of
+// WAL mode, has no effect and the transaction stays open, so retrying it
+// is safe.
+#[async_trait]
+impl DatabaseTransactionTrait for DatabaseTransaction {
+    async fn insert(&mut self,
+                    query: &str,
+                    values: Option<ValueList>) -> Result<i64, DatabaseServiceError> {
+        let values = values.unwrap_or_default();
+        let (context, conn) = self.parts();
+        context.run(DatabaseOperation::Insert.as_str(), query, &values, true, async {
+            run_statement!(context, DatabaseOperation::Insert, true, conn, query, execute_insert(conn, query, &values))
+        }).await
     }
 
     async fn update(&mut self,
                     query: &str,
-                    blobs: Option<BlobList>) -> Result<u64, DatabaseServiceError> {
-        if self.pool.is_none() {
-            return Err(DatabaseServiceError::new(self.get_not_connected_msg().as_str()));
-        }
-        let mut conn = self.pool.as_mut().unwrap().acquire().await
-            .map_err(|e| DatabaseServiceError::new(e.to_string().as_str()))?;
-        let mut query_builder = sqlx::query(query);
-        for blob in blobs.unwrap_or_else(|| vec![]) {
-            query_builder = query_builder.bind(blob);
-        }
-        let rows_affected = query_builder.execute(&mut *conn).await
-            .map(|result| result.rows_affected())
-            .map_err(|e| DatabaseServiceError::new(e.to_string().as_str()))?;
-        Ok(rows_affected)
+                    values: Option<ValueList>) -> Result<u64, DatabaseServiceError> {
+        let values = values.unwrap_or_default();
+        let (context, conn) = self.parts();
+        context.run(DatabaseOperation::Update.as_str(), query, &values, true, async {
+            run_statement!(context, DatabaseOperation::Update, true, conn, query, execute_statement(conn, query, &values))
+        }).await
     }
 
     async fn delete(&mut self, query: &str) -> Result<u64, DatabaseServiceError> {
-        if self.pool.is_none() {
-            return Err(DatabaseServiceError::new(self.get_not_connected_msg().as_str()));
-        }
-        let mut conn = self.pool.as_mut().unwrap().acquire().await
-            .map_err(|e| DatabaseServiceError::new(e.to_string().as_str()))?;
-        let rows_affected = sqlx::query(query).execute(&mut *conn).await
-            .map(|result| result.rows_affected())
-            .map_err(|e| DatabaseServiceError::new(e.to_string().as_str()))?;
-        Ok(rows_affected)
+        let (context, conn) = self.parts();
+        context.run(DatabaseOperation::Delete.as_str(), query, &[], true, async {
+            run_statement!(context, DatabaseOperation::Delete, true, conn, query, execute_statement(conn, query, &[]))
+        }).await
     }
 
     async fn select(&mut self, query: &str) -> Result<Vec<DatabaseRow>, DatabaseServiceError> {
-        if self.pool.is_none() {
-            return Err(DatabaseServiceError::new(self.get_not_connected_msg().as_str()));
-        }
-        let mut conn = self.pool.as_mut().unwrap().acquire().await
-            .map_err(|e| DatabaseServiceError::new(e.to_string().as_str()))?;
-        let rows = sqlx::query(query).fetch_all(&mut *conn).await
-            .map_err(|e| DatabaseServiceError::new(e.to_string().as_str()))?;
-        let mut retval = vec![];
-        for row in rows {
-            let mut new_row = DatabaseRow::new();
-            for column in row.columns() {
-                new_row.add_column(column.name(), match column.type_info().name() {
-                    "INTEGER" => DatabaseRowValue::Integer(row.try_get(column.ordinal()).unwrap()),
-                    "TEXT" => DatabaseRowValue::Text(row.try_get(column.ordinal()).unwrap()),
-                    "REAL" => DatabaseRowValue::Real(row.try_get(column.ordinal()).unwrap()),
-                    "BLOB" => DatabaseRowValue::Blob(row.try_get(column.ordinal()).unwrap()),
-                    "BOOLEAN" => DatabaseRowValue::Bool(row.try_get(column.ordinal()).unwrap()),
-                    _ => unimplemented!()
+        let (context, conn) = self.parts();
+        context.run(DatabaseOperation::Select.as_str(), query, &[], true, async {
+            run_statement!(context, DatabaseOperation::Select, true, conn, query, fetch_rows(conn, query))
+        }).await
+    }
+
+    async fn execute(&mut self, query: &str) -> Result<u64, DatabaseServiceError> {
+        let (context, conn) = self.parts();
+        context.run(DatabaseOperation::Execute.as_str(), query, &[], true, async {
+            run_statement!(context, DatabaseOperation::Execute, true, conn, query, execute_statement(conn, query, &[]))
+        }).await
+    }
+
+    // A failed commit leaves the transaction to drop, which rolls it back
+    async fn commit(mut self: Box<Self>) -> Result<(), DatabaseServiceError> {
+        self.finish("commit", "COMMIT").await
+    }
+
+    async fn rollback(mut self: Box<Self>) -> Result<(), DatabaseServiceError> {
+        self.finish("rollback", "ROLLBACK").await
+    }
+}
+
+impl DatabaseTransaction {
+    async fn finish(&mut self, operation: &str, statement: &str) -> Result<(), DatabaseServiceError> {
+        let (context, conn) = self.parts();
+        context.run(operation, statement, &[], true, async {
+            execute_statement(conn, statement, &[]).await.map(|_| ())
+        }).await?;
+        // Returns the connection to the pool
+        self.conn = None;
+        Ok(())
+    }
+}
+
+// Rolls back a transaction neither committed nor rolled back before its
+// connection goes back to the pool. Without a runtime to run the ROLLBACK
+// on, the connection is closed instead, which rolls the transaction back.
+impl Drop for DatabaseTransaction {
+    fn drop(&mut self) {
+        let Some(mut conn) = self.conn.take() else { return };
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                runtime.spawn(async move {
+                    let _ = execute_statement(&mut conn, "ROLLBACK", &[]).await;
                 });
-            }
-            retval.push(new_row);
+            },
+            Err(_) => drop(conn.detach())
+        }
+    }
+}
+
+// Errors whose code is SQLITE_BUSY or SQLITE_LOCKED are of kind Busy.
+pub(crate) fn database_error(e: sqlx::Error) -> DatabaseServiceError {
+    let busy = match &e {
+        sqlx::Error::Database(database_error) => database_error.code()
+            .and_then(|code| code.parse::<i32>().ok())
+            .map(|code| BUSY_RESULT_CODES.contains(&(code & 0xff)))
+            .unwrap_or(false),
+        _ => false
+    };
+    if busy {
+        DatabaseServiceError::with_kind(DatabaseServiceErrorKind::Busy, e.to_string().as_str())
+    } else {
+        DatabaseServiceError::new(e.to_string().as_str())
+    }
+}
+
+async fn execute_insert(conn: &mut SqliteConnection,
+                        query: &str,
+                        values: &[DatabaseRowValue]) -> Result<i64, DatabaseServiceError> {
+    let mut query_builder = sqlx::query(query);
+    for value in values {
+        query_builder = bind_value(query_builder, value);
+    }
+    let last_inserted_id = query_builder.execute(&mut *conn).await
+        .map(|result| result.last_insert_rowid())
+        .map_err(database_error)?;
+    Ok(last_inserted_id)
+}
+
+async fn execute_statement(conn: &mut SqliteConnection,
+                           query: &str,
+                           values: &[DatabaseRowValue]) -> Result<u64, DatabaseServiceError> {
+    let mut query_builder = sqlx::query(query);
+    for value in values {
+        query_builder = bind_value(query_builder, value);
+    }
+    let rows_affected = query_builder.execute(&mut *conn).await
+        .map(|result| result.rows_affected())
+        .map_err(database_error)?;
+    Ok(rows_affected)
+}
+
+async fn fetch_rows(conn: &mut SqliteConnection, query: &str) -> Result<Vec<DatabaseRow>, DatabaseServiceError> {
+    let rows = sqlx::query(query).fetch_all(&mut *conn).await
+        .map_err(database_error)?;
+    let mut retval = vec![];
+    for row in rows {
+        let mut new_row = DatabaseRow::new();
+        for column in row.columns() {
+            new_row.add_column(column.name(), read_column_value(&row, column)?);
         }
-        Ok(retval)
+        retval.push(new_row);
+    }
+    Ok(retval)
+}
+
+// Values are bound by reference so that a retried statement does not copy
+// them again.
+fn bind_value<'q>(query_builder: Query<'q, Sqlite, SqliteArguments<'q>>,
+                  value: &'q DatabaseRowValue) -> Query<'q, Sqlite, SqliteArguments<'q>> {
+    match value {
+        DatabaseRowValue::Integer(i) => query_builder.bind(*i),
+        DatabaseRowValue::Text(t) => query_builder.bind(t.as_str()),
+        DatabaseRowValue::Real(r) => query_builder.bind(*r),
+        DatabaseRowValue::Blob(b) => query_builder.bind(b.as_slice()),
+        DatabaseRowValue::Bool(b) => query_builder.bind(*b),
+        DatabaseRowValue::Null => query_builder.bind(None::<i64>)
+    }
+}
+
+// Columns declared BOOLEAN are reported as such, every other value is read
+// according to its storage class so that columns declared with custom type
+// names (DATETIME, UUID, JSON, ...) can still be decoded.
+fn read_column_value(row: &SqliteRow, column: &SqliteColumn) -> Result<DatabaseRowValue, DatabaseServiceError> {
+    let ordinal = column.ordinal();
+    let raw_value = row.try_get_raw(ordinal)
+        .map_err(|e| DatabaseServiceError::new(e.to_string().as_str()))?;
+    if raw_value.is_null() {
+        return Ok(DatabaseRowValue::Null);
+    }
+    let storage_class = raw_value.type_info().name().to_owned();
+    let value = match (column.type_info().name(), storage_class.as_str()) {
+        ("BOOLEAN", _) => row.try_get_unchecked(ordinal).map(DatabaseRowValue::Bool),
+        (_, "INTEGER") => row.try_get_unchecked(ordinal).map(DatabaseRowValue::Integer),
+        (_, "REAL") => row.try_get_unchecked(ordinal).map(DatabaseRowValue::Real),
+        (_, "BLOB") => row.try_get_unchecked(ordinal).map(DatabaseRowValue::Blob),
+        _ => row.try_get_unchecked(ordinal).map(DatabaseRowValue::Text)
+    };
+    value.map_err(|e| DatabaseServiceError::new(e.to_string().as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_services::database_options::SynchronousLevel;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn databaseservice_connect_with_options_apply_pragmas() {
+        let options = DatabaseOptions {
+            max_connections: 1,
+            busy_timeout: Duration::from_millis(1500),
+            foreign_keys: false,
+            synchronous: Some(SynchronousLevel::Off),
+            ..DatabaseOptions::default()
+        };
+        let mut db_service = DatabaseService::with_options("sqlite::memory:", options);
+        db_service.connect().await.unwrap();
+        let rows = db_service.select("SELECT timeout, foreign_keys, synchronous FROM pragma_busy_timeout, pragma_foreign_keys, pragma_synchronous").await.unwrap();
+        assert_eq!(1500, rows[0].get::<i64>("timeout").unwrap());
+        assert_eq!(0, rows[0].get::<i64>("foreign_keys").unwrap());
+        assert_eq!(0, rows[0].get::<i64>("synchronous").unwrap());
+    }
+
+    // The database lives in the returned directory, deleted with its -wal and
+    // -shm files when the directory is dropped
+    async fn locked_database(retry_policy: RetryPolicy) -> (DatabaseService, Box<dyn DatabaseTransactionTrait>, TempDir) {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("sqlxusage.db");
+        let url = format!("sqlite://{}", path.display());
+        let options = DatabaseOptions {
+            busy_timeout: Duration::ZERO,
+            create_if_missing: true,
+            retry_policy,
+            ..DatabaseOptions::default()
+        };
+        let mut holder = DatabaseService::with_options(url.as_str(), options.clone());
+        holder.connect().await.unwrap();
+        holder.execute("CREATE TABLE t (x INTEGER)").await.unwrap();
+        let mut transaction = holder.begin().await.unwrap();
+        transaction.insert("INSERT INTO t VALUES(1)", None).await.unwrap();
+        let mut db_service = DatabaseService::with_options(url.as_str(), options);
+        db_service.connect().await.unwrap();
+        (db_service, transaction, dir)
+    }
+
+    #[tokio::test]
+    async fn databaseservice_insert_with_locked_database_retry_until_released() {
+        let policy = RetryPolicy { initial_backoff: Duration::from_millis(20), jitter: false, ..RetryPolicy::default() };
+        let (db_service, transaction, _dir) = locked_database(policy).await;
+        let (result, _) = tokio::join!(
+            db_service.insert("INSERT INTO t VALUES(2)", None),
+            async {
+                tokio::time::sleep(Duration::from_millis(30)).await;
+                transaction.commit().await.unwrap();
+            });
+        assert!(result.is_ok());
+        assert_eq!(2, db_service.select("SELECT count(*) AS n FROM t").await.unwrap()[0].get::<i64>("n").unwrap());
+    }
+
+    #[tokio::test]
+    async fn databaseservice_insert_with_locked_database_return_busy_err_with_retries() {
+        let policy = RetryPolicy { max_attempts: 3, initial_backoff: Duration::from_millis(1), ..RetryPolicy::default() };
+        let (mut db_service, _transaction, _dir) = locked_database(policy).await;
+        let metrics = Arc::new(DatabaseMetrics::new());
+        db_service.set_metrics(metrics.clone());
+        let err = db_service.insert("INSERT INTO t VALUES(2)", None).await.unwrap_err();
+        assert_eq!(DatabaseServiceErrorKind::Busy, err.kind());
+        assert_eq!(2, err.retries());
+        assert_eq!(2, metrics.busy_retry_count("insert"));
+        assert_eq!(1, metrics.query_count("insert", "busy"));
+        db_service.set_retry_policy(DatabaseOperation::Insert, RetryPolicy::none());
+        assert_eq!(0, db_service.insert("INSERT INTO t VALUES(2)", None).await.unwrap_err().retries());
+    }
+
+    #[tokio::test]
+    async fn databaseservice_concurrent_transactions_reading_before_writing_both_commit() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("sqlxusage.db");
+        let url = format!("sqlite://{}", path.display());
+        let options = DatabaseOptions {
+            busy_timeout: Duration::ZERO,
+            journal_mode: Some(JournalMode::Wal),
+            create_if_missing: true,
+            retry_policy: RetryPolicy { initial_backoff: Duration::from_millis(20), jitter: false, ..RetryPolicy::default() },
+            ..DatabaseOptions::default()
+        };
+        let mut first = DatabaseService::with_options(url.as_str(), options.clone());
+        first.connect().await.unwrap();
+        first.execute("CREATE TABLE t (x INTEGER)").await.unwrap();
+        let mut second = DatabaseService::with_options(url.as_str(), options);
+        second.connect().await.unwrap();
+        let mut transaction = first.begin().await.unwrap();
+        transaction.select("SELECT x FROM t").await.unwrap();
+        let (second_result, first_result) = tokio::join!(
+            async {
+                let mut transaction = second.begin().await?;
+                transaction.select("SELECT x FROM t").await?;
+                transaction.insert("INSERT INTO t VALUES(2)", None).await?;
+                transaction.commit().await
+            },
+            async {
+                tokio::time::sleep(Duration::from_millis(30)).await;
+                transaction.insert("INSERT INTO t VALUES(1)", None).await?;
+                transaction.commit().await
+            });
+        assert!(first_result.is_ok());
+        assert!(second_result.is_ok());
+        assert_eq!(2, first.select("SELECT x FROM t").await.unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn databaseservice_drop_transaction_roll_it_back() {
+        let mut db_service = DatabaseService::with_options("sqlite::memory:", DatabaseOptions { max_connections: 1, ..DatabaseOptions::default() });
+        db_service.connect().await.unwrap();
+        db_service.execute("CREATE TABLE t (x INTEGER)").await.unwrap();
+        let mut transaction = db_service.begin().await.unwrap();
+        transaction.insert("INSERT INTO t VALUES(1)", None).await.unwrap();
+        drop(transaction);
+        db_service.insert("INSERT INTO t VALUES(2)", None).await.unwrap();
+        let rows = db_service.select("SELECT x FROM t").await.unwrap();
+        assert_eq!(vec![2], rows.iter().map(|row| row.get::<i64>("x").unwrap()).collect::<Vec<i64>>());
+    }
+
+    #[tokio::test]
+    async fn databaseservice_health_report_pool_and_last_error() {
+        let mut db_service = DatabaseService::with_options("sqlite::memory:", DatabaseOptions { max_connections: 1, ..DatabaseOptions::default() });
+        let health = db_service.health().await;
+        assert!(!health.reachable);
+        assert_eq!(Some(db_service.get_not_connected_msg()), health.last_error);
+        db_service.connect().await.unwrap();
+        assert!(db_service.ping().await.is_ok());
+        let _ = db_service.select("SELECT * FROM missing_table").await;
+        let health = db_service.health().await;
+        assert!(health.reachable);
+        // Connections are released to the pool in the background
+        assert_eq!((1, 1, 1), (health.pool_size, health.idle_connections + health.in_use_connections, health.max_connections));
+        assert_eq!(Some("memory".to_owned()), health.journal_mode);
+        assert_eq!(None, health.database_file_size);
+        assert!(health.last_error.unwrap().contains("missing_table"));
+    }
+
+    #[tokio::test]
+    async fn databaseservice_select_slower_than_threshold_record_query_plan() {
+        let options = DatabaseOptions {
+            max_connections: 1,
+            slow_query_threshold: Some(Duration::ZERO),
+            slow_query_table: true,
+            ..DatabaseOptions::default()
+        };
+        let mut db_service = DatabaseService::with_options("sqlite::memory:", options);
+        db_service.connect().await.unwrap();
+        db_service.execute(include_str!("../../migrations/add_slow_query_log.sql")).await.unwrap();
+        db_service.execute("CREATE TABLE t (x INTEGER)").await.unwrap();
+        db_service.select("SELECT x FROM t WHERE x = 1").await.unwrap();
+        let rows = db_service.select("SELECT operation, query_plan, full_scans FROM Slow_Query WHERE statement LIKE 'SELECT x%'").await.unwrap();
+        assert_eq!("select", rows[0].get::<String>("operation").unwrap());
+        assert_eq!("SCAN t", rows[0].get::<String>("query_plan").unwrap());
+        assert_eq!("t", rows[0].get::<String>("full_scans").unwrap());
+    }
+
+    async fn reader_pool_database() -> (DatabaseService, TempDir) {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("sqlxusage.db");
+        let options = DatabaseOptions {
+            max_connections: 2,
+            journal_mode: Some(JournalMode::Wal),
+            create_if_missing: true,
+            reader_pool: true,
+            ..DatabaseOptions::default()
+        };
+        let mut db_service = DatabaseService::with_options(format!("sqlite://{}", path.display()).as_str(), options);
+        db_service.connect().await.unwrap();
+        db_service.execute("CREATE TABLE t (x INTEGER)").await.unwrap();
+        (db_service, dir)
+    }
+
+    #[tokio::test]
+    async fn databaseservice_with_reader_pool_route_selects_to_readers() {
+        let (db_service, _dir) = reader_pool_database().await;
+        db_service.insert("INSERT INTO t VALUES(1)", None).await.unwrap();
+        assert_eq!(1, db_service.select("SELECT count(*) AS n FROM t").await.unwrap()[0].get::<i64>("n").unwrap());
+        let result = db_service.select("INSERT INTO t VALUES(2) RETURNING x").await;
+        assert!(result.is_err_and(|e| e.to_string().contains("readonly")));
+        db_service.execute("CREATE TEMP TABLE scratch AS SELECT x FROM t").await.unwrap();
+        assert!(db_service.select("SELECT x FROM scratch").await.is_err());
+        assert_eq!(3, db_service.health().await.max_connections);
+        let shared: Arc<dyn DatabaseServiceTrait> = Arc::new(db_service);
+        assert_eq!(1, shared.select_from_writer("SELECT x FROM scratch").await.unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn databaseservice_with_reader_pool_select_during_transaction_see_committed_rows() {
+        let (db_service, _dir) = reader_pool_database().await;
+        db_service.insert("INSERT INTO t VALUES(1)", None).await.unwrap();
+        let mut transaction = db_service.begin().await.unwrap();
+        transaction.insert("INSERT INTO t VALUES(2)", None).await.unwrap();
+        let rows = tokio::time::timeout(Duration::from_secs(1), db_service.select("SELECT x FROM t")).await.unwrap().unwrap();
+        assert_eq!(1, rows.len());
+        transaction.commit().await.unwrap();
+        assert_eq!(2, db_service.select("SELECT x FROM t").await.unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn databaseservice_connect_with_reader_pool_outside_wal_mode_return_err() {
+        let mut db_service = DatabaseService::with_options("sqlite::memory:", DatabaseOptions { reader_pool: true, ..DatabaseOptions::default() });
+        let err = db_service.connect().await.unwrap_err();
+        assert_eq!("The reader pool needs the WAL journal mode but the database is in memory mode", err.to_string());
+        assert!(db_service.select("SELECT 1").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn databaseservice_connect_with_invalid_options_return_err() {
+        let mut db_service = DatabaseService::with_options("sqlite::memory:", DatabaseOptions { max_connections: 0, ..DatabaseOptions::default() });
+        assert!(db_service.connect().await.is_err());
     }
 }
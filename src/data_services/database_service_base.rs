@@ -1,20 +1,228 @@
 use std::collections::HashMap;
 use async_trait::async_trait;
-use crate::data_services::database_service_error::DatabaseServiceError;
+use tokio::io::{AsyncRead, AsyncWrite};
+use crate::data_services::database_service_error::{DatabaseServiceError, DatabaseServiceErrorKind};
 
 pub type Blob = Vec<u8>;
-pub type BlobList = Vec<Blob>;
+pub type ValueList = Vec<DatabaseRowValue>;
+// The former name of ValueList, from when only blobs could be bound
+pub type BlobList = ValueList;
 
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum DatabaseRowValue {
     Integer(i64),
     Text(String),
     Real(f64),
     Blob(Blob),
-    Bool(bool)
+    Bool(bool),
+    Null
 }
 
-#[derive(Clone)]
+impl DatabaseRowValue {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            DatabaseRowValue::Integer(_) => "Integer",
+            DatabaseRowValue::Text(_) => "Text",
+            DatabaseRowValue::Real(_) => "Real",
+            DatabaseRowValue::Blob(_) => "Blob",
+            DatabaseRowValue::Bool(_) => "Bool",
+            DatabaseRowValue::Null => "Null"
+        }
+    }
+}
+
+// Decodes a Rust value from a column value. Conversions must be lossless:
+// an integer that does not fit in the target width, a real with a
+// fractional part read as an integer, or an integer too large to be
+// represented exactly as a float are all reported as errors.
+pub trait FromDatabaseValue: Sized {
+    fn from_database_value(value: &DatabaseRowValue) -> Result<Self, DatabaseServiceError>;
+}
+
+pub(crate) fn conversion_error(value: &DatabaseRowValue, target: &str) -> DatabaseServiceError {
+    DatabaseServiceError::with_kind(DatabaseServiceErrorKind::InvalidConversion,
+                                    format!("a {} value cannot be converted to {}", value.type_name(), target).as_str())
+}
+
+// Largest integer magnitudes that f64 and f32 can represent without loss.
+const F64_EXACT_INTEGER_LIMIT: i64 = 1 << 53;
+const F32_EXACT_INTEGER_LIMIT: i64 = 1 << 24;
+
+macro_rules! impl_from_database_value_for_integer {
+    ($($t:ty),*) => {
+        $(
+            impl FromDatabaseValue for $t {
+                fn from_database_value(value: &DatabaseRowValue) -> Result<Self, DatabaseServiceError> {
+                    let integer = match value {
+                        DatabaseRowValue::Integer(i) => *i,
+                        DatabaseRowValue::Bool(b) => i64::from(*b),
+                        DatabaseRowValue::Real(r) if r.fract() == 0.0
+                            && *r >= i64::MIN as f64 && *r < i64::MAX as f64 => *r as i64,
+                        _ => return Err(conversion_error(value, stringify!($t)))
+                    };
+                    <$t>::try_from(integer)
+                        .map_err(|_| DatabaseServiceError::with_kind(DatabaseServiceErrorKind::InvalidConversion,
+                                                                     format!("the value {} is out of range for {}", integer, stringify!($t)).as_str()))
+                }
+            }
+        )*
+    };
+}
+
+impl_from_database_value_for_integer!(i8, i16, i32, i64, i128, isize, u8, u16, u32, u64, u128, usize);
+
+impl FromDatabaseValue for f64 {
+    fn from_database_value(value: &DatabaseRowValue) -> Result<Self, DatabaseServiceError> {
+        match value {
+            DatabaseRowValue::Real(r) => Ok(*r),
+            DatabaseRowValue::Integer(i) if i.unsigned_abs() <= F64_EXACT_INTEGER_LIMIT as u64 => Ok(*i as f64),
+            _ => Err(conversion_error(value, "f64"))
+        }
+    }
+}
+
+impl FromDatabaseValue for f32 {
+    fn from_database_value(value: &DatabaseRowValue) -> Result<Self, DatabaseServiceError> {
+        match value {
+            DatabaseRowValue::Real(r) if r.is_nan() || (*r as f32) as f64 == *r => Ok(*r as f32),
+            DatabaseRowValue::Integer(i) if i.unsigned_abs() <= F32_EXACT_INTEGER_LIMIT as u64 => Ok(*i as f32),
+            _ => Err(conversion_error(value, "f32"))
+        }
+    }
+}
+
+impl FromDatabaseValue for bool {
+    fn from_database_value(value: &DatabaseRowValue) -> Result<Self, DatabaseServiceError> {
+        match value {
+            DatabaseRowValue::Bool(b) => Ok(*b),
+            DatabaseRowValue::Integer(0) => Ok(false),
+            DatabaseRowValue::Integer(1) => Ok(true),
+            _ => Err(conversion_error(value, "bool"))
+        }
+    }
+}
+
+impl FromDatabaseValue for String {
+    fn from_database_value(value: &DatabaseRowValue) -> Result<Self, DatabaseServiceError> {
+        match value {
+            DatabaseRowValue::Text(t) => Ok(t.to_owned()),
+            _ => Err(conversion_error(value, "String"))
+        }
+    }
+}
+
+impl FromDatabaseValue for Vec<u8> {
+    fn from_database_value(value: &DatabaseRowValue) -> Result<Self, DatabaseServiceError> {
+        match value {
+            DatabaseRowValue::Blob(b) => Ok(b.to_vec()),
+            _ => Err(conversion_error(value, "Vec<u8>"))
+        }
+    }
+}
+
+impl<T: FromDatabaseValue> FromDatabaseValue for Option<T> {
+    fn from_database_value(value: &DatabaseRowValue) -> Result<Self, DatabaseServiceError> {
+        match value {
+            DatabaseRowValue::Null => Ok(None),
+            _ => T::from_database_value(value).map(Some)
+        }
+    }
+}
+
+impl FromDatabaseValue for DatabaseRowValue {
+    fn from_database_value(value: &DatabaseRowValue) -> Result<Self, DatabaseServiceError> {
+        Ok(value.clone())
+    }
+}
+
+// Encodes a Rust value into a column value to be bound to a query. This is
+// the counterpart of FromDatabaseValue: any value encoded by a type must be
+// decodable back by the same type.
+pub trait ToDatabaseValue {
+    fn to_database_value(&self) -> Result<DatabaseRowValue, DatabaseServiceError>;
+}
+
+macro_rules! impl_to_database_value_for_integer {
+    ($($t:ty),*) => {
+        $(
+            impl ToDatabaseValue for $t {
+                fn to_database_value(&self) -> Result<DatabaseRowValue, DatabaseServiceError> {
+                    i64::try_from(*self)
+                        .map(DatabaseRowValue::Integer)
+                        .map_err(|_| DatabaseServiceError::with_kind(DatabaseServiceErrorKind::InvalidConversion,
+                                                                     format!("the value {} does not fit in an Integer column", self).as_str()))
+                }
+            }
+        )*
+    };
+}
+
+impl_to_database_value_for_integer!(i8, i16, i32, i64, i128, isize, u8, u16, u32, u64, u128, usize);
+
+impl ToDatabaseValue for f64 {
+    fn to_database_value(&self) -> Result<DatabaseRowValue, DatabaseServiceError> {
+        Ok(DatabaseRowValue::Real(*self))
+    }
+}
+
+impl ToDatabaseValue for f32 {
+    fn to_database_value(&self) -> Result<DatabaseRowValue, DatabaseServiceError> {
+        Ok(DatabaseRowValue::Real(f64::from(*self)))
+    }
+}
+
+impl ToDatabaseValue for bool {
+    fn to_database_value(&self) -> Result<DatabaseRowValue, DatabaseServiceError> {
+        Ok(DatabaseRowValue::Bool(*self))
+    }
+}
+
+impl ToDatabaseValue for str {
+    fn to_database_value(&self) -> Result<DatabaseRowValue, DatabaseServiceError> {
+        Ok(DatabaseRowValue::Text(self.to_owned()))
+    }
+}
+
+impl ToDatabaseValue for String {
+    fn to_database_value(&self) -> Result<DatabaseRowValue, DatabaseServiceError> {
+        self.as_str().to_database_value()
+    }
+}
+
+impl ToDatabaseValue for [u8] {
+    fn to_database_value(&self) -> Result<DatabaseRowValue, DatabaseServiceError> {
+        Ok(DatabaseRowValue::Blob(self.to_vec()))
+    }
+}
+
+impl ToDatabaseValue for Vec<u8> {
+    fn to_database_value(&self) -> Result<DatabaseRowValue, DatabaseServiceError> {
+        self.as_slice().to_database_value()
+    }
+}
+
+impl<T: ToDatabaseValue> ToDatabaseValue for Option<T> {
+    fn to_database_value(&self) -> Result<DatabaseRowValue, DatabaseServiceError> {
+        match self {
+            Some(value) => value.to_database_value(),
+            None => Ok(DatabaseRowValue::Null)
+        }
+    }
+}
+
+impl<T: ToDatabaseValue + ?Sized> ToDatabaseValue for &T {
+    fn to_database_value(&self) -> Result<DatabaseRowValue, DatabaseServiceError> {
+        (**self).to_database_value()
+    }
+}
+
+impl ToDatabaseValue for DatabaseRowValue {
+    fn to_database_value(&self) -> Result<DatabaseRowValue, DatabaseServiceError> {
+        Ok(self.clone())
+    }
+}
+
+#[derive(Clone, Default)]
 pub struct DatabaseRow {
     columns: HashMap<String, DatabaseRowValue>
 }
@@ -30,36 +238,63 @@ impl DatabaseRow {
         self.columns.insert(column_name.to_owned(), value);
     }
 
+    pub fn has_column(&self, column_name: &str) -> bool {
+        self.columns.contains_key(column_name)
+    }
+
+    fn get_value(&self, column_name: &str) -> Result<&DatabaseRowValue, DatabaseServiceError> {
+        self.columns.get(column_name)
+            .ok_or_else(|| DatabaseServiceError::with_kind(DatabaseServiceErrorKind::MissingColumn,
+                                                           format!("The column {} does not exist in the row", column_name).as_str()))
+    }
+
+    // Returns the column converted to T, or a MissingColumn error when the
+    // row has no such column.
+    pub fn get<T: FromDatabaseValue>(&self, column_name: &str) -> Result<T, DatabaseServiceError> {
+        T::from_database_value(self.get_value(column_name)?)
+            .map_err(|e| DatabaseServiceError::with_kind(e.kind(),
+                                                         format!("The column {} cannot be read: {}", column_name, e).as_str()))
+    }
+
+    // Same as get, but a column absent from the row (e.g. not part of the
+    // SELECT list) yields Ok(None) instead of an error.
+    pub fn try_get<T: FromDatabaseValue>(&self, column_name: &str) -> Result<Option<T>, DatabaseServiceError> {
+        if !self.has_column(column_name) {
+            return Ok(None);
+        }
+        self.get(column_name).map(Some)
+    }
+
     pub fn get_integer(&self, column_name: &str) -> Result<i64, DatabaseServiceError> {
-        match self.columns.get(column_name).unwrap() {
+        match self.get_value(column_name)? {
             DatabaseRowValue::Integer(i) => Ok(*i),
             _ => Err(DatabaseServiceError::new(format!("The column {} is not Integer type", column_name).as_str()))
         }
     }
 
     pub fn get_text(&self, column_name: &str) -> Result<&str, DatabaseServiceError> {
-        match self.columns.get(column_name).unwrap() {
+        match self.get_value(column_name)? {
             DatabaseRowValue::Text(t) => Ok(t),
             _ => Err(DatabaseServiceError::new(format!("The column {} is not Text type", column_name).as_str()))
         }
     }
 
     pub fn get_real(&self, column_name: &str) -> Result<f64, DatabaseServiceError> {
-        match self.columns.get(column_name).unwrap() {
+        match self.get_value(column_name)? {
             DatabaseRowValue::Real(i) => Ok(*i),
             _ => Err(DatabaseServiceError::new(format!("The column {} is not Real type", column_name).as_str()))
         }
     }
 
     pub fn get_blob(&self, column_name: &str) -> Result<Blob, DatabaseServiceError> {
-        match self.columns.get(column_name).unwrap() {
+        match self.get_value(column_name)? {
             DatabaseRowValue::Blob(i) => Ok(i.to_vec()),
             _ => Err(DatabaseServiceError::new(format!("The column {} is not Blob type", column_name).as_str()))
         }
     }
 
     pub fn get_bool(&self, column_name: &str) -> Result<bool, DatabaseServiceError> {
-        match self.columns.get(column_name).unwrap() {
+        match self.get_value(column_name)? {
             DatabaseRowValue::Bool(i) => Ok(*i),
             _ => Err(DatabaseServiceError::new(format!("The column {} is not Bool type", column_name).as_str()))
         }
@@ -67,13 +302,149 @@ impl DatabaseRow {
 }
 
 #[async_trait]
-pub trait DatabaseServiceTrait {
+// Implementations are shared between tasks, e.g. through the clones of an
+// ItemStorageService, so every method takes &self and must be safe to call
+// concurrently.
+pub trait DatabaseServiceTrait: Send + Sync {
+    async fn insert(&self,
+                    query: &str,
+                    values: Option<ValueList>) -> Result<i64, DatabaseServiceError>;
+    async fn update(&self,
+                    query: &str,
+                    values: Option<ValueList>) -> Result<u64, DatabaseServiceError>;
+    async fn delete(&self, query: &str) -> Result<u64, DatabaseServiceError>;
+    async fn select(&self, query: &str) -> Result<Vec<DatabaseRow>, DatabaseServiceError>;
+    // Reads through the connection the writes go to, so that the read sees
+    // what only that connection can see, e.g. temporary tables created by
+    // execute. The same as select for a service with a single connection
+    // pool.
+    async fn select_from_writer(&self, query: &str) -> Result<Vec<DatabaseRow>, DatabaseServiceError> {
+        self.select(query).await
+    }
+    async fn execute(&self, query: &str) -> Result<u64, DatabaseServiceError>;
+    async fn begin(&self) -> Result<Box<dyn DatabaseTransactionTrait>, DatabaseServiceError>;
+    async fn open_blob(&self,
+                       table: &str,
+                       column: &str,
+                       row_id: i64,
+                       writable: bool) -> Result<Box<dyn DatabaseBlobTrait>, DatabaseServiceError>;
+}
+
+// Streaming access to a single BLOB value of a row. The size of the value
+// cannot change: writes past the end fail.
+pub trait DatabaseBlobTrait: AsyncRead + AsyncWrite + Send + Unpin {
+    fn size(&self) -> u64;
+    // Dropping the blob closes it too, but loses the error SQLite may only
+    // report on close, e.g. for a failed write.
+    fn close(self: Box<Self>) -> Result<(), DatabaseServiceError>;
+}
+
+// Statements run through a transaction are only visible to other
+// connections once commit is called. Dropping the transaction rolls it back.
+#[async_trait]
+pub trait DatabaseTransactionTrait: Send {
     async fn insert(&mut self,
                     query: &str,
-                    blobs: Option<BlobList>) -> Result<i64, DatabaseServiceError>;
+                    values: Option<ValueList>) -> Result<i64, DatabaseServiceError>;
     async fn update(&mut self,
                     query: &str,
-                    blobs: Option<BlobList>) -> Result<u64, DatabaseServiceError>;
+                    values: Option<ValueList>) -> Result<u64, DatabaseServiceError>;
     async fn delete(&mut self, query: &str) -> Result<u64, DatabaseServiceError>;
     async fn select(&mut self, query: &str) -> Result<Vec<DatabaseRow>, DatabaseServiceError>;
+    async fn execute(&mut self, query: &str) -> Result<u64, DatabaseServiceError>;
+    async fn commit(self: Box<Self>) -> Result<(), DatabaseServiceError>;
+    async fn rollback(self: Box<Self>) -> Result<(), DatabaseServiceError>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_sample_row() -> DatabaseRow {
+        let mut retval = DatabaseRow::new();
+        retval.add_column("id", DatabaseRowValue::Integer(300));
+        retval.add_column("descr", DatabaseRowValue::Text("Test1".to_owned()));
+        retval.add_column("amount", DatabaseRowValue::Real(1.5));
+        retval.add_column("active", DatabaseRowValue::Bool(true));
+        retval.add_column("picture", DatabaseRowValue::Blob(vec![1, 2]));
+        retval.add_column("note", DatabaseRowValue::Null);
+        retval
+    }
+
+    #[test]
+    fn databaserow_get_with_matching_types_return_values() {
+        let row = get_sample_row();
+        assert_eq!(300, row.get::<i64>("id").unwrap());
+        assert_eq!(300, row.get::<u16>("id").unwrap());
+        assert_eq!("Test1", row.get::<String>("descr").unwrap());
+        assert_eq!(1.5, row.get::<f64>("amount").unwrap());
+        assert_eq!(1.5, row.get::<f32>("amount").unwrap());
+        assert!(row.get::<bool>("active").unwrap());
+        assert_eq!(vec![1, 2], row.get::<Vec<u8>>("picture").unwrap());
+    }
+
+    #[test]
+    fn databaserow_get_with_lossless_widening_return_values() {
+        let row = get_sample_row();
+        assert_eq!(300.0, row.get::<f64>("id").unwrap());
+        assert_eq!(1, row.get::<i32>("active").unwrap());
+    }
+
+    #[test]
+    fn databaserow_get_with_min_integer_as_float_return_err() {
+        let mut row = DatabaseRow::new();
+        row.add_column("amount", DatabaseRowValue::Integer(i64::MIN));
+        assert_eq!(DatabaseServiceErrorKind::InvalidConversion, row.get::<f64>("amount").unwrap_err().kind());
+        assert_eq!(DatabaseServiceErrorKind::InvalidConversion, row.get::<f32>("amount").unwrap_err().kind());
+    }
+
+    #[test]
+    fn databaserow_get_with_out_of_range_integer_return_err() {
+        let row = get_sample_row();
+        let err = row.get::<u8>("id").unwrap_err();
+        assert_eq!(DatabaseServiceErrorKind::InvalidConversion, err.kind());
+    }
+
+    #[test]
+    fn databaserow_get_with_fractional_real_as_integer_return_err() {
+        let row = get_sample_row();
+        assert_eq!(DatabaseServiceErrorKind::InvalidConversion, row.get::<i64>("amount").unwrap_err().kind());
+    }
+
+    #[test]
+    fn databaserow_get_with_missing_column_return_err() {
+        let row = get_sample_row();
+        assert_eq!(DatabaseServiceErrorKind::MissingColumn, row.get::<i64>("unknown").unwrap_err().kind());
+        assert_eq!(DatabaseServiceErrorKind::MissingColumn, row.get_integer("unknown").unwrap_err().kind());
+    }
+
+    #[test]
+    fn databaserow_get_option_with_null_return_none() {
+        let row = get_sample_row();
+        assert_eq!(None, row.get::<Option<String>>("note").unwrap());
+        assert_eq!(Some(300), row.get::<Option<i64>>("id").unwrap());
+        assert_eq!(DatabaseServiceErrorKind::InvalidConversion, row.get::<String>("note").unwrap_err().kind());
+    }
+
+    #[test]
+    fn databaserow_try_get_with_missing_column_return_none() {
+        let row = get_sample_row();
+        assert_eq!(None, row.try_get::<i64>("unknown").unwrap());
+        assert_eq!(Some(300), row.try_get::<i64>("id").unwrap());
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Quantity(u32);
+
+    impl FromDatabaseValue for Quantity {
+        fn from_database_value(value: &DatabaseRowValue) -> Result<Self, DatabaseServiceError> {
+            u32::from_database_value(value).map(Quantity)
+        }
+    }
+
+    #[test]
+    fn databaserow_get_with_user_type_return_value() {
+        let row = get_sample_row();
+        assert_eq!(Quantity(300), row.get::<Quantity>("id").unwrap());
+    }
 }
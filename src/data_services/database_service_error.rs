@@ -1,15 +1,111 @@
 use core::fmt;
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DatabaseServiceErrorKind {
+    Other,
+    MissingColumn,
+    InvalidConversion,
+    InvalidFilter,
+    InvalidPicture,
+    // The row was changed by someone else since it was read
+    Conflict,
+    // The item breaks validation rules, see field_errors
+    InvalidItem,
+    // Configuration keys are missing or invalid, see field_errors
+    InvalidConfig,
+    // SQLITE_BUSY or SQLITE_LOCKED: another connection holds a lock, the
+    // statement may succeed if retried
+    Busy
+}
+
+impl DatabaseServiceErrorKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DatabaseServiceErrorKind::Other => "other",
+            DatabaseServiceErrorKind::MissingColumn => "missing_column",
+            DatabaseServiceErrorKind::InvalidConversion => "invalid_conversion",
+            DatabaseServiceErrorKind::InvalidFilter => "invalid_filter",
+            DatabaseServiceErrorKind::InvalidPicture => "invalid_picture",
+            DatabaseServiceErrorKind::Conflict => "conflict",
+            DatabaseServiceErrorKind::InvalidItem => "invalid_item",
+            DatabaseServiceErrorKind::InvalidConfig => "invalid_config",
+            DatabaseServiceErrorKind::Busy => "busy"
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String
+}
+
+impl fmt::Display for FieldError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
 pub struct DatabaseServiceError {
-    message: String
+    kind: DatabaseServiceErrorKind,
+    message: String,
+    field_errors: Vec<FieldError>,
+    retries: u32
 }
 
 impl DatabaseServiceError {
     pub fn new(message: &str) ->  Self {
+        DatabaseServiceError::with_kind(DatabaseServiceErrorKind::Other, message)
+    }
+
+    pub fn with_kind(kind: DatabaseServiceErrorKind, message: &str) -> Self {
         DatabaseServiceError {
-            message: message.to_string()
+            kind,
+            message: message.to_string(),
+            field_errors: vec![],
+            retries: 0
         }
     }
+
+    pub fn invalid_item(field_errors: Vec<FieldError>) -> Self {
+        DatabaseServiceError::with_field_errors(DatabaseServiceErrorKind::InvalidItem, "The item is invalid", field_errors)
+    }
+
+    pub fn invalid_config(field_errors: Vec<FieldError>) -> Self {
+        DatabaseServiceError::with_field_errors(DatabaseServiceErrorKind::InvalidConfig, "The configuration is invalid", field_errors)
+    }
+
+    fn with_field_errors(kind: DatabaseServiceErrorKind, message: &str, field_errors: Vec<FieldError>) -> Self {
+        let messages: Vec<String> = field_errors.iter().map(|e| e.to_string()).collect();
+        DatabaseServiceError {
+            kind,
+            message: format!("{}: {}", message, messages.join(", ")),
+            field_errors,
+            retries: 0
+        }
+    }
+
+    // The error of the last attempt of an operation retried in vain
+    pub fn with_retries(self, retries: u32) -> Self {
+        DatabaseServiceError {
+            message: format!("{} (gave up after {} retries)", self.message, retries),
+            retries,
+            ..self
+        }
+    }
+
+    pub fn kind(&self) -> DatabaseServiceErrorKind {
+        self.kind
+    }
+
+    pub fn field_errors(&self) -> &[FieldError] {
+        &self.field_errors
+    }
+
+    // Number of times the operation was retried before failing
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
 }
 
 impl fmt::Display for DatabaseServiceError {
@@ -23,4 +119,3 @@ impl fmt::Debug for DatabaseServiceError {
         write!(f, "{}", self.message)
     }
 }
-
@@ -0,0 +1,395 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+use crate::data_services::database_service_error::{DatabaseServiceError, DatabaseServiceErrorKind};
+use crate::data_services::item_filter::ItemFilter;
+use crate::data_services::item_storage_service::ItemStorageService;
+use crate::models::item::Item;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ItemCacheOptions {
+    // Items kept in total, a list of items counting for its length. The
+    // least recently used entries are evicted beyond it.
+    pub max_items: usize,
+    // Entries older than this are read again, None keeps them until they
+    // are evicted or invalidated. Bounds how long the changes made by other
+    // processes go unnoticed.
+    pub ttl: Option<Duration>
+}
+
+impl Default for ItemCacheOptions {
+    fn default() -> Self {
+        ItemCacheOptions {
+            max_items: 1000,
+            ttl: Some(Duration::from_secs(60))
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ItemCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    // Entries removed to stay within max_items
+    pub evictions: u64,
+    // Entries found older than the ttl
+    pub expirations: u64,
+    // Entries removed because items changed or the cache was cleared
+    pub invalidations: u64,
+    // Entries currently cached and the items they hold
+    pub entries: usize,
+    pub items: usize
+}
+
+impl ItemCacheStats {
+    // Share of the reads served from the cache, 0 before any read
+    pub fn hit_ratio(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            reads => self.hits as f64 / reads as f64
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum CacheKey {
+    Item(i64),
+    // The SQL conditions of the filter
+    Items(String)
+}
+
+#[derive(Clone)]
+enum CachedValue {
+    // None when the item does not exist or is in the trash
+    Item(Option<Item>),
+    Items(Vec<Item>)
+}
+
+impl CachedValue {
+    fn size(&self) -> usize {
+        match self {
+            CachedValue::Item(_) => 1,
+            CachedValue::Items(items) => items.len().max(1)
+        }
+    }
+}
+
+struct CacheEntry {
+    value: CachedValue,
+    cached_at: Instant,
+    // Key of the entry in the LRU order
+    last_used: u64
+}
+
+struct ItemCache {
+    options: ItemCacheOptions,
+    entries: HashMap<CacheKey, CacheEntry>,
+    // Keys by last use, the least recently used first
+    lru: BTreeMap<u64, CacheKey>,
+    use_count: u64,
+    size: usize,
+    // Change count of the storage the entries are up to date with
+    change_count: u64,
+    stats: ItemCacheStats
+}
+
+impl ItemCache {
+    fn new(options: ItemCacheOptions, change_count: u64) -> Self {
+        ItemCache {
+            options,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            use_count: 0,
+            size: 0,
+            change_count,
+            stats: ItemCacheStats::default()
+        }
+    }
+
+    fn stats(&self) -> ItemCacheStats {
+        ItemCacheStats {
+            entries: self.entries.len(),
+            items: self.size,
+            ..self.stats
+        }
+    }
+
+    // Items changed in a way the cache was not told about: every entry may
+    // be outdated.
+    fn sync(&mut self, change_count: u64) {
+        if change_count != self.change_count {
+            self.invalidate(|_| true);
+            self.change_count = change_count;
+        }
+    }
+
+    fn get(&mut self, key: &CacheKey, change_count: u64) -> Option<CachedValue> {
+        self.sync(change_count);
+        let expired = match self.entries.get(key) {
+            Some(entry) => self.options.ttl.is_some_and(|ttl| entry.cached_at.elapsed() >= ttl),
+            None => {
+                self.stats.misses += 1;
+                return None;
+            }
+        };
+        if expired {
+            self.remove(key);
+            self.stats.expirations += 1;
+            self.stats.misses += 1;
+            return None;
+        }
+        self.stats.hits += 1;
+        self.use_count += 1;
+        let entry = self.entries.get_mut(key)?;
+        self.lru.remove(&entry.last_used);
+        self.lru.insert(self.use_count, key.clone());
+        entry.last_used = self.use_count;
+        Some(entry.value.clone())
+    }
+
+    // The value is only cached when no change was committed since
+    // read_change_count, taken before reading it: it may be outdated
+    // otherwise.
+    fn insert(&mut self, key: CacheKey, value: CachedValue, read_change_count: u64, change_count: u64) {
+        self.sync(change_count);
+        if read_change_count != self.change_count || value.size() > self.options.max_items {
+            return;
+        }
+        self.remove(&key);
+        self.use_count += 1;
+        self.size += value.size();
+        self.lru.insert(self.use_count, key.clone());
+        self.entries.insert(key, CacheEntry {
+            value,
+            cached_at: Instant::now(),
+            last_used: self.use_count
+        });
+        while self.size > self.options.max_items {
+            let Some((_, key)) = self.lru.pop_first() else { break };
+            if let Some(entry) = self.entries.remove(&key) {
+                self.size -= entry.value.size();
+                self.stats.evictions += 1;
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.last_used);
+            self.size -= entry.value.size();
+        }
+    }
+
+    fn invalidate(&mut self, outdated: impl Fn(&CacheKey) -> bool) {
+        let keys: Vec<CacheKey> = self.entries.keys()
+            .filter(|key| outdated(key))
+            .cloned()
+            .collect();
+        for key in keys {
+            self.remove(&key);
+            self.stats.invalidations += 1;
+        }
+    }
+
+    // After a change of one item made through the cache, only that item and
+    // the lists of items are outdated. When other changes were committed in
+    // the meantime, all the entries are.
+    fn item_changed(&mut self, item_id: i64, change_count: u64) {
+        if change_count == self.change_count + 1 {
+            self.invalidate(|key| matches!(key, CacheKey::Items(_)) || *key == CacheKey::Item(item_id));
+            self.change_count = change_count;
+        } else {
+            self.sync(change_count);
+        }
+    }
+}
+
+// Serves get_item, get_items and get_all_items from a cache of the items
+// read before, in front of an ItemStorageService. Clones share the cache.
+// Every change committed through the wrapped service or its clones, this
+// wrapper bypassed or not, invalidates the cached items it may affect.
+// Changes made by other processes are only seen once the entries expire.
+#[derive(Clone)]
+pub struct CachedItemStorageService {
+    storage: ItemStorageService,
+    cache: Arc<Mutex<ItemCache>>
+}
+
+impl CachedItemStorageService {
+    pub fn new(storage: ItemStorageService, options: ItemCacheOptions) -> Self {
+        let cache = ItemCache::new(options, storage.change_count());
+        CachedItemStorageService {
+            storage,
+            cache: Arc::new(Mutex::new(cache))
+        }
+    }
+
+    // For the operations that are not cached
+    pub fn storage(&self) -> &ItemStorageService {
+        &self.storage
+    }
+
+    // Entries outdated by changes made since the last read are not counted
+    pub fn stats(&self) -> ItemCacheStats {
+        let mut cache = self.cache();
+        cache.sync(self.storage.change_count());
+        cache.stats()
+    }
+
+    pub fn clear(&self) {
+        self.cache().invalidate(|_| true);
+    }
+
+    fn cache(&self) -> MutexGuard<'_, ItemCache> {
+        self.cache.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub async fn get_item(&self, item_id: i64) -> Result<Option<Item>, DatabaseServiceError> {
+        let key = CacheKey::Item(item_id);
+        let read_change_count = self.storage.change_count();
+        if let Some(CachedValue::Item(item)) = self.cache().get(&key, read_change_count) {
+            return Ok(item);
+        }
+        let item = self.storage.get_item(item_id).await?;
+        self.cache().insert(key, CachedValue::Item(item.clone()), read_change_count, self.storage.change_count());
+        Ok(item)
+    }
+
+    pub async fn get_all_items(&self) -> Result<Vec<Item>, DatabaseServiceError> {
+        self.get_items(&ItemFilter::new()).await
+    }
+
+    pub async fn get_items(&self, filter: &ItemFilter) -> Result<Vec<Item>, DatabaseServiceError> {
+        let key = CacheKey::Items(filter.to_sql(&HashSet::new()));
+        let read_change_count = self.storage.change_count();
+        if let Some(CachedValue::Items(items)) = self.cache().get(&key, read_change_count) {
+            return Ok(items);
+        }
+        let items = self.storage.get_items(filter).await?;
+        self.cache().insert(key, CachedValue::Items(items.clone()), read_change_count, self.storage.change_count());
+        Ok(items)
+    }
+
+    pub async fn add_item(&self, item: &Item) -> Result<i64, DatabaseServiceError> {
+        let item_id = self.storage.add_item(item).await?;
+        self.cache().item_changed(item_id, self.storage.change_count());
+        Ok(item_id)
+    }
+
+    pub async fn update_item(&self, updated_item: &Item) -> Result<u64, DatabaseServiceError> {
+        let result = self.storage.update_item(updated_item).await;
+        self.changed(updated_item.get_id(), &result);
+        result
+    }
+
+    pub async fn delete_item(&self, item_id: i64, expected_version: i64) -> Result<u64, DatabaseServiceError> {
+        let result = self.storage.delete_item(item_id, expected_version).await;
+        self.changed(item_id, &result);
+        result
+    }
+
+    // A Conflict error proves the cached item outdated, e.g. by a change
+    // made by another process.
+    fn changed(&self, item_id: i64, result: &Result<u64, DatabaseServiceError>) {
+        let mut cache = self.cache();
+        match result {
+            Ok(_) => cache.item_changed(item_id, self.storage.change_count()),
+            Err(e) if e.kind() == DatabaseServiceErrorKind::Conflict => cache.invalidate(|key| *key == CacheKey::Item(item_id)),
+            Err(_) => ()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_services::database_options::DatabaseOptions;
+    use crate::data_services::database_service::DatabaseService;
+    use crate::data_services::database_service_base::DatabaseServiceTrait;
+
+    fn item_value(item_id: i64) -> CachedValue {
+        CachedValue::Item(Some(Item::new(item_id, "pen", 1.0, true, None)))
+    }
+
+    #[test]
+    fn itemcache_insert_beyond_max_items_evict_least_recently_used() {
+        let mut cache = ItemCache::new(ItemCacheOptions { max_items: 3, ttl: None }, 0);
+        cache.insert(CacheKey::Item(1), item_value(1), 0, 0);
+        cache.insert(CacheKey::Item(2), item_value(2), 0, 0);
+        assert!(cache.get(&CacheKey::Item(1), 0).is_some());
+        cache.insert(CacheKey::Items(String::new()), CachedValue::Items(vec![Item::new(3, "cup", 2.0, true, None); 2]), 0, 0);
+        assert!(cache.get(&CacheKey::Item(2), 0).is_none());
+        assert!(cache.get(&CacheKey::Item(1), 0).is_some());
+        cache.insert(CacheKey::Items("too large".to_owned()), CachedValue::Items(vec![Item::new(4, "mug", 3.0, true, None); 4]), 0, 0);
+        assert_eq!(ItemCacheStats { hits: 2, misses: 1, evictions: 1, expirations: 0, invalidations: 0, entries: 2, items: 3 }, cache.stats());
+    }
+
+    #[test]
+    fn itemcache_get_expired_entry_return_none() {
+        let mut cache = ItemCache::new(ItemCacheOptions { max_items: 10, ttl: Some(Duration::ZERO) }, 0);
+        cache.insert(CacheKey::Item(1), item_value(1), 0, 0);
+        assert!(cache.get(&CacheKey::Item(1), 0).is_none());
+        assert_eq!((1, 1, 0), (cache.stats().expirations, cache.stats().misses, cache.stats().entries));
+    }
+
+    #[test]
+    fn itemcache_with_unknown_changes_drop_all_entries() {
+        let mut cache = ItemCache::new(ItemCacheOptions::default(), 0);
+        cache.insert(CacheKey::Item(1), item_value(1), 0, 0);
+        cache.insert(CacheKey::Item(2), item_value(2), 0, 0);
+        cache.insert(CacheKey::Items(String::new()), CachedValue::Items(vec![]), 0, 0);
+        cache.item_changed(1, 1);
+        assert!(cache.get(&CacheKey::Item(1), 1).is_none());
+        assert!(cache.get(&CacheKey::Items(String::new()), 1).is_none());
+        assert!(cache.get(&CacheKey::Item(2), 1).is_some());
+        // Read before a change committed elsewhere
+        cache.insert(CacheKey::Item(1), item_value(1), 1, 2);
+        assert!(cache.get(&CacheKey::Item(1), 2).is_none());
+        assert_eq!(0, cache.stats().entries);
+        assert_eq!(3, cache.stats().invalidations);
+    }
+
+    async fn item_storage() -> ItemStorageService {
+        let mut db_service = DatabaseService::with_options("sqlite::memory:", DatabaseOptions { max_connections: 1, ..DatabaseOptions::default() });
+        db_service.connect().await.unwrap();
+        for migration in [include_str!("../../migrations/initial_db_creation.sql"),
+                          include_str!("../../migrations/add_item_attributes.sql"),
+                          include_str!("../../migrations/add_picture_storage.sql"),
+                          include_str!("../../migrations/add_picture_metadata.sql"),
+                          include_str!("../../migrations/add_item_soft_delete.sql"),
+                          include_str!("../../migrations/add_item_audit.sql"),
+                          include_str!("../../migrations/add_item_version.sql"),
+                          include_str!("../../migrations/add_item_money.sql")] {
+            db_service.execute(migration).await.unwrap();
+        }
+        ItemStorageService::new(Box::new(db_service))
+    }
+
+    #[tokio::test]
+    async fn cacheditemstorageservice_update_item_invalidate_only_changed_item() {
+        let cached = CachedItemStorageService::new(item_storage().await, ItemCacheOptions::default());
+        let pen_id = cached.add_item(&Item::new(0, "pen", 1.0, true, None)).await.unwrap();
+        let cup_id = cached.add_item(&Item::new(0, "cup", 2.0, true, None)).await.unwrap();
+        let mut pen = cached.get_item(pen_id).await.unwrap().unwrap();
+        cached.get_item(cup_id).await.unwrap();
+        assert_eq!(2, cached.get_all_items().await.unwrap().len());
+        pen.set_descr("fountain pen");
+        cached.update_item(&pen).await.unwrap();
+        assert_eq!("fountain pen", cached.get_item(pen_id).await.unwrap().unwrap().get_descr());
+        assert_eq!("cup", cached.get_item(cup_id).await.unwrap().unwrap().get_descr());
+        assert_eq!("fountain pen", cached.get_all_items().await.unwrap()[0].get_descr());
+        let stats = cached.stats();
+        assert_eq!((1, 5), (stats.hits, stats.misses));
+        assert_eq!(0.5, ItemCacheStats { hits: 1, misses: 1, ..stats }.hit_ratio());
+    }
+
+    #[tokio::test]
+    async fn cacheditemstorageservice_change_through_wrapped_service_invalidate_cache() {
+        let cached = CachedItemStorageService::new(item_storage().await, ItemCacheOptions::default());
+        let pen_id = cached.add_item(&Item::new(0, "pen", 1.0, true, None)).await.unwrap();
+        assert!(cached.get_item(pen_id).await.unwrap().is_some());
+        cached.storage().clone().delete_item(pen_id, 1).await.unwrap();
+        assert!(cached.get_item(pen_id).await.unwrap().is_none());
+        assert!(cached.get_all_items().await.unwrap().is_empty());
+    }
+}
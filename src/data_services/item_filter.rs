@@ -0,0 +1,338 @@
+use std::collections::HashSet;
+use crate::data_services::database_service_base::{DatabaseRowValue, ToDatabaseValue};
+use crate::data_services::database_service_error::{DatabaseServiceError, DatabaseServiceErrorKind};
+
+const ITEM_COLUMNS: [&str; 5] = ["id", "descr", "amount", "currency", "active"];
+pub const ATTRIBUTES_PREFIX: &str = "attributes.";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FilterOperator {
+    Equal,
+    NotEqual,
+    LessThan,
+    LessOrEqual,
+    GreaterThan,
+    GreaterOrEqual,
+    Like
+}
+
+impl FilterOperator {
+    fn to_sql(self) -> &'static str {
+        match self {
+            FilterOperator::Equal => "=",
+            FilterOperator::NotEqual => "<>",
+            FilterOperator::LessThan => "<",
+            FilterOperator::LessOrEqual => "<=",
+            FilterOperator::GreaterThan => ">",
+            FilterOperator::GreaterOrEqual => ">=",
+            FilterOperator::Like => "LIKE"
+        }
+    }
+
+    fn parse(token: &str) -> Option<Self> {
+        match token.to_uppercase().as_str() {
+            "=" | "==" => Some(FilterOperator::Equal),
+            "!=" | "<>" => Some(FilterOperator::NotEqual),
+            "<" => Some(FilterOperator::LessThan),
+            "<=" => Some(FilterOperator::LessOrEqual),
+            ">" => Some(FilterOperator::GreaterThan),
+            ">=" => Some(FilterOperator::GreaterOrEqual),
+            "LIKE" => Some(FilterOperator::Like),
+            _ => None
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum FilterField {
+    Column(String),
+    // JSON path inside the attributes document, without the leading '$.'
+    Attribute(String)
+}
+
+impl FilterField {
+    pub fn parse(name: &str) -> Result<Self, DatabaseServiceError> {
+        if let Some(path) = name.strip_prefix(ATTRIBUTES_PREFIX) {
+            if !is_valid_json_path(path) {
+                return Err(invalid_filter(format!("The attribute path {} is not valid", path).as_str()));
+            }
+            return Ok(FilterField::Attribute(path.to_owned()));
+        }
+        if ITEM_COLUMNS.contains(&name) {
+            Ok(FilterField::Column(name.to_owned()))
+        } else {
+            Err(invalid_filter(format!("The field {} cannot be used in a filter", name).as_str()))
+        }
+    }
+
+    fn to_sql(&self, indexed_attributes: &HashSet<String>) -> String {
+        match self {
+            FilterField::Column(name) => name.to_owned(),
+            FilterField::Attribute(path) if indexed_attributes.contains(path) => attribute_column_name(path),
+            FilterField::Attribute(path) => format!("json_extract(attributes, '$.{}')", path)
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FilterCondition {
+    field: FilterField,
+    operator: FilterOperator,
+    value: DatabaseRowValue
+}
+
+// A conjunction of conditions on item columns and on JSON paths of the
+// attributes column, e.g. `attributes.color = 'red' AND amount > 10`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ItemFilter {
+    conditions: Vec<FilterCondition>
+}
+
+impl ItemFilter {
+    pub fn new() -> Self {
+        Self {
+            conditions: vec![]
+        }
+    }
+
+    pub fn with_condition(mut self,
+                          field: &str,
+                          operator: FilterOperator,
+                          value: impl ToDatabaseValue) -> Result<Self, DatabaseServiceError> {
+        let value = value.to_database_value()?;
+        if let DatabaseRowValue::Real(r) = value {
+            if !r.is_finite() {
+                return Err(invalid_filter(format!("The value {} cannot be used in a filter", r).as_str()));
+            }
+        }
+        if let DatabaseRowValue::Blob(_) = value {
+            return Err(invalid_filter("Blob values cannot be used in a filter"));
+        }
+        self.conditions.push(FilterCondition {
+            field: FilterField::parse(field)?,
+            operator,
+            value
+        });
+        Ok(self)
+    }
+
+    pub fn parse(expression: &str) -> Result<Self, DatabaseServiceError> {
+        let tokens = tokenize(expression)?;
+        let mut retval = ItemFilter::new();
+        if tokens.is_empty() {
+            return Ok(retval);
+        }
+        for chunk in tokens.split(|token| matches!(token, Token::Word(w) if w.eq_ignore_ascii_case("AND"))) {
+            let (field, operator, literal) = match chunk {
+                [Token::Word(field), Token::Word(operator), literal] => (field, operator, literal),
+                _ => return Err(invalid_filter(format!("The filter {} is not valid", expression).as_str()))
+            };
+            let operator = FilterOperator::parse(operator)
+                .ok_or_else(|| invalid_filter(format!("The operator {} is not supported", operator).as_str()))?;
+            let value = match literal {
+                Token::Text(t) => DatabaseRowValue::Text(t.to_owned()),
+                Token::Word(w) => parse_literal(w)?
+            };
+            retval = retval.with_condition(field, operator, value)?;
+        }
+        Ok(retval)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.conditions.is_empty()
+    }
+
+    // Returns the WHERE clause (with a leading space) or an empty string
+    // when there is no condition.
+    pub fn to_sql(&self, indexed_attributes: &HashSet<String>) -> String {
+        if self.conditions.is_empty() {
+            return String::new();
+        }
+        format!(" WHERE {}", self.to_sql_conditions(indexed_attributes).join(" AND "))
+    }
+
+    // Returns one SQL condition per filter condition. Attribute paths that
+    // have a generated column are compiled to that column so its index can
+    // be used.
+    pub fn to_sql_conditions(&self, indexed_attributes: &HashSet<String>) -> Vec<String> {
+        self.conditions.iter()
+            .map(|c| {
+                let field = c.field.to_sql(indexed_attributes);
+                match (&c.value, c.operator) {
+                    (DatabaseRowValue::Null, FilterOperator::Equal) => format!("{} IS NULL", field),
+                    (DatabaseRowValue::Null, FilterOperator::NotEqual) => format!("{} IS NOT NULL", field),
+                    (value, operator) => format!("{} {} {}", field, operator.to_sql(), to_sql_literal(value))
+                }
+            })
+            .collect()
+    }
+}
+
+// The name of the generated column of an attribute path. Each character
+// other than a letter or a digit is escaped with a distinct _ sequence so
+// that two paths never share a column, e.g. size.width and size_width.
+pub fn attribute_column_name(path: &str) -> String {
+    let escaped: String = path.chars()
+        .map(|c| match c {
+            '_' => "__".to_owned(),
+            '.' => "_d".to_owned(),
+            '[' => "_i".to_owned(),
+            ']' => "_e".to_owned(),
+            c => c.to_string()
+        })
+        .collect();
+    format!("attr_{}", escaped)
+}
+
+pub fn is_valid_json_path(path: &str) -> bool {
+    !path.is_empty() && path.split('.').all(|segment| {
+        let name = segment.split('[').next().unwrap_or_default();
+        let indexes_valid = segment[name.len()..].split_inclusive(']').all(|index| {
+            index.len() > 2 && index.starts_with('[') && index.ends_with(']')
+                && index[1..index.len() - 1].chars().all(|c| c.is_ascii_digit())
+        });
+        !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') && indexes_valid
+    })
+}
+
+pub fn to_sql_literal(value: &DatabaseRowValue) -> String {
+    match value {
+        DatabaseRowValue::Integer(i) => i.to_string(),
+        DatabaseRowValue::Text(t) => format!("'{}'", t.replace('\'', "''")),
+        DatabaseRowValue::Real(r) => r.to_string(),
+        DatabaseRowValue::Blob(b) => format!("X'{}'", b.iter().map(|byte| format!("{:02X}", byte)).collect::<String>()),
+        DatabaseRowValue::Bool(b) => b.to_string(),
+        DatabaseRowValue::Null => "NULL".to_owned()
+    }
+}
+
+fn invalid_filter(message: &str) -> DatabaseServiceError {
+    DatabaseServiceError::with_kind(DatabaseServiceErrorKind::InvalidFilter, message)
+}
+
+fn parse_literal(word: &str) -> Result<DatabaseRowValue, DatabaseServiceError> {
+    match word.to_lowercase().as_str() {
+        "true" => return Ok(DatabaseRowValue::Bool(true)),
+        "false" => return Ok(DatabaseRowValue::Bool(false)),
+        "null" => return Ok(DatabaseRowValue::Null),
+        _ => {}
+    }
+    if let Ok(i) = word.parse::<i64>() {
+        return Ok(DatabaseRowValue::Integer(i));
+    }
+    word.parse::<f64>()
+        .map(DatabaseRowValue::Real)
+        .map_err(|_| invalid_filter(format!("The value {} is not valid, text values must be quoted", word).as_str()))
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Text(String)
+}
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, DatabaseServiceError> {
+    let mut tokens = vec![];
+    let mut chars = expression.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '\'' {
+            chars.next();
+            let mut text = String::new();
+            loop {
+                match chars.next() {
+                    Some('\'') if chars.peek() == Some(&'\'') => {
+                        chars.next();
+                        text.push('\'');
+                    },
+                    Some('\'') => break,
+                    Some(other) => text.push(other),
+                    None => return Err(invalid_filter("Unterminated text value in filter"))
+                }
+            }
+            tokens.push(Token::Text(text));
+        } else if "=!<>".contains(c) {
+            let mut operator = String::new();
+            while let Some(&o) = chars.peek() {
+                if !"=!<>".contains(o) {
+                    break;
+                }
+                operator.push(o);
+                chars.next();
+            }
+            tokens.push(Token::Word(operator));
+        } else {
+            let mut word = String::new();
+            while let Some(&w) = chars.peek() {
+                if w.is_whitespace() || "'=!<>".contains(w) {
+                    break;
+                }
+                word.push(w);
+                chars.next();
+            }
+            tokens.push(Token::Word(word));
+        }
+    }
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn itemfilter_parse_with_attribute_path_return_json_extract() {
+        let filter = ItemFilter::parse("attributes.color = 'red'").unwrap();
+        assert_eq!(" WHERE json_extract(attributes, '$.color') = 'red'", filter.to_sql(&HashSet::new()));
+    }
+
+    #[test]
+    fn itemfilter_parse_with_multiple_conditions_return_conjunction() {
+        let filter = ItemFilter::parse("attributes.size.width>=10 and active = true AND descr LIKE 'O''Brien%'").unwrap();
+        assert_eq!(" WHERE json_extract(attributes, '$.size.width') >= 10 AND active = true AND descr LIKE 'O''Brien%'",
+                   filter.to_sql(&HashSet::new()));
+    }
+
+    #[test]
+    fn itemfilter_to_sql_with_indexed_attribute_return_generated_column() {
+        let filter = ItemFilter::parse("attributes.color = 'red'").unwrap();
+        let indexed = HashSet::from(["color".to_owned()]);
+        assert_eq!(" WHERE attr_color = 'red'", filter.to_sql(&indexed));
+    }
+
+    #[test]
+    fn attribute_column_name_with_similar_paths_return_distinct_names() {
+        assert_eq!("attr_size_dwidth", attribute_column_name("size.width"));
+        assert_eq!("attr_size__width", attribute_column_name("size_width"));
+        assert_eq!("attr_tags_i0_e", attribute_column_name("tags[0]"));
+        assert_eq!("attr_tags__0__", attribute_column_name("tags_0_"));
+    }
+
+    #[test]
+    fn itemfilter_parse_with_null_return_is_null() {
+        let filter = ItemFilter::parse("attributes.tags[0] = null").unwrap();
+        assert_eq!(" WHERE json_extract(attributes, '$.tags[0]') IS NULL", filter.to_sql(&HashSet::new()));
+    }
+
+    #[test]
+    fn itemfilter_parse_with_empty_expression_return_no_where_clause() {
+        assert_eq!("", ItemFilter::parse("  ").unwrap().to_sql(&HashSet::new()));
+    }
+
+    #[test]
+    fn itemfilter_parse_with_unknown_column_return_err() {
+        let err = ItemFilter::parse("picture = 'x'").unwrap_err();
+        assert_eq!(DatabaseServiceErrorKind::InvalidFilter, err.kind());
+    }
+
+    #[test]
+    fn itemfilter_parse_with_injected_path_return_err() {
+        assert!(ItemFilter::parse("attributes.color') = 1 OR 1=1 -- = 'red'").is_err());
+    }
+
+    #[test]
+    fn itemfilter_parse_with_unquoted_text_return_err() {
+        assert!(ItemFilter::parse("descr = red").is_err());
+    }
+}
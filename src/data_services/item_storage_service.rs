@@ -1,129 +1,1526 @@
-use crate::data_services::database_service_base::DatabaseServiceTrait;
-use crate::data_services::database_service_error::DatabaseServiceError;
-use crate::models::item::Item;
+use std::collections::HashSet;
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::Duration;
+use sha2::{Digest, Sha256};
+use tokio::fs::File;
+use tokio::io::AsyncWrite;
+use crate::data_services::custom_type_conversions::Json;
+use crate::data_services::database_service_base::{Blob, DatabaseBlobTrait, DatabaseRow, DatabaseServiceTrait, DatabaseTransactionTrait, ToDatabaseValue};
+use crate::data_services::database_service_error::{DatabaseServiceError, DatabaseServiceErrorKind};
+use crate::data_services::item_filter::{attribute_column_name, is_valid_json_path, to_sql_literal, ItemFilter, ATTRIBUTES_PREFIX};
+use crate::data_services::item_validation::ItemValidator;
+use crate::data_services::picture_processing::{describe_dimensions, describe_picture, detect_picture_format, make_thumbnail, JpegSizeScanner,
+                                               PictureFormat, PictureInfo, PictureLimits};
+use crate::models::item::{compute_picture_hash, format_picture_hash, Item, ItemAttributes, ItemChanges, ItemField};
+use crate::models::item_audit::{diff_fields, diff_versions, item_fields, merged_item_fields, FieldChange, ItemAuditAction, ItemAuditEntry, ItemFields};
+use crate::models::money::{Currency, Money, DEFAULT_CURRENCY};
 
+const ITEM_COLUMNS: &str = "id, descr, amount_minor, currency, active, picture_hash, attributes, deleted_at, version";
+const DEFAULT_ACTOR: &str = "unknown";
+const DEFAULT_TRASH_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+// Bytes kept from the start of a streamed picture to detect its format
+const PICTURE_HEADER_SIZE: usize = 64 * 1024;
+const INSERT_COLUMNS: &str = "descr, amount, amount_minor, currency, active, picture_hash, attributes";
+const UPSERT_ASSIGNMENTS: &str = "descr = excluded.descr, amount = excluded.amount, amount_minor = excluded.amount_minor, currency = excluded.currency, active = excluded.active, picture_hash = excluded.picture_hash, attributes = excluded.attributes, version = version + 1";
+// Rows per multi-row INSERT, keeping the statements of large batches short
+const BULK_INSERT_CHUNK_SIZE: usize = 500;
+const BULK_SAVEPOINT: &str = "bulk_item";
+
+// One result per item of a bulk operation, in the order of the items
+pub type BulkResults<T> = Vec<Result<T, DatabaseServiceError>>;
+
+// Identifies the stored item an upserted item replaces
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UpsertKey {
+    // An item without id (0 or less) is always inserted, an item with an id
+    // that does not exist is inserted with that id
+    Id,
+    // descr or an attribute path such as attributes.sku, made unique among
+    // the live items by create_natural_key
+    Natural(String)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpsertOutcome {
+    Inserted(i64),
+    Updated(i64)
+}
+
+impl UpsertOutcome {
+    pub fn item_id(self) -> i64 {
+        match self {
+            UpsertOutcome::Inserted(item_id) | UpsertOutcome::Updated(item_id) => item_id
+        }
+    }
+}
+
+// Clones share the database service, the known attribute indexes and the
+// count of changes, so a clone per task or request is cheap. The settings
+// changed through the set_ methods only apply to the clone they are called
+// on.
+#[derive(Clone)]
 pub struct ItemStorageService {
-    db_service: Box<dyn DatabaseServiceTrait>
+    db_service: Arc<dyn DatabaseServiceTrait>,
+    indexed_attributes: Arc<RwLock<HashSet<String>>>,
+    change_count: Arc<AtomicU64>,
+    picture_limits: PictureLimits,
+    trash_retention: Duration,
+    // Recorded in the audit log for every change
+    actor: Arc<str>,
+    item_validator: Arc<ItemValidator>
 }
 
 impl ItemStorageService {
     pub fn new(db_service: Box<dyn DatabaseServiceTrait>) -> Self {
+        ItemStorageService::with_shared(Arc::from(db_service))
+    }
+
+    // Several services, e.g. with other settings, can use the same database
+    // service.
+    pub fn with_shared(db_service: Arc<dyn DatabaseServiceTrait>) -> Self {
         ItemStorageService {
-            db_service
+            db_service,
+            indexed_attributes: Arc::new(RwLock::new(HashSet::new())),
+            change_count: Arc::new(AtomicU64::new(0)),
+            picture_limits: PictureLimits::default(),
+            trash_retention: DEFAULT_TRASH_RETENTION,
+            actor: Arc::from(DEFAULT_ACTOR),
+            item_validator: Arc::new(ItemValidator::default())
+        }
+    }
+
+    // Number of transactions changing items committed through this service
+    // or its clones, e.g. to tell when cached items may be outdated
+    pub fn change_count(&self) -> u64 {
+        self.change_count.load(Ordering::SeqCst)
+    }
+
+    async fn finish_changes<T>(&self,
+                               transaction: Box<dyn DatabaseTransactionTrait>,
+                               result: Result<T, DatabaseServiceError>) -> Result<T, DatabaseServiceError> {
+        let result = finish_transaction(transaction, result).await;
+        if result.is_ok() {
+            self.change_count.fetch_add(1, Ordering::SeqCst);
+        }
+        result
+    }
+
+    pub fn get_actor(&self) -> &str {
+        &self.actor
+    }
+
+    // Identifies who makes the next changes in the audit log, e.g. a user
+    // name or a process name.
+    pub fn set_actor(&mut self, actor: &str) {
+        self.actor = Arc::from(actor);
+    }
+
+    pub fn get_trash_retention(&self) -> Duration {
+        self.trash_retention
+    }
+
+    // Items stay in the trash for this long before purge removes them.
+    pub fn set_trash_retention(&mut self, trash_retention: Duration) {
+        self.trash_retention = trash_retention;
+    }
+
+    pub fn get_picture_limits(&self) -> &PictureLimits {
+        &self.picture_limits
+    }
+
+    pub fn set_picture_limits(&mut self, picture_limits: PictureLimits) {
+        self.picture_limits = picture_limits;
+    }
+
+    pub fn get_item_validator(&self) -> &ItemValidator {
+        &self.item_validator
+    }
+
+    // Replaces the rules items must follow to be added or updated.
+    pub fn set_item_validator(&mut self, item_validator: ItemValidator) {
+        self.item_validator = Arc::new(item_validator);
+    }
+
+    // Returns an InvalidItem error listing every rule the item breaks.
+    pub fn validate_item(&self, item: &Item) -> Result<(), DatabaseServiceError> {
+        let field_errors = self.item_validator.validate(item);
+        if field_errors.is_empty() {
+            Ok(())
+        } else {
+            Err(DatabaseServiceError::invalid_item(field_errors))
+        }
+    }
+
+    pub async fn add_item(&self, item: &Item) -> Result<i64, DatabaseServiceError> {
+        self.validate_item(item)?;
+        let mut transaction = self.db_service.begin().await?;
+        let result = add_item_in_transaction(transaction.as_mut(), item, &self.picture_limits, &self.actor).await;
+        self.finish_changes(transaction, result).await
+    }
+
+    // The item version must be the version of the stored item, which is then
+    // incremented. A Conflict error is returned when the stored item was
+    // changed since it was read.
+    pub async fn update_item(&self, updated_item: &Item) -> Result<u64, DatabaseServiceError> {
+        self.validate_item(updated_item)?;
+        let mut transaction = self.db_service.begin().await?;
+        let result = update_item_in_transaction(transaction.as_mut(), updated_item, &self.picture_limits, &self.actor).await;
+        self.finish_changes(transaction, result).await
+    }
+
+    // Moves the item to the trash. It is no longer returned nor updated but
+    // keeps its picture until it is purged. Returns 0 when the item does not
+    // exist or is already in the trash, and a Conflict error when the stored
+    // item is not at the expected version.
+    pub async fn delete_item(&self, item_id: i64, expected_version: i64) -> Result<u64, DatabaseServiceError> {
+        let mut transaction = self.db_service.begin().await?;
+        let result = delete_item_in_transaction(transaction.as_mut(), item_id, expected_version, &self.actor).await;
+        self.finish_changes(transaction, result).await
+    }
+
+    // Adds the items in one transaction and returns their ids. Items that are
+    // invalid or have an unsupported picture are skipped and get an error;
+    // any other error rolls back the whole batch.
+    pub async fn add_items(&self, items: &[Item]) -> Result<BulkResults<i64>, DatabaseServiceError> {
+        let mut retval: BulkResults<i64> = items.iter()
+            .map(|item| self.check_new_item(item).map(|_| 0))
+            .collect();
+        let valid_items: Vec<&Item> = items.iter()
+            .zip(retval.iter())
+            .filter(|(_, result)| result.is_ok())
+            .map(|(item, _)| item)
+            .collect();
+        let mut transaction = self.db_service.begin().await?;
+        let result = add_items_in_transaction(transaction.as_mut(), &valid_items, &self.picture_limits, &self.actor).await;
+        let mut item_ids = self.finish_changes(transaction, result).await?.into_iter();
+        for result in retval.iter_mut().filter(|result| result.is_ok()) {
+            *result = Ok(item_ids.next().unwrap_or_default());
+        }
+        Ok(retval)
+    }
+
+    // Updates the items in one transaction, each like update_item. A failed
+    // item, e.g. on a version conflict, leaves the others updated.
+    pub async fn update_items(&self, items: &[Item]) -> Result<BulkResults<u64>, DatabaseServiceError> {
+        let mut transaction = self.db_service.begin().await?;
+        let result = update_items_in_transaction(transaction.as_mut(), items, &self.item_validator, &self.picture_limits, &self.actor).await;
+        self.finish_changes(transaction, result).await
+    }
+
+    // Moves the items, given as (id, expected version), to the trash in one
+    // transaction, each like delete_item.
+    pub async fn delete_items(&self, items: &[(i64, i64)]) -> Result<BulkResults<u64>, DatabaseServiceError> {
+        let mut transaction = self.db_service.begin().await?;
+        let result = delete_items_in_transaction(transaction.as_mut(), items, &self.actor).await;
+        self.finish_changes(transaction, result).await
+    }
+
+    // Applies the changes to every live item matching the filter, e.g. to
+    // deactivate all items below an amount. Returns the number of items
+    // changed; nothing is changed when one of them fails.
+    pub async fn update_items_where(&self, filter: &ItemFilter, changes: &ItemChanges) -> Result<u64, DatabaseServiceError> {
+        let where_clause = self.live_items_where_clause(filter);
+        let mut transaction = self.db_service.begin().await?;
+        let result = update_items_where_in_transaction(transaction.as_mut(),
+                                                       where_clause.as_str(),
+                                                       changes,
+                                                       &self.item_validator,
+                                                       &self.picture_limits,
+                                                       &self.actor).await;
+        self.finish_changes(transaction, result).await
+    }
+
+    // Moves every live item matching the filter to the trash and returns
+    // their number.
+    pub async fn delete_items_where(&self, filter: &ItemFilter) -> Result<u64, DatabaseServiceError> {
+        let where_clause = self.live_items_where_clause(filter);
+        let mut transaction = self.db_service.begin().await?;
+        let result = delete_items_where_in_transaction(transaction.as_mut(), where_clause.as_str(), &self.actor).await;
+        self.finish_changes(transaction, result).await
+    }
+
+    fn check_new_item(&self, item: &Item) -> Result<(), DatabaseServiceError> {
+        self.validate_item(item)?;
+        if !item.get_picture().is_empty() {
+            describe_picture(item.get_picture(), &self.picture_limits)?;
+        }
+        Ok(())
+    }
+
+    // Inserts the item, or replaces all the fields of the live item with the
+    // same key, whatever its version. Items in the trash are never replaced:
+    // upserting the id of a trashed item fails, and a natural key only has
+    // to be unique among live items.
+    pub async fn upsert_item(&self, item: &Item, key: &UpsertKey) -> Result<UpsertOutcome, DatabaseServiceError> {
+        self.validate_item(item)?;
+        if let UpsertKey::Natural(key) = key {
+            natural_key_column(key)?;
+        }
+        let mut transaction = self.db_service.begin().await?;
+        let result = upsert_item_in_transaction(transaction.as_mut(), item, key, &self.picture_limits, &self.actor).await;
+        self.finish_changes(transaction, result).await
+    }
+
+    // Creates the unique index upsert_item needs to use the key as
+    // UpsertKey::Natural. An attribute path also gets its generated column,
+    // see create_attribute_index.
+    pub async fn create_natural_key(&self, key: &str) -> Result<(), DatabaseServiceError> {
+        let column_name = natural_key_column(key)?;
+        if let Some(path) = key.strip_prefix(ATTRIBUTES_PREFIX) {
+            self.create_attribute_index(path).await?;
+        }
+        self.db_service.execute(format!("CREATE UNIQUE INDEX IF NOT EXISTS idx_item_key_{} ON item({}) WHERE deleted_at IS NULL",
+                                        column_name,
+                                        column_name).as_str()).await?;
+        Ok(())
+    }
+
+    // Changes the given fields of a live item, whatever its version. Returns
+    // 0 when the item does not exist or is in the trash.
+    pub async fn patch_item(&self, item_id: i64, changes: &ItemChanges) -> Result<u64, DatabaseServiceError> {
+        let mut transaction = self.db_service.begin().await?;
+        let result = patch_item_in_transaction(transaction.as_mut(),
+                                               item_id,
+                                               changes,
+                                               &self.item_validator,
+                                               &self.picture_limits,
+                                               &self.actor).await;
+        self.finish_changes(transaction, result).await
+    }
+
+    pub async fn restore_item(&self, item_id: i64) -> Result<u64, DatabaseServiceError> {
+        let mut transaction = self.db_service.begin().await?;
+        let result = restore_item_in_transaction(transaction.as_mut(), item_id, &self.actor).await;
+        self.finish_changes(transaction, result).await
+    }
+
+    // Returns the trashed items, most recently deleted first.
+    pub async fn list_trash(&self) -> Result<Vec<Item>, DatabaseServiceError> {
+        let rows = self.db_service.select(format!("SELECT {} FROM item WHERE deleted_at IS NOT NULL ORDER BY deleted_at DESC",
+                                                  ITEM_COLUMNS).as_str()).await?;
+        rows.iter().map(item_from_row).collect()
+    }
+
+    // Permanently removes the items trashed for longer than the trash
+    // retention, with the pictures no other item references. Returns the
+    // number of items removed.
+    pub async fn purge(&self) -> Result<usize, DatabaseServiceError> {
+        let mut transaction = self.db_service.begin().await?;
+        let result = purge_in_transaction(transaction.as_mut(), self.trash_retention, &self.actor).await;
+        self.finish_changes(transaction, result).await
+    }
+
+    pub async fn get_item(&self, item_id: i64) -> Result<Option<Item>, DatabaseServiceError> {
+        let rows = self.db_service.select(format!("SELECT {} FROM item WHERE id = {} AND deleted_at IS NULL",
+                                                  ITEM_COLUMNS,
+                                                  item_id).as_str()).await?;
+        rows.first().map(item_from_row).transpose()
+    }
+
+    // Items are returned without their picture bytes, only with the
+    // reference to the stored picture. See get_items_with_pictures.
+    pub async fn get_all_items(&self) -> Result<Vec<Item>, DatabaseServiceError> {
+        self.get_items(&ItemFilter::new()).await
+    }
+
+    pub async fn get_items(&self, filter: &ItemFilter) -> Result<Vec<Item>, DatabaseServiceError> {
+        let rows = self.db_service.select(format!("SELECT {} FROM item{}",
+                                                  ITEM_COLUMNS,
+                                                  self.live_items_where_clause(filter)).as_str()).await?;
+        rows.iter().map(item_from_row).collect()
+    }
+
+    pub async fn get_all_items_with_pictures(&self) -> Result<Vec<Item>, DatabaseServiceError> {
+        self.get_items_with_pictures(&ItemFilter::new()).await
+    }
+
+    pub async fn get_items_with_pictures(&self, filter: &ItemFilter) -> Result<Vec<Item>, DatabaseServiceError> {
+        let rows = self.db_service.select(format!("SELECT {}, picture.data AS picture FROM item LEFT JOIN picture ON picture.hash = item.picture_hash{}",
+                                                  ITEM_COLUMNS,
+                                                  self.live_items_where_clause(filter)).as_str()).await?;
+        rows.iter().map(item_from_row).collect()
+    }
+
+    // Returns the changes of an item, oldest first, including the changes
+    // made before it was purged.
+    pub async fn item_history(&self, item_id: i64) -> Result<Vec<ItemAuditEntry>, DatabaseServiceError> {
+        let rows = self.db_service.select(format!("SELECT id, item_id, action, actor, changed_at, old_values, new_values FROM item_audit WHERE item_id = {} ORDER BY id",
+                                                  item_id).as_str()).await?;
+        rows.iter().map(audit_entry_from_row).collect()
+    }
+
+    // Compares two versions of an item, a version being the state of the
+    // item after an entry of its history (numbered from 1).
+    pub async fn diff_item_versions(&self,
+                                    item_id: i64,
+                                    from_version: usize,
+                                    to_version: usize) -> Result<Vec<FieldChange>, DatabaseServiceError> {
+        let history = self.item_history(item_id).await?;
+        diff_versions(&history, from_version, to_version)
+            .ok_or_else(|| DatabaseServiceError::new(format!("The item {} has {} versions, unable to compare versions {} and {}",
+                                                             item_id,
+                                                             history.len(),
+                                                             from_version,
+                                                             to_version).as_str()))
+    }
+
+    fn live_items_where_clause(&self, filter: &ItemFilter) -> String {
+        let mut conditions = vec!["deleted_at IS NULL".to_owned()];
+        let indexed_attributes = self.indexed_attributes.read().unwrap_or_else(PoisonError::into_inner);
+        conditions.extend(filter.to_sql_conditions(&indexed_attributes));
+        format!(" WHERE {}", conditions.join(" AND "))
+    }
+
+    pub async fn get_picture(&self, picture_hash: &str) -> Result<Option<Blob>, DatabaseServiceError> {
+        let rows = self.db_service.select(format!("SELECT data FROM picture WHERE hash = {}",
+                                                  to_sql_literal(&picture_hash.to_database_value()?)).as_str()).await?;
+        rows.first()
+            .map(|row| row.get("data"))
+            .transpose()
+    }
+
+    // Returns None for unknown pictures and for legacy pictures whose format
+    // was not recognized.
+    pub async fn get_picture_info(&self, picture_hash: &str) -> Result<Option<PictureInfo>, DatabaseServiceError> {
+        let rows = self.db_service.select(format!("SELECT mime_type, width, height FROM picture WHERE hash = {}",
+                                                  to_sql_literal(&picture_hash.to_database_value()?)).as_str()).await?;
+        let row = match rows.first() {
+            Some(row) => row,
+            None => return Ok(None)
+        };
+        let format = row.get::<Option<String>>("mime_type")?
+            .and_then(|mime_type| PictureFormat::from_mime_type(&mime_type));
+        match format {
+            Some(format) => Ok(Some(PictureInfo {
+                format,
+                width: row.get("width")?,
+                height: row.get("height")?
+            })),
+            None => Ok(None)
+        }
+    }
+
+    // Thumbnails are PNG for raster pictures and cropped text for text art.
+    // Pictures stored without a thumbnail (streamed or migrated ones) get it
+    // generated and cached on first access.
+    pub async fn get_picture_thumbnail(&self, picture_hash: &str) -> Result<Option<Blob>, DatabaseServiceError> {
+        let hash_literal = to_sql_literal(&picture_hash.to_database_value()?);
+        let rows = self.db_service.select(format!("SELECT thumbnail FROM picture WHERE hash = {}",
+                                                  hash_literal).as_str()).await?;
+        match rows.first() {
+            Some(row) => if let Some(thumbnail) = row.get::<Option<Blob>>("thumbnail")? {
+                return Ok(Some(thumbnail));
+            },
+            None => return Ok(None)
+        }
+        let picture = match self.get_picture(picture_hash).await? {
+            Some(picture) => picture,
+            None => return Ok(None)
+        };
+        let (info, thumbnail) = match prepare_picture(&picture, &self.picture_limits) {
+            Ok(prepared) => prepared,
+            Err(_) => return Ok(None)
+        };
+        self.db_service.update(format!("UPDATE picture SET mime_type = '{}', width = {}, height = {}, thumbnail = ?1 WHERE hash = {}",
+                                       info.format.mime_type(),
+                                       info.width,
+                                       info.height,
+                                       hash_literal).as_str(),
+                                       Some(vec![thumbnail.to_database_value()?])).await?;
+        Ok(Some(thumbnail))
+    }
+
+    // Streams the bytes of a stored picture without loading them in memory.
+    pub async fn open_picture_reader(&self, picture_hash: &str) -> Result<Option<Box<dyn DatabaseBlobTrait>>, DatabaseServiceError> {
+        let rows = self.db_service.select(format!("SELECT rowid FROM picture WHERE hash = {}",
+                                                  to_sql_literal(&picture_hash.to_database_value()?)).as_str()).await?;
+        match rows.first() {
+            Some(row) => Ok(Some(self.db_service.open_blob("picture", "data", row.get("rowid")?, false).await?)),
+            None => Ok(None)
+        }
+    }
+
+    // Starts streaming a new picture of picture_size bytes for an item. The
+    // bytes are written to a placeholder picture row which becomes the item
+    // picture when PictureWriter::finish is called. The placeholder is
+    // deleted when the writer fails or is dropped without being finished.
+    // The writer holds the write lock, and with a reader pool the writer
+    // connection, until then, so every other write waits for it: only
+    // stream bytes already at hand, e.g. a local file, not a slow upload.
+    pub async fn open_picture_writer(&self, item_id: i64, picture_size: u64) -> Result<PictureWriter<'_>, DatabaseServiceError> {
+        let max_size = self.picture_limits.max_size.min(i32::MAX as u64);
+        if picture_size == 0 || picture_size > max_size {
+            return Err(DatabaseServiceError::with_kind(DatabaseServiceErrorKind::InvalidPicture,
+                                                       format!("The picture size must be between 1 and {} bytes", max_size).as_str()));
+        }
+        let items = self.db_service.select(format!("SELECT version FROM item WHERE id = {} AND deleted_at IS NULL", item_id).as_str()).await?;
+        let item_version = match items.first() {
+            Some(row) => row.get::<i64>("version")?,
+            None => return Err(DatabaseServiceError::new(format!("The item {} does not exist", item_id).as_str()))
+        };
+        let staging_row_id = self.db_service.insert(format!("INSERT INTO picture (hash, data, ref_count) VALUES('pending:' || hex(randomblob(16)), zeroblob({}), 0)",
+                                                            picture_size).as_str(),
+                                                            None).await?;
+        let blob = match self.db_service.open_blob("picture", "data", staging_row_id, true).await {
+            Ok(blob) => blob,
+            Err(e) => {
+                let _ = self.db_service.delete(format!("DELETE FROM picture WHERE rowid = {}", staging_row_id).as_str()).await;
+                return Err(e);
+            }
+        };
+        Ok(PictureWriter {
+            storage: self,
+            blob: Some(blob),
+            hasher: Sha256::new(),
+            header: Vec::with_capacity(PICTURE_HEADER_SIZE.min(picture_size as usize)),
+            jpeg_scanner: JpegSizeScanner::new(),
+            written_size: 0,
+            item_id,
+            item_version,
+            staging_row_id
+        })
+    }
+
+    // Streams an image file to the item picture and returns the picture hash.
+    pub async fn import_picture_file(&self, item_id: i64, path: &Path) -> Result<String, DatabaseServiceError> {
+        let mut file = File::open(path).await
+            .map_err(|e| DatabaseServiceError::new(format!("Unable to open {}: {}", path.display(), e).as_str()))?;
+        let file_size = file.metadata().await
+            .map_err(|e| DatabaseServiceError::new(format!("Unable to read {}: {}", path.display(), e).as_str()))?
+            .len();
+        let mut writer = self.open_picture_writer(item_id, file_size).await?;
+        if let Err(e) = tokio::io::copy(&mut file, &mut writer).await {
+            // Finishing with missing bytes fails and removes the partial picture
+            let _ = writer.finish().await;
+            return Err(DatabaseServiceError::new(format!("Unable to read {}: {}", path.display(), e).as_str()));
+        }
+        writer.finish().await
+    }
+
+    // Deletes the placeholders of streamed pictures that were never finished
+    // nor deleted, e.g. by a process that crashed, and returns their number.
+    // Only call it when no picture is being streamed, e.g. at startup.
+    pub async fn delete_pending_pictures(&self) -> Result<u64, DatabaseServiceError> {
+        self.db_service.delete("DELETE FROM picture WHERE hash LIKE 'pending:%' AND ref_count = 0").await
+    }
+
+    // Moves the pictures still stored in the legacy item.picture column to
+    // the picture table. Returns the number of items migrated.
+    pub async fn migrate_legacy_pictures(&self) -> Result<usize, DatabaseServiceError> {
+        let mut transaction = self.db_service.begin().await?;
+        let result = migrate_legacy_pictures_in_transaction(transaction.as_mut(), &self.picture_limits, &self.actor).await;
+        self.finish_changes(transaction, result).await
+    }
+
+    // Adds a virtual generated column extracting the attribute path, indexes
+    // it, and makes subsequent filters on that path use the column.
+    pub async fn create_attribute_index(&self, path: &str) -> Result<(), DatabaseServiceError> {
+        if !is_valid_json_path(path) {
+            return Err(DatabaseServiceError::with_kind(DatabaseServiceErrorKind::InvalidFilter,
+                                                       format!("The attribute path {} is not valid", path).as_str()));
+        }
+        let column_name = attribute_column_name(path);
+        let existing_columns = self.db_service.select(format!("SELECT name FROM pragma_table_xinfo('item') WHERE name = '{}'",
+                                                              column_name).as_str()).await?;
+        if existing_columns.is_empty() {
+            self.db_service.execute(format!("ALTER TABLE item ADD COLUMN {} GENERATED ALWAYS AS (json_extract(attributes, '$.{}')) VIRTUAL",
+                                            column_name,
+                                            path).as_str()).await?;
+        }
+        self.db_service.execute(format!("CREATE INDEX IF NOT EXISTS idx_item_{} ON item({})",
+                                        column_name,
+                                        column_name).as_str()).await?;
+        self.register_attribute_index(path);
+        Ok(())
+    }
+
+    // Declares an attribute index created outside of this service (e.g. by
+    // a migration or a previous run) so that filters make use of it.
+    pub fn register_attribute_index(&self, path: &str) {
+        self.indexed_attributes.write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(path.to_owned());
+    }
+}
+
+pub struct PictureWriter<'a> {
+    storage: &'a ItemStorageService,
+    blob: Option<Box<dyn DatabaseBlobTrait>>,
+    hasher: Sha256,
+    header: Vec<u8>,
+    // A JPEG may have its dimensions after the header
+    jpeg_scanner: JpegSizeScanner,
+    written_size: u64,
+    item_id: i64,
+    // The version of the item when the writer was opened: finish fails if
+    // the item changed since
+    item_version: i64,
+    staging_row_id: i64
+}
+
+impl PictureWriter<'_> {
+    // Makes the written picture the item picture and returns its hash. When
+    // the same picture is already stored, the written copy is discarded.
+    pub async fn finish(mut self) -> Result<String, DatabaseServiceError> {
+        let blob = self.blob.take();
+        let expected_size = blob.as_ref().map(|b| b.size()).unwrap_or_default();
+        // Closing the blob commits the bytes written
+        let closed = blob.map(|blob| blob.close()).unwrap_or(Ok(()));
+        let result = match closed {
+            Err(e) => Err(e),
+            Ok(()) if self.written_size == expected_size => self.attach().await,
+            Ok(()) => Err(DatabaseServiceError::new(format!("Only {} of the {} bytes of the picture were written",
+                                                            self.written_size,
+                                                            expected_size).as_str()))
+        };
+        if result.is_err() {
+            let _ = self.storage.db_service.delete(format!("DELETE FROM picture WHERE rowid = {}",
+                                                           self.staging_row_id).as_str()).await;
+        }
+        result
+    }
+
+    // Only the header of the picture is kept, the thumbnail is generated on
+    // the first call to get_picture_thumbnail.
+    async fn attach(&mut self) -> Result<String, DatabaseServiceError> {
+        let info = match (detect_picture_format(&self.header), self.jpeg_scanner.dimensions()) {
+            (Some(PictureFormat::Jpeg), Some((width, height))) =>
+                describe_dimensions(PictureFormat::Jpeg, width, height, &self.storage.picture_limits)?,
+            _ => describe_picture(&self.header, &self.storage.picture_limits)?
+        };
+        if info.format == PictureFormat::Text && self.written_size > self.header.len() as u64 {
+            return Err(DatabaseServiceError::with_kind(DatabaseServiceErrorKind::InvalidPicture,
+                                                       format!("Text pictures larger than {} bytes cannot be streamed",
+                                                               PICTURE_HEADER_SIZE).as_str()));
+        }
+        let picture_hash = format_picture_hash(&self.hasher.clone().finalize());
+        let mut transaction = self.storage.db_service.begin().await?;
+        let result = attach_streamed_picture(transaction.as_mut(),
+                                             self.item_id,
+                                             self.item_version,
+                                             self.staging_row_id,
+                                             &picture_hash,
+                                             &info,
+                                             &self.storage.actor).await
+            .map(|_| picture_hash);
+        self.storage.finish_changes(transaction, result).await
+    }
+}
+
+// A writer dropped without finish, e.g. on an upload abandoned half way,
+// deletes its placeholder in the background.
+impl Drop for PictureWriter<'_> {
+    fn drop(&mut self) {
+        let Some(blob) = self.blob.take() else { return };
+        // The blob keeps its row locked until it is closed
+        drop(blob);
+        let db_service = self.storage.db_service.clone();
+        let query = format!("DELETE FROM picture WHERE rowid = {}", self.staging_row_id);
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                runtime.spawn(async move {
+                    let _ = db_service.delete(query.as_str()).await;
+                });
+            },
+            Err(_) => tracing::warn!("the picture placeholder {} cannot be deleted outside of a runtime", self.staging_row_id)
+        }
+    }
+}
+
+impl AsyncWrite for PictureWriter<'_> {
+    fn poll_write(self: Pin<&mut Self>,
+                  cx: &mut Context<'_>,
+                  buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let blob = match this.blob.as_mut() {
+            Some(blob) => blob,
+            None => return Poll::Ready(Err(io::Error::other("The picture writer is finished")))
+        };
+        let result = Pin::new(blob.as_mut()).poll_write(cx, buf);
+        if let Poll::Ready(Ok(count)) = result {
+            let header_count = count.min(PICTURE_HEADER_SIZE - this.header.len());
+            this.header.extend_from_slice(&buf[..header_count]);
+            this.jpeg_scanner.feed(&buf[..count]);
+            this.hasher.update(&buf[..count]);
+            this.written_size += count as u64;
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut().blob.as_mut() {
+            Some(blob) => Pin::new(blob.as_mut()).poll_flush(cx),
+            None => Poll::Ready(Ok(()))
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut().blob.as_mut() {
+            Some(blob) => Pin::new(blob.as_mut()).poll_shutdown(cx),
+            None => Poll::Ready(Ok(()))
+        }
+    }
+}
+
+async fn attach_streamed_picture(transaction: &mut dyn DatabaseTransactionTrait,
+                                 item_id: i64,
+                                 item_version: i64,
+                                 staging_row_id: i64,
+                                 picture_hash: &str,
+                                 info: &PictureInfo,
+                                 actor: &str) -> Result<(), DatabaseServiceError> {
+    let existing_pictures = transaction.select(format!("SELECT rowid FROM picture WHERE hash = '{}'",
+                                                       picture_hash).as_str()).await?;
+    if existing_pictures.is_empty() {
+        transaction.update(format!("UPDATE picture SET hash = '{}', ref_count = 1, mime_type = '{}', width = {}, height = {} WHERE rowid = {}",
+                                   picture_hash,
+                                   info.format.mime_type(),
+                                   info.width,
+                                   info.height,
+                                   staging_row_id).as_str(),
+                                   None).await?;
+    } else {
+        transaction.delete(format!("DELETE FROM picture WHERE rowid = {}",
+                                   staging_row_id).as_str()).await?;
+        transaction.update(format!("UPDATE picture SET ref_count = ref_count + 1 WHERE hash = '{}'",
+                                   picture_hash).as_str(),
+                                   None).await?;
+    }
+    let rows = transaction.select(format!("SELECT picture_hash, version FROM item WHERE id = {} AND deleted_at IS NULL",
+                                          item_id).as_str()).await?;
+    let previous_picture_hash = match rows.first() {
+        Some(row) if row.get::<i64>("version")? != item_version =>
+            return Err(version_conflict(item_id, item_version, row.get("version")?)),
+        Some(row) => row.get::<Option<String>>("picture_hash")?,
+        None => return Err(DatabaseServiceError::new(format!("The item {} does not exist", item_id).as_str()))
+    };
+    transaction.update(format!("UPDATE item SET picture_hash = '{}', version = version + 1 WHERE id = {} AND version = {}",
+                               picture_hash,
+                               item_id,
+                               item_version).as_str(),
+                               None).await?;
+    record_picture_change(transaction, item_id, previous_picture_hash.as_deref(), picture_hash, actor).await?;
+    if let Some(previous_picture_hash) = previous_picture_hash {
+        release_picture(transaction, &previous_picture_hash).await?;
+    }
+    Ok(())
+}
+
+async fn finish_transaction<T>(transaction: Box<dyn DatabaseTransactionTrait>,
+                               result: Result<T, DatabaseServiceError>) -> Result<T, DatabaseServiceError> {
+    match result {
+        Ok(value) => {
+            transaction.commit().await?;
+            Ok(value)
+        },
+        Err(e) => {
+            let _ = transaction.rollback().await;
+            Err(e)
+        }
+    }
+}
+
+async fn add_item_in_transaction(transaction: &mut dyn DatabaseTransactionTrait,
+                                 item: &Item,
+                                 picture_limits: &PictureLimits,
+                                 actor: &str) -> Result<i64, DatabaseServiceError> {
+    acquire_picture(transaction, item, picture_limits).await?;
+    let item_id = transaction.insert(format!("INSERT INTO item ({}) VALUES({})", INSERT_COLUMNS, item_values_sql(item, "?1")?).as_str(),
+                                     Some(vec![Json(item.get_attributes()).to_database_value()?])).await?;
+    record_change(transaction, item_id, ItemAuditAction::Insert, actor, None, Some(item_fields(item))).await?;
+    Ok(item_id)
+}
+
+// The rows of a multi-row INSERT do not always get consecutive ids, e.g.
+// when a trigger inserts items too, so they are returned by the INSERT.
+// RETURNING gives them in no particular order, but the ids SQLite picks
+// increase from one row to the next.
+async fn add_items_in_transaction(transaction: &mut dyn DatabaseTransactionTrait,
+                                  items: &[&Item],
+                                  picture_limits: &PictureLimits,
+                                  actor: &str) -> Result<Vec<i64>, DatabaseServiceError> {
+    let mut retval = vec![];
+    for chunk in items.chunks(BULK_INSERT_CHUNK_SIZE) {
+        let mut rows = vec![];
+        for item in chunk {
+            acquire_picture(transaction, item, picture_limits).await?;
+            let attributes = to_sql_literal(&Json(item.get_attributes()).to_database_value()?);
+            rows.push(format!("({})", item_values_sql(item, attributes.as_str())?));
+        }
+        let inserted_rows = transaction.select(format!("INSERT INTO item ({}) VALUES{} RETURNING id", INSERT_COLUMNS, rows.join(", ")).as_str()).await?;
+        let mut item_ids = inserted_rows.iter()
+            .map(|row| row.get::<i64>("id"))
+            .collect::<Result<Vec<i64>, DatabaseServiceError>>()?;
+        item_ids.sort_unstable();
+        if item_ids.len() != chunk.len() {
+            return Err(DatabaseServiceError::new(format!("{} items were inserted instead of {}", item_ids.len(), chunk.len()).as_str()));
+        }
+        for (item_id, item) in item_ids.into_iter().zip(chunk) {
+            record_change(transaction, item_id, ItemAuditAction::Insert, actor, None, Some(item_fields(item))).await?;
+            retval.push(item_id);
+        }
+    }
+    Ok(retval)
+}
+
+// The values of an item for INSERT_COLUMNS, its attributes being given as
+// a parameter or a literal.
+fn item_values_sql(item: &Item, attributes_sql: &str) -> Result<String, DatabaseServiceError> {
+    Ok(format!("{}, {}, {}, {}, {}, {}, {}",
+               to_sql_literal(&item.get_descr().to_database_value()?),
+               item.get_money().to_f64(),
+               item.get_money().minor_units(),
+               to_sql_literal(&item.get_money().currency().code().to_database_value()?),
+               item.get_active(),
+               to_sql_literal(&item.get_picture_hash().to_database_value()?),
+               attributes_sql))
+}
+
+async fn update_items_in_transaction(transaction: &mut dyn DatabaseTransactionTrait,
+                                     items: &[Item],
+                                     item_validator: &ItemValidator,
+                                     picture_limits: &PictureLimits,
+                                     actor: &str) -> Result<BulkResults<u64>, DatabaseServiceError> {
+    let mut retval = vec![];
+    for item in items {
+        let field_errors = item_validator.validate(item);
+        if !field_errors.is_empty() {
+            retval.push(Err(DatabaseServiceError::invalid_item(field_errors)));
+            continue;
+        }
+        transaction.execute(format!("SAVEPOINT {}", BULK_SAVEPOINT).as_str()).await?;
+        let result = update_item_in_transaction(transaction, item, picture_limits, actor).await;
+        finish_savepoint(transaction, result.is_ok()).await?;
+        retval.push(result);
+    }
+    Ok(retval)
+}
+
+async fn delete_items_in_transaction(transaction: &mut dyn DatabaseTransactionTrait,
+                                     items: &[(i64, i64)],
+                                     actor: &str) -> Result<BulkResults<u64>, DatabaseServiceError> {
+    let mut retval = vec![];
+    for (item_id, expected_version) in items {
+        transaction.execute(format!("SAVEPOINT {}", BULK_SAVEPOINT).as_str()).await?;
+        let result = delete_item_in_transaction(transaction, *item_id, *expected_version, actor).await;
+        finish_savepoint(transaction, result.is_ok()).await?;
+        retval.push(result);
+    }
+    Ok(retval)
+}
+
+// Keeps the changes made since the savepoint, or undoes them when the item
+// failed so that the transaction can go on with the next one.
+async fn finish_savepoint(transaction: &mut dyn DatabaseTransactionTrait, keep_changes: bool) -> Result<(), DatabaseServiceError> {
+    if !keep_changes {
+        transaction.execute(format!("ROLLBACK TO {}", BULK_SAVEPOINT).as_str()).await?;
+    }
+    transaction.execute(format!("RELEASE {}", BULK_SAVEPOINT).as_str()).await?;
+    Ok(())
+}
+
+async fn update_items_where_in_transaction(transaction: &mut dyn DatabaseTransactionTrait,
+                                           where_clause: &str,
+                                           changes: &ItemChanges,
+                                           item_validator: &ItemValidator,
+                                           picture_limits: &PictureLimits,
+                                           actor: &str) -> Result<u64, DatabaseServiceError> {
+    let rows = transaction.select(format!("SELECT {} FROM item{}", ITEM_COLUMNS, where_clause).as_str()).await?;
+    let mut retval = 0;
+    for row in rows {
+        let previous_item = item_from_row(&row)?;
+        let mut updated_item = item_from_row(&row)?;
+        changes.apply_to(&mut updated_item);
+        let field_errors = item_validator.validate(&updated_item);
+        if !field_errors.is_empty() {
+            return Err(DatabaseServiceError::invalid_item(field_errors));
+        }
+        retval += write_item_changes(transaction, &previous_item, &updated_item, picture_limits, actor).await?;
+    }
+    Ok(retval)
+}
+
+async fn delete_items_where_in_transaction(transaction: &mut dyn DatabaseTransactionTrait,
+                                           where_clause: &str,
+                                           actor: &str) -> Result<u64, DatabaseServiceError> {
+    let rows = transaction.select(format!("SELECT id FROM item{}", where_clause).as_str()).await?;
+    let item_ids = rows.iter()
+        .map(|row| row.get::<i64>("id"))
+        .collect::<Result<Vec<i64>, DatabaseServiceError>>()?;
+    if item_ids.is_empty() {
+        return Ok(0);
+    }
+    let rows = transaction.select("SELECT datetime('now') AS now").await?;
+    let deleted_at = match rows.first() {
+        Some(row) => row.get::<String>("now")?,
+        None => return Err(DatabaseServiceError::new("The current date cannot be read"))
+    };
+    let rows_affected = transaction.update(format!("UPDATE item SET deleted_at = '{}', version = version + 1 WHERE id IN ({})",
+                                                   deleted_at,
+                                                   item_ids.iter().map(|id| id.to_string()).collect::<Vec<String>>().join(", ")).as_str(),
+                                                   None).await?;
+    for item_id in item_ids {
+        record_change(transaction,
+                      item_id,
+                      ItemAuditAction::Delete,
+                      actor,
+                      Some(single_field("deleted_at", serde_json::Value::Null)),
+                      Some(single_field("deleted_at", deleted_at.as_str()))).await?;
+    }
+    Ok(rows_affected)
+}
+
+async fn upsert_item_in_transaction(transaction: &mut dyn DatabaseTransactionTrait,
+                                    item: &Item,
+                                    key: &UpsertKey,
+                                    picture_limits: &PictureLimits,
+                                    actor: &str) -> Result<UpsertOutcome, DatabaseServiceError> {
+    // Trashed items are selected by id so that they are not overwritten
+    let (key_condition, statement) = match key {
+        UpsertKey::Id if item.get_id() <= 0 =>
+            return add_item_in_transaction(transaction, item, picture_limits, actor).await.map(UpsertOutcome::Inserted),
+        UpsertKey::Id => (format!("id = {}", item.get_id()),
+                          format!("INSERT INTO item (id, {}) VALUES({}, {}) ON CONFLICT(id) DO UPDATE SET {} WHERE deleted_at IS NULL",
+                                  INSERT_COLUMNS,
+                                  item.get_id(),
+                                  item_values_sql(item, "?1")?,
+                                  UPSERT_ASSIGNMENTS)),
+        UpsertKey::Natural(key) => {
+            let column_name = natural_key_column(key)?;
+            (format!("{} = {} AND deleted_at IS NULL", column_name, natural_key_value_sql(item, key)?),
+             format!("INSERT INTO item ({}) VALUES({}) ON CONFLICT({}) WHERE deleted_at IS NULL DO UPDATE SET {}",
+                     INSERT_COLUMNS,
+                     item_values_sql(item, "?1")?,
+                     column_name,
+                     UPSERT_ASSIGNMENTS))
+        }
+    };
+    let rows = transaction.select(format!("SELECT {} FROM item WHERE {}", ITEM_COLUMNS, key_condition).as_str()).await?;
+    let previous_item = rows.first().map(item_from_row).transpose()?;
+    let values = Some(vec![Json(item.get_attributes()).to_database_value()?]);
+    let previous_item = match previous_item {
+        Some(previous_item) if previous_item.is_deleted() =>
+            return Err(DatabaseServiceError::new(format!("The item {} is in the trash", previous_item.get_id()).as_str())),
+        Some(previous_item) => previous_item,
+        None => {
+            acquire_picture(transaction, item, picture_limits).await?;
+            let item_id = transaction.insert(statement.as_str(), values).await?;
+            record_change(transaction, item_id, ItemAuditAction::Insert, actor, None, Some(item_fields(item))).await?;
+            return Ok(UpsertOutcome::Inserted(item_id));
+        }
+    };
+    let previous_picture_hash = previous_item.get_picture_hash();
+    let picture_changed = previous_picture_hash != item.get_picture_hash();
+    if picture_changed {
+        acquire_picture(transaction, item, picture_limits).await?;
+    }
+    if transaction.update(statement.as_str(), values).await? == 0 {
+        return Err(DatabaseServiceError::with_kind(DatabaseServiceErrorKind::Conflict,
+                                                   format!("The item {} was changed during the upsert", previous_item.get_id()).as_str()));
+    }
+    let (old_values, new_values) = diff_fields(&item_fields(&previous_item), &item_fields(item));
+    record_change(transaction, previous_item.get_id(), ItemAuditAction::Update, actor, Some(old_values), Some(new_values)).await?;
+    if let (true, Some(previous_picture_hash)) = (picture_changed, previous_picture_hash) {
+        release_picture(transaction, previous_picture_hash).await?;
+    }
+    Ok(UpsertOutcome::Updated(previous_item.get_id()))
+}
+
+// The column holding a natural key: descr, or the generated column of an
+// attribute path
+fn natural_key_column(key: &str) -> Result<String, DatabaseServiceError> {
+    match key.strip_prefix(ATTRIBUTES_PREFIX) {
+        None if key == "descr" => Ok(key.to_owned()),
+        Some(path) if is_valid_json_path(path) => Ok(attribute_column_name(path)),
+        _ => Err(DatabaseServiceError::with_kind(DatabaseServiceErrorKind::InvalidFilter,
+                                                 format!("{} cannot be used as a natural key", key).as_str()))
+    }
+}
+
+fn natural_key_value_sql(item: &Item, key: &str) -> Result<String, DatabaseServiceError> {
+    match key.strip_prefix(ATTRIBUTES_PREFIX) {
+        Some(path) => Ok(format!("json_extract({}, '$.{}')",
+                                 to_sql_literal(&Json(item.get_attributes()).to_database_value()?),
+                                 path)),
+        None => Ok(to_sql_literal(&item.get_descr().to_database_value()?))
+    }
+}
+
+async fn update_item_in_transaction(transaction: &mut dyn DatabaseTransactionTrait,
+                                    updated_item: &Item,
+                                    picture_limits: &PictureLimits,
+                                    actor: &str) -> Result<u64, DatabaseServiceError> {
+    let previous_item = match select_live_item_row(transaction, updated_item.get_id()).await? {
+        Some(row) => item_from_row(&row)?,
+        None => return Ok(0)
+    };
+    if previous_item.get_version() != updated_item.get_version() {
+        return Err(version_conflict(updated_item.get_id(), updated_item.get_version(), previous_item.get_version()));
+    }
+    write_item_changes(transaction, &previous_item, updated_item, picture_limits, actor).await
+}
+
+async fn patch_item_in_transaction(transaction: &mut dyn DatabaseTransactionTrait,
+                                   item_id: i64,
+                                   changes: &ItemChanges,
+                                   item_validator: &ItemValidator,
+                                   picture_limits: &PictureLimits,
+                                   actor: &str) -> Result<u64, DatabaseServiceError> {
+    let row = match select_live_item_row(transaction, item_id).await? {
+        Some(row) => row,
+        None => return Ok(0)
+    };
+    let previous_item = item_from_row(&row)?;
+    let mut patched_item = item_from_row(&row)?;
+    changes.apply_to(&mut patched_item);
+    let field_errors = item_validator.validate(&patched_item);
+    if !field_errors.is_empty() {
+        return Err(DatabaseServiceError::invalid_item(field_errors));
+    }
+    write_item_changes(transaction, &previous_item, &patched_item, picture_limits, actor).await
+}
+
+async fn select_live_item_row(transaction: &mut dyn DatabaseTransactionTrait, item_id: i64) -> Result<Option<DatabaseRow>, DatabaseServiceError> {
+    let rows = transaction.select(format!("SELECT {} FROM item WHERE id = {} AND deleted_at IS NULL",
+                                          ITEM_COLUMNS,
+                                          item_id).as_str()).await?;
+    Ok(rows.into_iter().next())
+}
+
+// Only writes the dirty fields of the updated item, the other columns keep
+// their stored values.
+async fn write_item_changes(transaction: &mut dyn DatabaseTransactionTrait,
+                            previous_item: &Item,
+                            updated_item: &Item,
+                            picture_limits: &PictureLimits,
+                            actor: &str) -> Result<u64, DatabaseServiceError> {
+    let previous_picture_hash = previous_item.get_picture_hash();
+    let picture_changed = updated_item.is_dirty(ItemField::Picture) && previous_picture_hash != updated_item.get_picture_hash();
+    if picture_changed {
+        acquire_picture(transaction, updated_item, picture_limits).await?;
+    }
+    let mut assignments = vec![];
+    let mut values = vec![];
+    for field in ItemField::ALL.into_iter().filter(|field| updated_item.is_dirty(*field)) {
+        match field {
+            ItemField::Descr => assignments.push(format!("descr = {}",
+                                                         to_sql_literal(&updated_item.get_descr().to_database_value()?))),
+            ItemField::Amount => assignments.push(format!("amount = {}, amount_minor = {}, currency = {}",
+                                                          updated_item.get_money().to_f64(),
+                                                          updated_item.get_money().minor_units(),
+                                                          to_sql_literal(&updated_item.get_money().currency().code().to_database_value()?))),
+            ItemField::Active => assignments.push(format!("active = {}", updated_item.get_active())),
+            ItemField::Picture => assignments.push(format!("picture_hash = {}",
+                                                           to_sql_literal(&updated_item.get_picture_hash().to_database_value()?))),
+            ItemField::Attributes => {
+                assignments.push("attributes = ?".to_owned());
+                values.push(Json(updated_item.get_attributes()).to_database_value()?);
+            }
+        }
+    }
+    assignments.push("version = version + 1".to_owned());
+    let rows_affected = transaction.update(format!("UPDATE item SET {} WHERE id = {} AND version = {}",
+                                                   assignments.join(", "),
+                                                   previous_item.get_id(),
+                                                   previous_item.get_version()).as_str(),
+                                                   if values.is_empty() { None } else { Some(values) }).await?;
+    if rows_affected == 0 {
+        return Err(version_conflict(previous_item.get_id(), previous_item.get_version(), previous_item.get_version() + 1));
+    }
+    // Recorded even when no field changed so that the history entries match
+    // the item versions
+    let (old_values, new_values) = diff_fields(&item_fields(previous_item), &merged_item_fields(previous_item, updated_item));
+    record_change(transaction, previous_item.get_id(), ItemAuditAction::Update, actor, Some(old_values), Some(new_values)).await?;
+    if let (true, Some(previous_picture_hash)) = (picture_changed, previous_picture_hash) {
+        release_picture(transaction, previous_picture_hash).await?;
+    }
+    Ok(rows_affected)
+}
+
+async fn delete_item_in_transaction(transaction: &mut dyn DatabaseTransactionTrait,
+                                    item_id: i64,
+                                    expected_version: i64,
+                                    actor: &str) -> Result<u64, DatabaseServiceError> {
+    let rows_affected = transaction.update(format!("UPDATE item SET deleted_at = datetime('now'), version = version + 1 WHERE id = {} AND deleted_at IS NULL AND version = {}",
+                                                   item_id,
+                                                   expected_version).as_str(),
+                                                   None).await?;
+    if rows_affected == 0 {
+        let rows = transaction.select(format!("SELECT version FROM item WHERE id = {} AND deleted_at IS NULL",
+                                              item_id).as_str()).await?;
+        return match rows.first() {
+            Some(row) => Err(version_conflict(item_id, expected_version, row.get("version")?)),
+            None => Ok(0)
+        };
+    }
+    let rows = transaction.select(format!("SELECT deleted_at FROM item WHERE id = {}",
+                                          item_id).as_str()).await?;
+    let deleted_at = match rows.first() {
+        Some(row) => row.get::<Option<String>>("deleted_at")?,
+        None => None
+    };
+    record_change(transaction,
+                  item_id,
+                  ItemAuditAction::Delete,
+                  actor,
+                  Some(single_field("deleted_at", serde_json::Value::Null)),
+                  Some(single_field("deleted_at", deleted_at))).await?;
+    Ok(rows_affected)
+}
+
+async fn restore_item_in_transaction(transaction: &mut dyn DatabaseTransactionTrait,
+                                     item_id: i64,
+                                     actor: &str) -> Result<u64, DatabaseServiceError> {
+    let rows = transaction.select(format!("SELECT deleted_at FROM item WHERE id = {} AND deleted_at IS NOT NULL",
+                                          item_id).as_str()).await?;
+    let deleted_at = match rows.first() {
+        Some(row) => row.get::<Option<String>>("deleted_at")?,
+        None => return Ok(0)
+    };
+    let rows_affected = transaction.update(format!("UPDATE item SET deleted_at = NULL, version = version + 1 WHERE id = {}",
+                                                   item_id).as_str(),
+                                                   None).await?;
+    record_change(transaction,
+                  item_id,
+                  ItemAuditAction::Restore,
+                  actor,
+                  Some(single_field("deleted_at", deleted_at)),
+                  Some(single_field("deleted_at", serde_json::Value::Null))).await?;
+    Ok(rows_affected)
+}
+
+async fn purge_in_transaction(transaction: &mut dyn DatabaseTransactionTrait,
+                              trash_retention: Duration,
+                              actor: &str) -> Result<usize, DatabaseServiceError> {
+    let rows = transaction.select(format!("SELECT id, picture_hash FROM item WHERE deleted_at <= datetime('now', '-{} seconds')",
+                                          trash_retention.as_secs()).as_str()).await?;
+    for row in rows.iter() {
+        let item_id = row.get::<i64>("id")?;
+        transaction.delete(format!("DELETE FROM item WHERE id = {}",
+                                   item_id).as_str()).await?;
+        record_change(transaction, item_id, ItemAuditAction::Purge, actor, None, None).await?;
+        if let Some(picture_hash) = row.get::<Option<String>>("picture_hash")? {
+            release_picture(transaction, &picture_hash).await?;
         }
     }
+    Ok(rows.len())
+}
 
-    pub async fn add_item(&mut self, item: &Item) -> Result<i64, DatabaseServiceError> {
-        self.db_service.insert(format!("INSERT INTO item (descr, amount, active, picture) VALUES('{}', {}, {}, ?1)",
-                                       item.get_descr(),
-                                       item.get_amount(),
-                                       item.get_active()
-                                       ).as_str(),
-                                       Some(vec![item.get_picture().to_vec()])).await
+// Legacy pictures are migrated even when they are not valid, without
+// metadata, so that no item loses its picture.
+async fn migrate_legacy_pictures_in_transaction(transaction: &mut dyn DatabaseTransactionTrait,
+                                                picture_limits: &PictureLimits,
+                                                actor: &str) -> Result<usize, DatabaseServiceError> {
+    let rows = transaction.select("SELECT id, picture FROM item WHERE picture_hash IS NULL AND length(picture) > 0").await?;
+    for row in rows.iter() {
+        let item_id = row.get::<i64>("id")?;
+        let picture: Blob = row.get("picture")?;
+        let picture_hash = compute_picture_hash(&picture).unwrap_or_default();
+        let prepared_picture = prepare_picture(&picture, picture_limits).ok();
+        store_picture(transaction, &picture_hash, picture, prepared_picture).await?;
+        transaction.update(format!("UPDATE item SET picture_hash = '{}', picture = NULL, version = version + 1 WHERE id = {}",
+                                   picture_hash,
+                                   item_id).as_str(),
+                                   None).await?;
+        record_picture_change(transaction, item_id, None, &picture_hash, actor).await?;
     }
+    Ok(rows.len())
+}
 
-    pub async fn update_item(&mut self, updated_item: &Item) -> Result<u64, DatabaseServiceError> {
-        self.db_service.update(format!("UPDATE item SET descr = '{}', amount = {}, active = {}, picture = ? WHERE id = {}",
-                                       updated_item.get_descr(),
-                                       updated_item.get_amount(),
-                                       updated_item.get_active(),
-                                       updated_item.get_id()).as_str(),
-                                       Some(vec![updated_item.get_picture().to_vec()])).await
+async fn record_picture_change(transaction: &mut dyn DatabaseTransactionTrait,
+                               item_id: i64,
+                               previous_picture_hash: Option<&str>,
+                               picture_hash: &str,
+                               actor: &str) -> Result<(), DatabaseServiceError> {
+    if previous_picture_hash == Some(picture_hash) {
+        return Ok(());
     }
+    record_change(transaction,
+                  item_id,
+                  ItemAuditAction::Update,
+                  actor,
+                  Some(single_field("picture_hash", previous_picture_hash)),
+                  Some(single_field("picture_hash", Some(picture_hash)))).await
+}
+
+async fn record_change(transaction: &mut dyn DatabaseTransactionTrait,
+                       item_id: i64,
+                       action: ItemAuditAction,
+                       actor: &str,
+                       old_values: Option<ItemFields>,
+                       new_values: Option<ItemFields>) -> Result<(), DatabaseServiceError> {
+    transaction.insert(format!("INSERT INTO item_audit (item_id, action, actor, old_values, new_values) VALUES({}, '{}', ?1, ?2, ?3)",
+                               item_id,
+                               action.as_str()).as_str(),
+                               Some(vec![actor.to_database_value()?,
+                                         old_values.map(Json).to_database_value()?,
+                                         new_values.map(Json).to_database_value()?])).await?;
+    Ok(())
+}
 
-    pub async fn delete_item(&mut self, item_id: i64) -> Result<u64, DatabaseServiceError> {
-        self.db_service.delete(format!("DELETE FROM item WHERE id = {}",
-                                       item_id).as_str()).await
+fn version_conflict(item_id: i64, expected_version: i64, actual_version: i64) -> DatabaseServiceError {
+    DatabaseServiceError::with_kind(DatabaseServiceErrorKind::Conflict,
+                                    format!("The item {} was changed by someone else: expected version {} but found version {}",
+                                            item_id,
+                                            expected_version,
+                                            actual_version).as_str())
+}
+
+fn single_field<T: Into<serde_json::Value>>(field: &str, value: T) -> ItemFields {
+    let mut retval = ItemFields::new();
+    retval.insert(field.to_owned(), value.into());
+    retval
+}
+
+fn audit_entry_from_row(row: &DatabaseRow) -> Result<ItemAuditEntry, DatabaseServiceError> {
+    let action: String = row.get("action")?;
+    Ok(ItemAuditEntry {
+        id: row.get("id")?,
+        item_id: row.get("item_id")?,
+        action: ItemAuditAction::parse(&action)
+            .ok_or_else(|| DatabaseServiceError::new(format!("The audit action {} is not known", action).as_str()))?,
+        actor: row.get("actor")?,
+        changed_at: row.get("changed_at")?,
+        old_values: row.get::<Option<Json<ItemFields>>>("old_values")?.map(|j| j.0).unwrap_or_default(),
+        new_values: row.get::<Option<Json<ItemFields>>>("new_values")?.map(|j| j.0).unwrap_or_default()
+    })
+}
+
+// Adds a reference to the item picture, storing its bytes the first time
+// they are seen. An item holding only the reference to a stored picture
+// (bytes not loaded) just increments the reference count.
+async fn acquire_picture(transaction: &mut dyn DatabaseTransactionTrait,
+                         item: &Item,
+                         picture_limits: &PictureLimits) -> Result<(), DatabaseServiceError> {
+    let picture_hash = match item.get_picture_hash() {
+        Some(picture_hash) => picture_hash,
+        None => return Ok(())
+    };
+    if item.is_picture_loaded() {
+        let prepared_picture = prepare_picture(item.get_picture(), picture_limits)?;
+        return store_picture(transaction, picture_hash, item.get_picture().to_vec(), Some(prepared_picture)).await;
+    }
+    let rows_affected = transaction.update(format!("UPDATE picture SET ref_count = ref_count + 1 WHERE hash = '{}'",
+                                                   picture_hash).as_str(),
+                                                   None).await?;
+    if rows_affected == 0 {
+        return Err(DatabaseServiceError::new(format!("The picture {} does not exist", picture_hash).as_str()));
     }
+    Ok(())
+}
+
+fn prepare_picture(picture: &[u8], picture_limits: &PictureLimits) -> Result<(PictureInfo, Blob), DatabaseServiceError> {
+    let info = describe_picture(picture, picture_limits)?;
+    let thumbnail = make_thumbnail(picture, &info, picture_limits)?;
+    Ok((info, thumbnail))
+}
+
+async fn store_picture(transaction: &mut dyn DatabaseTransactionTrait,
+                       picture_hash: &str,
+                       picture: Blob,
+                       prepared_picture: Option<(PictureInfo, Blob)>) -> Result<(), DatabaseServiceError> {
+    let (info, thumbnail) = prepared_picture.unzip();
+    transaction.insert(format!("INSERT INTO picture (hash, data, ref_count, mime_type, width, height, thumbnail) VALUES('{}', ?1, 1, {}, {}, {}, ?2) ON CONFLICT(hash) DO UPDATE SET ref_count = ref_count + 1",
+                               picture_hash,
+                               to_sql_literal(&info.map(|i| i.format.mime_type()).to_database_value()?),
+                               to_sql_literal(&info.map(|i| i.width).to_database_value()?),
+                               to_sql_literal(&info.map(|i| i.height).to_database_value()?)).as_str(),
+                               Some(vec![picture.to_database_value()?, thumbnail.to_database_value()?])).await?;
+    Ok(())
+}
+
+async fn release_picture(transaction: &mut dyn DatabaseTransactionTrait,
+                         picture_hash: &str) -> Result<(), DatabaseServiceError> {
+    transaction.update(format!("UPDATE picture SET ref_count = ref_count - 1 WHERE hash = '{}'",
+                               picture_hash).as_str(),
+                               None).await?;
+    transaction.delete(format!("DELETE FROM picture WHERE hash = '{}' AND ref_count <= 0",
+                               picture_hash).as_str()).await?;
+    Ok(())
+}
 
-    pub async fn get_all_items(&mut self) -> Result<Vec<Item>, DatabaseServiceError> {
-        let rows = self.db_service.select("SELECT * FROM item").await?;
-        let items = rows.into_iter().map(|x| Item::new(x.get_integer("id").unwrap(),
-                                                       x.get_text("descr").unwrap(),
-                                                       x.get_real("amount").unwrap(),
-                                                       x.get_bool("active").unwrap(),
-                                                       Some(x.get_blob("picture").unwrap())
-                                                       )).collect();
-        Ok(items)
+fn item_from_row(row: &DatabaseRow) -> Result<Item, DatabaseServiceError> {
+    let picture = row.try_get::<Option<Blob>>("picture")?.flatten();
+    let mut item = Item::new(row.get("id")?,
+                             row.get::<String>("descr")?.as_str(),
+                             row.try_get::<Option<f64>>("amount")?.flatten().unwrap_or_default(),
+                             row.get::<Option<bool>>("active")?.unwrap_or_default(),
+                             picture);
+    if !item.has_picture() {
+        if let Some(picture_hash) = row.try_get::<Option<String>>("picture_hash")?.flatten() {
+            item.set_picture_reference(Some(picture_hash.as_str()));
+        }
+    }
+    if let Some(Json(attributes)) = row.try_get::<Json<ItemAttributes>>("attributes")? {
+        item.set_attributes(attributes);
+    }
+    if let Some(deleted_at) = row.try_get::<Option<String>>("deleted_at")?.flatten() {
+        item.set_deleted_at(Some(deleted_at.as_str()));
+    }
+    if let Some(version) = row.try_get::<i64>("version")? {
+        item.set_version(version);
     }
+    // Rows selected without the exact amount columns keep the REAL amount
+    if let Some(amount_minor) = row.try_get::<Option<i64>>("amount_minor")?.flatten() {
+        let currency_code = row.try_get::<Option<String>>("currency")?.flatten();
+        let currency = match currency_code.as_deref() {
+            Some(code) => Currency::new(code)
+                .ok_or_else(|| DatabaseServiceError::with_kind(DatabaseServiceErrorKind::InvalidConversion,
+                                                               format!("Invalid currency code '{}'", code).as_str()))?,
+            None => DEFAULT_CURRENCY
+        };
+        item.set_money(Money::new(amount_minor, currency));
+    }
+    item.clear_dirty_fields();
+    Ok(item)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::VecDeque;
+    use std::io::Cursor;
+    use std::sync::Mutex;
+    use crate::data_services::item_validation::ItemRule;
     use async_trait::async_trait;
-    use crate::data_services::database_service_base::{BlobList, DatabaseRow, DatabaseRowValue};
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, ReadBuf};
+    use crate::data_services::database_options::DatabaseOptions;
+    use crate::data_services::database_service::DatabaseService;
+    use crate::data_services::database_service_base::{DatabaseRowValue, ValueList};
+    use tempfile::{NamedTempFile, TempDir};
 
+    #[derive(Clone)]
     pub enum FakeResult {
         Ok(i64),
         OkSelect(Vec<DatabaseRow>),
         Err(String)
     }
 
+    impl FakeResult {
+        fn into_i64(self) -> Result<i64, DatabaseServiceError> {
+            match self {
+                FakeResult::Ok(x) => Ok(x),
+                FakeResult::Err(e) => Err(DatabaseServiceError::new(e.as_str())),
+                _ => unimplemented!()
+            }
+        }
+
+        fn into_rows(self) -> Result<Vec<DatabaseRow>, DatabaseServiceError> {
+            match self {
+                FakeResult::OkSelect(x) => Ok(x),
+                FakeResult::Err(e) => Err(DatabaseServiceError::new(e.as_str())),
+                _ => unimplemented!()
+            }
+        }
+    }
+
+    type ExpectedQueries = Arc<Mutex<VecDeque<(String, FakeResult)>>>;
+
+    fn pop_expected_query(expected_queries: &ExpectedQueries, query: &str) -> FakeResult {
+        let (expected_query, result) = expected_queries.lock().unwrap().pop_front()
+            .unwrap_or_else(|| panic!("Unexpected query {}", query));
+        assert_eq!(expected_query, query);
+        result
+    }
+
+    // Checks each query against received_query and answers with result.
+    // When a scenario needs several queries, the expected ones are queued in
+    // expected_queries and consumed in order before falling back. The
+    // transaction boundaries are expected as BEGIN, COMMIT and ROLLBACK.
     pub struct FakeDataService {
         pub received_query: String,
-        pub result: FakeResult
+        pub result: FakeResult,
+        pub expected_queries: ExpectedQueries
     }
 
     impl FakeDataService {
         pub fn new(query: &str, result: FakeResult) -> Self {
             Self {
                 received_query: query.to_owned(),
-                result
+                result,
+                expected_queries: Arc::new(Mutex::new(VecDeque::new()))
+            }
+        }
+
+        pub fn default() -> Self {
+            FakeDataService::new("", FakeResult::Ok(0))
+        }
+
+        pub fn with_queries(queries: Vec<(&str, FakeResult)>) -> Self {
+            let retval = FakeDataService::default();
+            retval.expected_queries.lock().unwrap().extend(queries.into_iter()
+                .map(|(query, result)| (query.to_owned(), result)));
+            retval
+        }
+
+        fn next_result(&self, query: &str) -> FakeResult {
+            if !self.expected_queries.lock().unwrap().is_empty() {
+                return pop_expected_query(&self.expected_queries, query);
             }
+            assert_eq!(self.received_query, query);
+            self.result.clone()
+        }
+    }
+
+    #[async_trait]
+    impl DatabaseServiceTrait for FakeDataService {
+        async fn insert(&self,
+                        query: &str,
+                        _values: Option<ValueList>) -> Result<i64, DatabaseServiceError> {
+            self.next_result(query).into_i64()
+        }
+
+        async fn update(&self,
+                        query: &str,
+                        _values: Option<ValueList>) -> Result<u64, DatabaseServiceError> {
+            self.next_result(query).into_i64().map(|x| x as u64)
+        }
+
+        async fn delete(&self, query: &str) -> Result<u64, DatabaseServiceError> {
+            self.next_result(query).into_i64().map(|x| x as u64)
+        }
+
+        async fn select(&self, query: &str) -> Result<Vec<DatabaseRow>, DatabaseServiceError> {
+            self.next_result(query).into_rows()
+        }
+
+        async fn execute(&self, query: &str) -> Result<u64, DatabaseServiceError> {
+            self.next_result(query).into_i64().map(|x| x as u64)
+        }
+
+        async fn begin(&self) -> Result<Box<dyn DatabaseTransactionTrait>, DatabaseServiceError> {
+            pop_expected_query(&self.expected_queries, "BEGIN").into_i64()?;
+            Ok(Box::new(FakeTransaction {
+                expected_queries: self.expected_queries.clone()
+            }))
+        }
+
+        async fn open_blob(&self,
+                           table: &str,
+                           column: &str,
+                           row_id: i64,
+                           _writable: bool) -> Result<Box<dyn DatabaseBlobTrait>, DatabaseServiceError> {
+            let size = self.next_result(format!("OPEN BLOB {}.{} {}", table, column, row_id).as_str()).into_i64()?;
+            Ok(Box::new(FakeBlob {
+                content: Cursor::new(vec![0; size as usize].into_boxed_slice())
+            }))
+        }
+    }
+
+    // An in memory blob of zeros whose size is the result of the expected
+    // OPEN BLOB pseudo query.
+    pub struct FakeBlob {
+        content: Cursor<Box<[u8]>>
+    }
+
+    impl DatabaseBlobTrait for FakeBlob {
+        fn size(&self) -> u64 {
+            self.content.get_ref().len() as u64
+        }
+
+        fn close(self: Box<Self>) -> Result<(), DatabaseServiceError> {
+            Ok(())
+        }
+    }
+
+    impl AsyncRead for FakeBlob {
+        fn poll_read(self: Pin<&mut Self>,
+                     cx: &mut Context<'_>,
+                     buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().content).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for FakeBlob {
+        fn poll_write(self: Pin<&mut Self>,
+                      cx: &mut Context<'_>,
+                      buf: &[u8]) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.get_mut().content).poll_write(cx, buf)
         }
 
-        pub fn default() -> Self {
-            FakeDataService {
-                received_query: "".to_owned(),
-                result: FakeResult::Ok(0)
-            }
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().content).poll_flush(cx)
         }
+
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().content).poll_shutdown(cx)
+        }
+    }
+
+    pub struct FakeTransaction {
+        expected_queries: ExpectedQueries
     }
 
     #[async_trait]
-    impl DatabaseServiceTrait for FakeDataService {
+    impl DatabaseTransactionTrait for FakeTransaction {
         async fn insert(&mut self,
                         query: &str,
-                        _blobs: Option<BlobList>) -> Result<i64, DatabaseServiceError> {
-            assert_eq!(self.received_query, query);
-            match &self.result {
-                FakeResult::Ok(x) => Ok(*x),
-                FakeResult::Err(e) => Err(DatabaseServiceError::new(e.as_str())),
-                _ => unimplemented!()
-            }
+                        _values: Option<ValueList>) -> Result<i64, DatabaseServiceError> {
+            pop_expected_query(&self.expected_queries, query).into_i64()
         }
 
         async fn update(&mut self,
                         query: &str,
-                        _blobs: Option<BlobList>) -> Result<u64, DatabaseServiceError> {
-            assert_eq!(self.received_query, query);
-            match &self.result {
-                FakeResult::Ok(x) => Ok(*x as u64),
-                FakeResult::Err(e) => Err(DatabaseServiceError::new(e.as_str())),
-                _ => unimplemented!()
-            }
+                        _values: Option<ValueList>) -> Result<u64, DatabaseServiceError> {
+            pop_expected_query(&self.expected_queries, query).into_i64().map(|x| x as u64)
         }
 
         async fn delete(&mut self, query: &str) -> Result<u64, DatabaseServiceError> {
-            assert_eq!(self.received_query, query);
-            match &self.result {
-                FakeResult::Ok(x) => Ok(*x as u64),
-                FakeResult::Err(e) => Err(DatabaseServiceError::new(e.as_str())),
-                _ => unimplemented!()
-            }
+            pop_expected_query(&self.expected_queries, query).into_i64().map(|x| x as u64)
         }
 
         async fn select(&mut self, query: &str) -> Result<Vec<DatabaseRow>, DatabaseServiceError> {
-            assert_eq!(self.received_query, query);
-            match &self.result {
-                FakeResult::OkSelect(x) => Ok(x.to_vec()),
-                FakeResult::Err(e) => Err(DatabaseServiceError::new(e.as_str())),
-                _ => unimplemented!()
-            }
+            pop_expected_query(&self.expected_queries, query).into_rows()
+        }
+
+        async fn execute(&mut self, query: &str) -> Result<u64, DatabaseServiceError> {
+            pop_expected_query(&self.expected_queries, query).into_i64().map(|x| x as u64)
+        }
+
+        async fn commit(self: Box<Self>) -> Result<(), DatabaseServiceError> {
+            pop_expected_query(&self.expected_queries, "COMMIT").into_i64().map(|_| ())
+        }
+
+        async fn rollback(self: Box<Self>) -> Result<(), DatabaseServiceError> {
+            pop_expected_query(&self.expected_queries, "ROLLBACK").into_i64().map(|_| ())
         }
     }
 
+    fn get_audit_query(item_id: i64, action: &str) -> String {
+        format!("INSERT INTO item_audit (item_id, action, actor, old_values, new_values) VALUES({}, '{}', ?1, ?2, ?3)", item_id, action)
+    }
+
+    fn get_item_row(id: i64, descr: &str, picture_hash: Option<&str>) -> DatabaseRow {
+        let mut retval = get_sample_item_row(id, descr);
+        retval.add_column("picture_hash", picture_hash.to_database_value().unwrap());
+        retval
+    }
+
+    const SELECT_ITEMS: &str = "SELECT id, descr, amount_minor, currency, active, picture_hash, attributes, deleted_at, version FROM item WHERE deleted_at IS NULL";
+
+    fn get_picture_hash_row(picture_hash: Option<&str>) -> DatabaseRow {
+        let mut retval = DatabaseRow::new();
+        retval.add_column("picture_hash", picture_hash.to_database_value().unwrap());
+        retval
+    }
+
+    fn get_id_row(id: i64) -> DatabaseRow {
+        let mut retval = DatabaseRow::new();
+        retval.add_column("id", DatabaseRowValue::Integer(id));
+        retval
+    }
+
+    fn get_picture_version_row(picture_hash: Option<&str>, version: i64) -> DatabaseRow {
+        let mut retval = get_picture_hash_row(picture_hash);
+        retval.add_column("version", DatabaseRowValue::Integer(version));
+        retval
+    }
+
     #[test]
     fn itemstorageservice_new_with_fake_db_return_success() {
         let fake_db = Box::new(FakeDataService::default());
@@ -132,78 +1529,396 @@ mod tests {
 
     #[tokio::test]
     async fn itemstorageservice_add_item_with_descr_test_return_ok() {
-        let mut fake_db = Box::new(FakeDataService::default());
-        fake_db.received_query = "INSERT INTO item (descr, amount, active, picture) VALUES('test', 1.23, true, ?1)".to_owned();
-        fake_db.result = FakeResult::Ok(1);
-        let mut storage = ItemStorageService::new(fake_db);
+        let fake_db = Box::new(FakeDataService::with_queries(vec![
+            ("BEGIN", FakeResult::Ok(0)),
+            ("INSERT INTO item (descr, amount, amount_minor, currency, active, picture_hash, attributes) VALUES('test', 1.23, 123, 'USD', true, NULL, ?1)", FakeResult::Ok(1)),
+            (get_audit_query(1, "insert").as_str(), FakeResult::Ok(1)),
+            ("COMMIT", FakeResult::Ok(0))
+        ]));
+        let storage = ItemStorageService::new(fake_db);
         assert_eq!(1, storage.add_item(&Item::new(-1, "test", 1.23, true, None)).await.unwrap());
     }
 
+    #[tokio::test]
+    async fn itemstorageservice_add_item_with_picture_store_picture_by_hash() {
+        let picture_hash = compute_picture_hash(b":-)").unwrap();
+        let fake_db = Box::new(FakeDataService::with_queries(vec![
+            ("BEGIN", FakeResult::Ok(0)),
+            (format!("INSERT INTO picture (hash, data, ref_count, mime_type, width, height, thumbnail) VALUES('{}', ?1, 1, 'text/plain', 3, 1, ?2) ON CONFLICT(hash) DO UPDATE SET ref_count = ref_count + 1", picture_hash).as_str(), FakeResult::Ok(1)),
+            (format!("INSERT INTO item (descr, amount, amount_minor, currency, active, picture_hash, attributes) VALUES('test', 1.23, 123, 'USD', true, '{}', ?1)", picture_hash).as_str(), FakeResult::Ok(1)),
+            (get_audit_query(1, "insert").as_str(), FakeResult::Ok(1)),
+            ("COMMIT", FakeResult::Ok(0))
+        ]));
+        let storage = ItemStorageService::new(fake_db);
+        assert_eq!(1, storage.add_item(&Item::new(-1, "test", 1.23, true, Some(b":-)".to_vec()))).await.unwrap());
+    }
+
     #[tokio::test]
     async fn itemstorageservice_add_item_with_error_execution_return_err() {
-        let fake_db = Box::new(FakeDataService::new("INSERT INTO item (descr, amount, active, picture) VALUES('test', 1.23, true, ?1)",
-            FakeResult::Err("error".to_owned())));
-        let mut storage = ItemStorageService::new(fake_db);
+        let fake_db = Box::new(FakeDataService::with_queries(vec![
+            ("BEGIN", FakeResult::Ok(0)),
+            ("INSERT INTO item (descr, amount, amount_minor, currency, active, picture_hash, attributes) VALUES('test', 1.23, 123, 'USD', true, NULL, ?1)", FakeResult::Err("error".to_owned())),
+            ("ROLLBACK", FakeResult::Ok(0))
+        ]));
+        let storage = ItemStorageService::new(fake_db);
         assert_eq!("error", storage.add_item(&Item::new(-1, "test", 1.23, true, None)).await.unwrap_err().to_string());
     }
 
+    #[tokio::test]
+    async fn itemstorageservice_add_item_with_invalid_item_return_err_without_query() {
+        let storage = ItemStorageService::new(Box::new(FakeDataService::with_queries(vec![])));
+        let err = storage.add_item(&Item::new(-1, "", f64::NAN, true, None)).await.unwrap_err();
+        assert_eq!(DatabaseServiceErrorKind::InvalidItem, err.kind());
+        assert_eq!(2, err.field_errors().len());
+        assert_eq!("The item is invalid: descr: must not be empty, amount: NaN is not a valid amount", err.to_string());
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_update_item_with_configured_rules_return_err() {
+        let mut storage = ItemStorageService::new(Box::new(FakeDataService::with_queries(vec![])));
+        storage.set_item_validator(ItemValidator::new(vec![ItemRule::DescrMaxLength { max: 4 }]));
+        let err = storage.update_item(&Item::new(1, "test2", 1.23, true, None)).await.unwrap_err();
+        assert_eq!("descr", err.field_errors()[0].field);
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_add_and_update_item_with_nan_amount_without_amount_rule_return_err() {
+        let mut storage = ItemStorageService::new(Box::new(FakeDataService::with_queries(vec![])));
+        storage.set_item_validator(ItemValidator::new(vec![ItemRule::DescrNotEmpty]));
+        let err = storage.add_item(&Item::new(0, "test", f64::NAN, true, None)).await.unwrap_err();
+        assert_eq!(DatabaseServiceErrorKind::InvalidItem, err.kind());
+        let mut item = Item::new(1, "test", 1.23, true, None);
+        item.set_amount(f64::NAN);
+        let err = storage.update_item(&item).await.unwrap_err();
+        assert_eq!(DatabaseServiceErrorKind::InvalidItem, err.kind());
+        assert_eq!("amount", err.field_errors()[0].field);
+    }
+
     #[tokio::test]
     async fn itemstorageservice_update_item_with_descr_test2_return_1_row_affected() {
-        let mut fake_db = Box::new(FakeDataService::default());
-        fake_db.received_query = "UPDATE item SET descr = 'test2', amount = 1.23, active = true, picture = ? WHERE id = 1".to_owned();
-        fake_db.result = FakeResult::Ok(1);
-        let mut storage = ItemStorageService::new(fake_db);
+        let fake_db = Box::new(FakeDataService::with_queries(vec![
+            ("BEGIN", FakeResult::Ok(0)),
+            ("SELECT id, descr, amount_minor, currency, active, picture_hash, attributes, deleted_at, version FROM item WHERE id = 1 AND deleted_at IS NULL", FakeResult::OkSelect(vec![get_item_row(1, "test", None)])),
+            ("UPDATE item SET descr = 'test2', amount = 1.23, amount_minor = 123, currency = 'USD', active = true, picture_hash = NULL, attributes = ?, version = version + 1 WHERE id = 1 AND version = 1", FakeResult::Ok(1)),
+            (get_audit_query(1, "update").as_str(), FakeResult::Ok(1)),
+            ("COMMIT", FakeResult::Ok(0))
+        ]));
+        let storage = ItemStorageService::new(fake_db);
         assert_eq!(1, storage.update_item(&Item::new(1, "test2", 1.23, true, None)).await.unwrap());
     }
 
+    #[tokio::test]
+    async fn itemstorageservice_update_item_with_new_picture_release_previous_picture() {
+        let picture_hash = compute_picture_hash(b":-)").unwrap();
+        let fake_db = Box::new(FakeDataService::with_queries(vec![
+            ("BEGIN", FakeResult::Ok(0)),
+            ("SELECT id, descr, amount_minor, currency, active, picture_hash, attributes, deleted_at, version FROM item WHERE id = 1 AND deleted_at IS NULL", FakeResult::OkSelect(vec![get_item_row(1, "test2", Some("abc"))])),
+            (format!("INSERT INTO picture (hash, data, ref_count, mime_type, width, height, thumbnail) VALUES('{}', ?1, 1, 'text/plain', 3, 1, ?2) ON CONFLICT(hash) DO UPDATE SET ref_count = ref_count + 1", picture_hash).as_str(), FakeResult::Ok(1)),
+            (format!("UPDATE item SET descr = 'test2', amount = 1.23, amount_minor = 123, currency = 'USD', active = true, picture_hash = '{}', attributes = ?, version = version + 1 WHERE id = 1 AND version = 1", picture_hash).as_str(), FakeResult::Ok(1)),
+            (get_audit_query(1, "update").as_str(), FakeResult::Ok(1)),
+            ("UPDATE picture SET ref_count = ref_count - 1 WHERE hash = 'abc'", FakeResult::Ok(1)),
+            ("DELETE FROM picture WHERE hash = 'abc' AND ref_count <= 0", FakeResult::Ok(1)),
+            ("COMMIT", FakeResult::Ok(0))
+        ]));
+        let storage = ItemStorageService::new(fake_db);
+        assert_eq!(1, storage.update_item(&Item::new(1, "test2", 1.23, true, Some(b":-)".to_vec()))).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_update_item_with_unloaded_picture_keep_picture() {
+        let fake_db = Box::new(FakeDataService::with_queries(vec![
+            ("BEGIN", FakeResult::Ok(0)),
+            ("SELECT id, descr, amount_minor, currency, active, picture_hash, attributes, deleted_at, version FROM item WHERE id = 1 AND deleted_at IS NULL", FakeResult::OkSelect(vec![get_item_row(1, "test2", Some("abc"))])),
+            ("UPDATE item SET descr = 'test2', amount = 1.23, amount_minor = 123, currency = 'USD', active = true, picture_hash = 'abc', attributes = ?, version = version + 1 WHERE id = 1 AND version = 1", FakeResult::Ok(1)),
+            (get_audit_query(1, "update").as_str(), FakeResult::Ok(1)),
+            ("COMMIT", FakeResult::Ok(0))
+        ]));
+        let storage = ItemStorageService::new(fake_db);
+        let mut item = Item::new(1, "test2", 1.23, true, None);
+        item.set_picture_reference(Some("abc"));
+        assert_eq!(1, storage.update_item(&item).await.unwrap());
+    }
+
     #[tokio::test]
     async fn itemstorageservice_update_item_with_non_existing_item_return_0_row_affected() {
-        let mut fake_db = Box::new(FakeDataService::default());
-        fake_db.received_query = "UPDATE item SET descr = 'test2', amount = 1.23, active = true, picture = ? WHERE id = 1".to_owned();
-        fake_db.result = FakeResult::Ok(0);
-        let mut storage = ItemStorageService::new(fake_db);
+        let fake_db = Box::new(FakeDataService::with_queries(vec![
+            ("BEGIN", FakeResult::Ok(0)),
+            ("SELECT id, descr, amount_minor, currency, active, picture_hash, attributes, deleted_at, version FROM item WHERE id = 1 AND deleted_at IS NULL", FakeResult::OkSelect(vec![])),
+            ("COMMIT", FakeResult::Ok(0))
+        ]));
+        let storage = ItemStorageService::new(fake_db);
         assert_eq!(0, storage.update_item(&Item::new(1, "test2", 1.23, true, None)).await.unwrap());
     }
 
+    #[tokio::test]
+    async fn itemstorageservice_update_item_with_active_changed_update_active_only() {
+        let fake_db = Box::new(FakeDataService::with_queries(vec![
+            ("BEGIN", FakeResult::Ok(0)),
+            ("SELECT id, descr, amount_minor, currency, active, picture_hash, attributes, deleted_at, version FROM item WHERE id = 1 AND deleted_at IS NULL", FakeResult::OkSelect(vec![get_item_row(1, "test", Some("abc"))])),
+            ("UPDATE item SET active = false, version = version + 1 WHERE id = 1 AND version = 1", FakeResult::Ok(1)),
+            (get_audit_query(1, "update").as_str(), FakeResult::Ok(1)),
+            ("COMMIT", FakeResult::Ok(0))
+        ]));
+        let storage = ItemStorageService::new(fake_db);
+        let mut item = Item::new(1, "test", 1.23, true, None);
+        item.set_picture_reference(Some("abc"));
+        item.clear_dirty_fields();
+        item.set_active(false);
+        assert_eq!(1, storage.update_item(&item).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_patch_item_update_changed_fields_only() {
+        let fake_db = Box::new(FakeDataService::with_queries(vec![
+            ("BEGIN", FakeResult::Ok(0)),
+            ("SELECT id, descr, amount_minor, currency, active, picture_hash, attributes, deleted_at, version FROM item WHERE id = 1 AND deleted_at IS NULL", FakeResult::OkSelect(vec![get_item_row(1, "test", None)])),
+            ("UPDATE item SET descr = 'test2', amount = 9.99, amount_minor = 999, currency = 'EUR', version = version + 1 WHERE id = 1 AND version = 1", FakeResult::Ok(1)),
+            (get_audit_query(1, "update").as_str(), FakeResult::Ok(1)),
+            ("COMMIT", FakeResult::Ok(0))
+        ]));
+        let storage = ItemStorageService::new(fake_db);
+        let mut changes = ItemChanges::default();
+        changes.set_descr("test2");
+        changes.set_money(Money::new(999, Currency::new("EUR").unwrap()));
+        assert_eq!(1, storage.patch_item(1, &changes).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_patch_item_with_non_existing_item_return_0_row_affected() {
+        let fake_db = Box::new(FakeDataService::with_queries(vec![
+            ("BEGIN", FakeResult::Ok(0)),
+            ("SELECT id, descr, amount_minor, currency, active, picture_hash, attributes, deleted_at, version FROM item WHERE id = 1 AND deleted_at IS NULL", FakeResult::OkSelect(vec![])),
+            ("COMMIT", FakeResult::Ok(0))
+        ]));
+        let storage = ItemStorageService::new(fake_db);
+        let mut changes = ItemChanges::default();
+        changes.set_active(false);
+        assert_eq!(0, storage.patch_item(1, &changes).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_patch_item_with_invalid_change_return_err() {
+        let fake_db = Box::new(FakeDataService::with_queries(vec![
+            ("BEGIN", FakeResult::Ok(0)),
+            ("SELECT id, descr, amount_minor, currency, active, picture_hash, attributes, deleted_at, version FROM item WHERE id = 1 AND deleted_at IS NULL", FakeResult::OkSelect(vec![get_item_row(1, "test", None)])),
+            ("ROLLBACK", FakeResult::Ok(0))
+        ]));
+        let storage = ItemStorageService::new(fake_db);
+        let mut changes = ItemChanges::default();
+        changes.set_descr("");
+        assert_eq!(DatabaseServiceErrorKind::InvalidItem, storage.patch_item(1, &changes).await.unwrap_err().kind());
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_add_items_insert_valid_items_in_one_statement() {
+        let fake_db = Box::new(FakeDataService::with_queries(vec![
+            ("BEGIN", FakeResult::Ok(0)),
+            ("INSERT INTO item (descr, amount, amount_minor, currency, active, picture_hash, attributes) VALUES('a', 1.23, 123, 'USD', true, NULL, '{}'), ('c', 1.23, 123, 'USD', true, NULL, '{}') RETURNING id", FakeResult::OkSelect(vec![get_id_row(12), get_id_row(10)])),
+            (get_audit_query(10, "insert").as_str(), FakeResult::Ok(1)),
+            (get_audit_query(12, "insert").as_str(), FakeResult::Ok(1)),
+            ("COMMIT", FakeResult::Ok(0))
+        ]));
+        let storage = ItemStorageService::new(fake_db);
+        let items = vec![
+            Item::new(-1, "a", 1.23, true, None),
+            Item::new(-1, "", 1.23, true, None),
+            Item::new(-1, "c", 1.23, true, None)
+        ];
+        let results = storage.add_items(&items).await.unwrap();
+        assert_eq!(10, *results[0].as_ref().unwrap());
+        assert_eq!(DatabaseServiceErrorKind::InvalidItem, results[1].as_ref().unwrap_err().kind());
+        assert_eq!(12, *results[2].as_ref().unwrap());
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_add_items_with_error_execution_return_err() {
+        let fake_db = Box::new(FakeDataService::with_queries(vec![
+            ("BEGIN", FakeResult::Ok(0)),
+            ("INSERT INTO item (descr, amount, amount_minor, currency, active, picture_hash, attributes) VALUES('a', 1.23, 123, 'USD', true, NULL, '{}') RETURNING id", FakeResult::Err("error".to_owned())),
+            ("ROLLBACK", FakeResult::Ok(0))
+        ]));
+        let storage = ItemStorageService::new(fake_db);
+        assert_eq!("error", storage.add_items(&[Item::new(-1, "a", 1.23, true, None)]).await.unwrap_err().to_string());
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_update_items_with_conflict_keep_other_items() {
+        let mut outdated_row = get_item_row(2, "b", None);
+        outdated_row.add_column("version", DatabaseRowValue::Integer(2));
+        let fake_db = Box::new(FakeDataService::with_queries(vec![
+            ("BEGIN", FakeResult::Ok(0)),
+            ("SAVEPOINT bulk_item", FakeResult::Ok(0)),
+            ("SELECT id, descr, amount_minor, currency, active, picture_hash, attributes, deleted_at, version FROM item WHERE id = 1 AND deleted_at IS NULL", FakeResult::OkSelect(vec![get_item_row(1, "a", None)])),
+            ("UPDATE item SET descr = 'a2', amount = 1.23, amount_minor = 123, currency = 'USD', active = true, picture_hash = NULL, attributes = ?, version = version + 1 WHERE id = 1 AND version = 1", FakeResult::Ok(1)),
+            (get_audit_query(1, "update").as_str(), FakeResult::Ok(1)),
+            ("RELEASE bulk_item", FakeResult::Ok(0)),
+            ("SAVEPOINT bulk_item", FakeResult::Ok(0)),
+            ("SELECT id, descr, amount_minor, currency, active, picture_hash, attributes, deleted_at, version FROM item WHERE id = 2 AND deleted_at IS NULL", FakeResult::OkSelect(vec![outdated_row])),
+            ("ROLLBACK TO bulk_item", FakeResult::Ok(0)),
+            ("RELEASE bulk_item", FakeResult::Ok(0)),
+            ("COMMIT", FakeResult::Ok(0))
+        ]));
+        let storage = ItemStorageService::new(fake_db);
+        let items = vec![Item::new(1, "a2", 1.23, true, None), Item::new(2, "b2", 1.23, true, None)];
+        let results = storage.update_items(&items).await.unwrap();
+        assert_eq!(1, *results[0].as_ref().unwrap());
+        assert_eq!(DatabaseServiceErrorKind::Conflict, results[1].as_ref().unwrap_err().kind());
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_delete_items_return_result_per_item() {
+        let fake_db = Box::new(FakeDataService::with_queries(vec![
+            ("BEGIN", FakeResult::Ok(0)),
+            ("SAVEPOINT bulk_item", FakeResult::Ok(0)),
+            ("UPDATE item SET deleted_at = datetime('now'), version = version + 1 WHERE id = 1 AND deleted_at IS NULL AND version = 1", FakeResult::Ok(1)),
+            ("SELECT deleted_at FROM item WHERE id = 1", FakeResult::OkSelect(vec![])),
+            (get_audit_query(1, "delete").as_str(), FakeResult::Ok(1)),
+            ("RELEASE bulk_item", FakeResult::Ok(0)),
+            ("SAVEPOINT bulk_item", FakeResult::Ok(0)),
+            ("UPDATE item SET deleted_at = datetime('now'), version = version + 1 WHERE id = 2 AND deleted_at IS NULL AND version = 1", FakeResult::Ok(0)),
+            ("SELECT version FROM item WHERE id = 2 AND deleted_at IS NULL", FakeResult::OkSelect(vec![])),
+            ("RELEASE bulk_item", FakeResult::Ok(0)),
+            ("COMMIT", FakeResult::Ok(0))
+        ]));
+        let storage = ItemStorageService::new(fake_db);
+        let results = storage.delete_items(&[(1, 1), (2, 1)]).await.unwrap();
+        assert_eq!(1, *results[0].as_ref().unwrap());
+        assert_eq!(0, *results[1].as_ref().unwrap());
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_update_items_where_change_matching_items() {
+        let fake_db = Box::new(FakeDataService::with_queries(vec![
+            ("BEGIN", FakeResult::Ok(0)),
+            (format!("{} AND amount < 10", SELECT_ITEMS).as_str(), FakeResult::OkSelect(vec![get_item_row(1, "a", None), get_item_row(2, "b", None)])),
+            ("UPDATE item SET active = false, version = version + 1 WHERE id = 1 AND version = 1", FakeResult::Ok(1)),
+            (get_audit_query(1, "update").as_str(), FakeResult::Ok(1)),
+            ("UPDATE item SET active = false, version = version + 1 WHERE id = 2 AND version = 1", FakeResult::Ok(1)),
+            (get_audit_query(2, "update").as_str(), FakeResult::Ok(1)),
+            ("COMMIT", FakeResult::Ok(0))
+        ]));
+        let storage = ItemStorageService::new(fake_db);
+        let mut changes = ItemChanges::default();
+        changes.set_active(false);
+        assert_eq!(2, storage.update_items_where(&ItemFilter::parse("amount < 10").unwrap(), &changes).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_delete_items_where_trash_matching_items() {
+        let mut now_row = DatabaseRow::new();
+        now_row.add_column("now", DatabaseRowValue::Text("2024-05-01 10:00:00".to_owned()));
+        let fake_db = Box::new(FakeDataService::with_queries(vec![
+            ("BEGIN", FakeResult::Ok(0)),
+            ("SELECT id FROM item WHERE deleted_at IS NULL AND active = false", FakeResult::OkSelect(vec![get_item_row(1, "a", None), get_item_row(2, "b", None)])),
+            ("SELECT datetime('now') AS now", FakeResult::OkSelect(vec![now_row])),
+            ("UPDATE item SET deleted_at = '2024-05-01 10:00:00', version = version + 1 WHERE id IN (1, 2)", FakeResult::Ok(2)),
+            (get_audit_query(1, "delete").as_str(), FakeResult::Ok(1)),
+            (get_audit_query(2, "delete").as_str(), FakeResult::Ok(1)),
+            ("COMMIT", FakeResult::Ok(0))
+        ]));
+        let storage = ItemStorageService::new(fake_db);
+        assert_eq!(2, storage.delete_items_where(&ItemFilter::parse("active = false").unwrap()).await.unwrap());
+    }
+
     #[tokio::test]
     async fn itemstorageservice_update_item_with_error_execution_return_err() {
-        let fake_db = Box::new(FakeDataService::new("UPDATE item SET descr = 'test2', amount = 1.23, active = true, picture = ? WHERE id = 1",
-            FakeResult::Err("error".to_owned())));
-        let mut storage = ItemStorageService::new(fake_db);
+        let fake_db = Box::new(FakeDataService::with_queries(vec![
+            ("BEGIN", FakeResult::Ok(0)),
+            ("SELECT id, descr, amount_minor, currency, active, picture_hash, attributes, deleted_at, version FROM item WHERE id = 1 AND deleted_at IS NULL", FakeResult::OkSelect(vec![get_item_row(1, "test", None)])),
+            ("UPDATE item SET descr = 'test2', amount = 1.23, amount_minor = 123, currency = 'USD', active = true, picture_hash = NULL, attributes = ?, version = version + 1 WHERE id = 1 AND version = 1", FakeResult::Err("error".to_owned())),
+            ("ROLLBACK", FakeResult::Ok(0))
+        ]));
+        let storage = ItemStorageService::new(fake_db);
         assert_eq!("error", storage.update_item(&Item::new(1, "test2", 1.23, true, None)).await.unwrap_err().to_string());
     }
 
     #[tokio::test]
     async fn itemstorageservice_delete_item_with_id_2_existing_return_1_row_affected() {
-        let mut fake_db = Box::new(FakeDataService::default());
-        fake_db.received_query = "DELETE FROM item WHERE id = 2".to_owned();
-        fake_db.result = FakeResult::Ok(1);
-        let mut storage = ItemStorageService::new(fake_db);
-        assert_eq!(1, storage.delete_item(2).await.unwrap());
+        let mut row = DatabaseRow::new();
+        row.add_column("deleted_at", DatabaseRowValue::Text("2024-05-01 10:00:00".to_owned()));
+        let fake_db = Box::new(FakeDataService::with_queries(vec![
+            ("BEGIN", FakeResult::Ok(0)),
+            ("UPDATE item SET deleted_at = datetime('now'), version = version + 1 WHERE id = 2 AND deleted_at IS NULL AND version = 3", FakeResult::Ok(1)),
+            ("SELECT deleted_at FROM item WHERE id = 2", FakeResult::OkSelect(vec![row])),
+            (get_audit_query(2, "delete").as_str(), FakeResult::Ok(1)),
+            ("COMMIT", FakeResult::Ok(0))
+        ]));
+        let storage = ItemStorageService::new(fake_db);
+        assert_eq!(1, storage.delete_item(2, 3).await.unwrap());
     }
 
     #[tokio::test]
     async fn itemstorageservice_delete_item_with_id_2_non_existing_return_0_row_affected() {
-        let mut fake_db = Box::new(FakeDataService::default());
-        fake_db.received_query = "DELETE FROM item WHERE id = 2".to_owned();
-        fake_db.result = FakeResult::Ok(0);
-        let mut storage = ItemStorageService::new(fake_db);
-        assert_eq!(0, storage.delete_item(2).await.unwrap());
+        let fake_db = Box::new(FakeDataService::with_queries(vec![
+            ("BEGIN", FakeResult::Ok(0)),
+            ("UPDATE item SET deleted_at = datetime('now'), version = version + 1 WHERE id = 2 AND deleted_at IS NULL AND version = 3", FakeResult::Ok(0)),
+            ("SELECT version FROM item WHERE id = 2 AND deleted_at IS NULL", FakeResult::OkSelect(vec![])),
+            ("COMMIT", FakeResult::Ok(0))
+        ]));
+        let storage = ItemStorageService::new(fake_db);
+        assert_eq!(0, storage.delete_item(2, 3).await.unwrap());
     }
 
     #[tokio::test]
     async fn itemstorageservice_delete_item_with_error_execution_return_err() {
-        let fake_db = Box::new(FakeDataService::new("DELETE FROM item WHERE id = 1",
-            FakeResult::Err("error".to_owned())));
+        let fake_db = Box::new(FakeDataService::with_queries(vec![
+            ("BEGIN", FakeResult::Ok(0)),
+            ("UPDATE item SET deleted_at = datetime('now'), version = version + 1 WHERE id = 1 AND deleted_at IS NULL AND version = 3", FakeResult::Err("error".to_owned())),
+            ("ROLLBACK", FakeResult::Ok(0))
+        ]));
+        let storage = ItemStorageService::new(fake_db);
+        assert_eq!("error", storage.delete_item(1, 3).await.unwrap_err().to_string());
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_restore_item_with_trashed_item_return_1_row_affected() {
+        let mut row = DatabaseRow::new();
+        row.add_column("deleted_at", DatabaseRowValue::Text("2024-05-01 10:00:00".to_owned()));
+        let fake_db = Box::new(FakeDataService::with_queries(vec![
+            ("BEGIN", FakeResult::Ok(0)),
+            ("SELECT deleted_at FROM item WHERE id = 2 AND deleted_at IS NOT NULL", FakeResult::OkSelect(vec![row])),
+            ("UPDATE item SET deleted_at = NULL, version = version + 1 WHERE id = 2", FakeResult::Ok(1)),
+            (get_audit_query(2, "restore").as_str(), FakeResult::Ok(1)),
+            ("COMMIT", FakeResult::Ok(0))
+        ]));
+        let storage = ItemStorageService::new(fake_db);
+        assert_eq!(1, storage.restore_item(2).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_list_trash_return_trashed_items() {
+        let mut row = get_sample_item_row(1, "Test1");
+        row.add_column("deleted_at", DatabaseRowValue::Text("2024-05-01 10:00:00".to_owned()));
+        let fake_db = Box::new(FakeDataService::new(format!("SELECT {} FROM item WHERE deleted_at IS NOT NULL ORDER BY deleted_at DESC", ITEM_COLUMNS).as_str(),
+            FakeResult::OkSelect(vec![row])));
+        let storage = ItemStorageService::new(fake_db);
+        let items = storage.list_trash().await.unwrap();
+        assert_eq!(Some("2024-05-01 10:00:00"), items[0].get_deleted_at());
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_purge_delete_expired_items_and_release_pictures() {
+        let mut row = get_picture_hash_row(Some("abc"));
+        row.add_column("id", DatabaseRowValue::Integer(3));
+        let fake_db = Box::new(FakeDataService::with_queries(vec![
+            ("BEGIN", FakeResult::Ok(0)),
+            ("SELECT id, picture_hash FROM item WHERE deleted_at <= datetime('now', '-3600 seconds')", FakeResult::OkSelect(vec![row])),
+            ("DELETE FROM item WHERE id = 3", FakeResult::Ok(1)),
+            (get_audit_query(3, "purge").as_str(), FakeResult::Ok(1)),
+            ("UPDATE picture SET ref_count = ref_count - 1 WHERE hash = 'abc'", FakeResult::Ok(1)),
+            ("DELETE FROM picture WHERE hash = 'abc' AND ref_count <= 0", FakeResult::Ok(1)),
+            ("COMMIT", FakeResult::Ok(0))
+        ]));
         let mut storage = ItemStorageService::new(fake_db);
-        assert_eq!("error", storage.delete_item(1).await.unwrap_err().to_string());
+        storage.set_trash_retention(Duration::from_secs(3600));
+        assert_eq!(1, storage.purge().await.unwrap());
     }
 
     #[tokio::test]
     async fn itemstorageservice_get_all_items_with_error_execution_return_err() {
-        let fake_db = Box::new(FakeDataService::new("SELECT * FROM item",
+        let fake_db = Box::new(FakeDataService::new(SELECT_ITEMS,
             FakeResult::Err("error".to_owned())));
-        let mut storage = ItemStorageService::new(fake_db);
+        let storage = ItemStorageService::new(fake_db);
         assert_eq!("error", storage.get_all_items().await.unwrap_err().to_string());
     }
 
@@ -211,25 +1926,622 @@ mod tests {
         let mut retval = DatabaseRow::new();
         retval.add_column("id", DatabaseRowValue::Integer(id));
         retval.add_column("descr", DatabaseRowValue::Text(descr.to_owned()));
-        retval.add_column("amount", DatabaseRowValue::Real(1.23));
+        retval.add_column("amount_minor", DatabaseRowValue::Integer(123));
+        retval.add_column("currency", DatabaseRowValue::Text("USD".to_owned()));
         retval.add_column("active", DatabaseRowValue::Bool(true));
-        retval.add_column("picture", DatabaseRowValue::Blob(vec![]));
+        retval.add_column("picture_hash", DatabaseRowValue::Null);
         retval
     }
 
     #[tokio::test]
     async fn itemstorageservice_get_all_items_with_2_rows_return_2_items() {
-        let fake_db = Box::new(FakeDataService::new("SELECT * FROM item",
+        let fake_db = Box::new(FakeDataService::new(SELECT_ITEMS,
             FakeResult::OkSelect(vec![
                 get_sample_item_row(1, "Test1"),
                 get_sample_item_row(2, "Test2")
             ])));
 
-        let mut storage = ItemStorageService::new(fake_db);
+        let storage = ItemStorageService::new(fake_db);
         let items = storage.get_all_items().await.unwrap();
         assert_eq!(2, items.len());
         assert_eq!("Test1", items[0].get_descr());
         assert_eq!("Test2", items[1].get_descr());
     }
-}
 
+    #[tokio::test]
+    async fn itemstorageservice_get_all_items_return_exact_amount() {
+        let mut row = get_sample_item_row(1, "Test1");
+        row.add_column("amount_minor", DatabaseRowValue::Integer(1500));
+        row.add_column("currency", DatabaseRowValue::Text("JPY".to_owned()));
+        let fake_db = Box::new(FakeDataService::new(SELECT_ITEMS, FakeResult::OkSelect(vec![row])));
+        let storage = ItemStorageService::new(fake_db);
+        let items = storage.get_all_items().await.unwrap();
+        assert_eq!(Money::new(1500, Currency::new("JPY").unwrap()), items[0].get_money());
+        assert_eq!(1500.0, items[0].get_amount());
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_get_all_items_with_invalid_currency_return_err() {
+        let mut row = get_sample_item_row(1, "Test1");
+        row.add_column("currency", DatabaseRowValue::Text("dollar".to_owned()));
+        let fake_db = Box::new(FakeDataService::new(SELECT_ITEMS, FakeResult::OkSelect(vec![row])));
+        let storage = ItemStorageService::new(fake_db);
+        assert_eq!(DatabaseServiceErrorKind::InvalidConversion, storage.get_all_items().await.unwrap_err().kind());
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_get_all_items_with_picture_return_unloaded_picture_reference() {
+        let mut row = get_sample_item_row(1, "Test1");
+        row.add_column("picture_hash", DatabaseRowValue::Text("abc".to_owned()));
+        let fake_db = Box::new(FakeDataService::new(SELECT_ITEMS,
+            FakeResult::OkSelect(vec![row])));
+        let storage = ItemStorageService::new(fake_db);
+        let items = storage.get_all_items().await.unwrap();
+        assert_eq!(Some("abc"), items[0].get_picture_hash());
+        assert!(!items[0].is_picture_loaded());
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_get_all_items_with_pictures_return_picture_bytes() {
+        let mut row = get_sample_item_row(1, "Test1");
+        row.add_column("picture_hash", compute_picture_hash(&[1, 2, 3]).to_database_value().unwrap());
+        row.add_column("picture", DatabaseRowValue::Blob(vec![1, 2, 3]));
+        let fake_db = Box::new(FakeDataService::new(format!("SELECT {}, picture.data AS picture FROM item LEFT JOIN picture ON picture.hash = item.picture_hash WHERE deleted_at IS NULL", ITEM_COLUMNS).as_str(),
+            FakeResult::OkSelect(vec![row])));
+        let storage = ItemStorageService::new(fake_db);
+        let items = storage.get_all_items_with_pictures().await.unwrap();
+        assert_eq!(&vec![1, 2, 3], items[0].get_picture());
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_get_all_items_with_missing_column_return_err() {
+        let mut row = DatabaseRow::new();
+        row.add_column("id", DatabaseRowValue::Integer(1));
+        let fake_db = Box::new(FakeDataService::new(SELECT_ITEMS,
+            FakeResult::OkSelect(vec![row])));
+        let storage = ItemStorageService::new(fake_db);
+        assert_eq!("The column descr does not exist in the row", storage.get_all_items().await.unwrap_err().to_string());
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_migrate_legacy_pictures_move_blobs_to_picture_table() {
+        let picture_hash = compute_picture_hash(&[1, 2, 3]).unwrap();
+        let mut row = DatabaseRow::new();
+        row.add_column("id", DatabaseRowValue::Integer(4));
+        row.add_column("picture", DatabaseRowValue::Blob(vec![1, 2, 3]));
+        let fake_db = Box::new(FakeDataService::with_queries(vec![
+            ("BEGIN", FakeResult::Ok(0)),
+            ("SELECT id, picture FROM item WHERE picture_hash IS NULL AND length(picture) > 0", FakeResult::OkSelect(vec![row])),
+            (format!("INSERT INTO picture (hash, data, ref_count, mime_type, width, height, thumbnail) VALUES('{}', ?1, 1, NULL, NULL, NULL, ?2) ON CONFLICT(hash) DO UPDATE SET ref_count = ref_count + 1", picture_hash).as_str(), FakeResult::Ok(1)),
+            (format!("UPDATE item SET picture_hash = '{}', picture = NULL, version = version + 1 WHERE id = 4", picture_hash).as_str(), FakeResult::Ok(1)),
+            (get_audit_query(4, "update").as_str(), FakeResult::Ok(1)),
+            ("COMMIT", FakeResult::Ok(0))
+        ]));
+        let storage = ItemStorageService::new(fake_db);
+        assert_eq!(1, storage.migrate_legacy_pictures().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_get_all_items_with_attributes_return_items_with_attributes() {
+        let mut row = get_sample_item_row(1, "Test1");
+        row.add_column("attributes", DatabaseRowValue::Text("{\"color\":\"red\"}".to_owned()));
+        let fake_db = Box::new(FakeDataService::new(SELECT_ITEMS,
+            FakeResult::OkSelect(vec![row])));
+        let storage = ItemStorageService::new(fake_db);
+        let items = storage.get_all_items().await.unwrap();
+        assert_eq!(Some("red".to_owned()), items[0].get_attribute::<String>("color"));
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_get_items_with_attribute_filter_return_json_extract_query() {
+        let fake_db = Box::new(FakeDataService::new(format!("{} AND json_extract(attributes, '$.color') = 'red'", SELECT_ITEMS).as_str(),
+            FakeResult::OkSelect(vec![get_sample_item_row(1, "Test1")])));
+        let storage = ItemStorageService::new(fake_db);
+        let items = storage.get_items(&ItemFilter::parse("attributes.color = 'red'").unwrap()).await.unwrap();
+        assert_eq!(1, items.len());
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_create_attribute_index_then_get_items_use_generated_column() {
+        let fake_db = Box::new(FakeDataService::with_queries(vec![
+            ("SELECT name FROM pragma_table_xinfo('item') WHERE name = 'attr_color'", FakeResult::OkSelect(vec![])),
+            ("ALTER TABLE item ADD COLUMN attr_color GENERATED ALWAYS AS (json_extract(attributes, '$.color')) VIRTUAL", FakeResult::Ok(0)),
+            ("CREATE INDEX IF NOT EXISTS idx_item_attr_color ON item(attr_color)", FakeResult::Ok(0)),
+            (format!("{} AND attr_color = 'red'", SELECT_ITEMS).as_str(), FakeResult::OkSelect(vec![]))
+        ]));
+        let storage = ItemStorageService::new(fake_db);
+        storage.create_attribute_index("color").await.unwrap();
+        let items = storage.get_items(&ItemFilter::parse("attributes.color = 'red'").unwrap()).await.unwrap();
+        assert_eq!(0, items.len());
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_create_attribute_index_with_invalid_path_return_err() {
+        let fake_db = Box::new(FakeDataService::default());
+        let storage = ItemStorageService::new(fake_db);
+        assert_eq!(DatabaseServiceErrorKind::InvalidFilter, storage.create_attribute_index("color'); DROP TABLE item; --").await.unwrap_err().kind());
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_create_natural_key_with_attribute_create_unique_index() {
+        let fake_db = Box::new(FakeDataService::with_queries(vec![
+            ("SELECT name FROM pragma_table_xinfo('item') WHERE name = 'attr_sku'", FakeResult::OkSelect(vec![get_picture_hash_row(None)])),
+            ("CREATE INDEX IF NOT EXISTS idx_item_attr_sku ON item(attr_sku)", FakeResult::Ok(0)),
+            ("CREATE UNIQUE INDEX IF NOT EXISTS idx_item_key_attr_sku ON item(attr_sku) WHERE deleted_at IS NULL", FakeResult::Ok(0))
+        ]));
+        let storage = ItemStorageService::new(fake_db);
+        storage.create_natural_key("attributes.sku").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_upsert_item_with_unsupported_natural_key_return_err() {
+        let storage = ItemStorageService::new(Box::new(FakeDataService::with_queries(vec![])));
+        assert_eq!(DatabaseServiceErrorKind::InvalidFilter, storage.create_natural_key("amount").await.unwrap_err().kind());
+        let key = UpsertKey::Natural("active".to_owned());
+        let err = storage.upsert_item(&Item::new(-1, "test", 1.23, true, None), &key).await.unwrap_err();
+        assert_eq!(DatabaseServiceErrorKind::InvalidFilter, err.kind());
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_upsert_item_with_new_id_return_inserted() {
+        let fake_db = Box::new(FakeDataService::with_queries(vec![
+            ("BEGIN", FakeResult::Ok(0)),
+            ("SELECT id, descr, amount_minor, currency, active, picture_hash, attributes, deleted_at, version FROM item WHERE id = 5", FakeResult::OkSelect(vec![])),
+            ("INSERT INTO item (id, descr, amount, amount_minor, currency, active, picture_hash, attributes) VALUES(5, 'test', 1.23, 123, 'USD', true, NULL, ?1) ON CONFLICT(id) DO UPDATE SET descr = excluded.descr, amount = excluded.amount, amount_minor = excluded.amount_minor, currency = excluded.currency, active = excluded.active, picture_hash = excluded.picture_hash, attributes = excluded.attributes, version = version + 1 WHERE deleted_at IS NULL", FakeResult::Ok(5)),
+            (get_audit_query(5, "insert").as_str(), FakeResult::Ok(1)),
+            ("COMMIT", FakeResult::Ok(0))
+        ]));
+        let storage = ItemStorageService::new(fake_db);
+        let outcome = storage.upsert_item(&Item::new(5, "test", 1.23, true, None), &UpsertKey::Id).await.unwrap();
+        assert_eq!(UpsertOutcome::Inserted(5), outcome);
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_upsert_item_with_trashed_id_return_err() {
+        let mut row = get_item_row(5, "test", None);
+        row.add_column("deleted_at", DatabaseRowValue::Text("2024-05-01 10:00:00".to_owned()));
+        let fake_db = Box::new(FakeDataService::with_queries(vec![
+            ("BEGIN", FakeResult::Ok(0)),
+            ("SELECT id, descr, amount_minor, currency, active, picture_hash, attributes, deleted_at, version FROM item WHERE id = 5", FakeResult::OkSelect(vec![row])),
+            ("ROLLBACK", FakeResult::Ok(0))
+        ]));
+        let storage = ItemStorageService::new(fake_db);
+        let err = storage.upsert_item(&Item::new(5, "test", 1.23, true, None), &UpsertKey::Id).await.unwrap_err();
+        assert_eq!("The item 5 is in the trash", err.to_string());
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_upsert_item_with_existing_natural_key_return_updated() {
+        let fake_db = Box::new(FakeDataService::with_queries(vec![
+            ("BEGIN", FakeResult::Ok(0)),
+            ("SELECT id, descr, amount_minor, currency, active, picture_hash, attributes, deleted_at, version FROM item WHERE attr_sku = json_extract('{\"sku\":\"X1\"}', '$.sku') AND deleted_at IS NULL", FakeResult::OkSelect(vec![get_item_row(3, "test", None)])),
+            ("INSERT INTO item (descr, amount, amount_minor, currency, active, picture_hash, attributes) VALUES('test2', 1.23, 123, 'USD', true, NULL, ?1) ON CONFLICT(attr_sku) WHERE deleted_at IS NULL DO UPDATE SET descr = excluded.descr, amount = excluded.amount, amount_minor = excluded.amount_minor, currency = excluded.currency, active = excluded.active, picture_hash = excluded.picture_hash, attributes = excluded.attributes, version = version + 1", FakeResult::Ok(1)),
+            (get_audit_query(3, "update").as_str(), FakeResult::Ok(1)),
+            ("COMMIT", FakeResult::Ok(0))
+        ]));
+        let storage = ItemStorageService::new(fake_db);
+        let mut item = Item::new(-1, "test2", 1.23, true, None);
+        item.set_attribute("sku", "X1").unwrap();
+        let outcome = storage.upsert_item(&item, &UpsertKey::Natural("attributes.sku".to_owned())).await.unwrap();
+        assert_eq!(UpsertOutcome::Updated(3), outcome);
+        assert_eq!(3, outcome.item_id());
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_open_picture_reader_return_blob() {
+        let mut row = DatabaseRow::new();
+        row.add_column("rowid", DatabaseRowValue::Integer(7));
+        let fake_db = Box::new(FakeDataService::with_queries(vec![
+            ("SELECT rowid FROM picture WHERE hash = 'abc'", FakeResult::OkSelect(vec![row])),
+            ("OPEN BLOB picture.data 7", FakeResult::Ok(3))
+        ]));
+        let storage = ItemStorageService::new(fake_db);
+        let mut reader = storage.open_picture_reader("abc").await.unwrap().unwrap();
+        let mut content = vec![];
+        reader.read_to_end(&mut content).await.unwrap();
+        assert_eq!(3, reader.size());
+        assert_eq!(vec![0, 0, 0], content);
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_open_picture_reader_with_unknown_hash_return_none() {
+        let fake_db = Box::new(FakeDataService::new("SELECT rowid FROM picture WHERE hash = 'abc'",
+            FakeResult::OkSelect(vec![])));
+        let storage = ItemStorageService::new(fake_db);
+        assert!(storage.open_picture_reader("abc").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_picture_writer_finish_attach_picture_to_item() {
+        let picture_hash = compute_picture_hash(b":-)").unwrap();
+        let mut item_row = DatabaseRow::new();
+        item_row.add_column("version", DatabaseRowValue::Integer(2));
+        let fake_db = Box::new(FakeDataService::with_queries(vec![
+            ("SELECT version FROM item WHERE id = 4 AND deleted_at IS NULL", FakeResult::OkSelect(vec![item_row])),
+            ("INSERT INTO picture (hash, data, ref_count) VALUES('pending:' || hex(randomblob(16)), zeroblob(3), 0)", FakeResult::Ok(9)),
+            ("OPEN BLOB picture.data 9", FakeResult::Ok(3)),
+            ("BEGIN", FakeResult::Ok(0)),
+            (format!("SELECT rowid FROM picture WHERE hash = '{}'", picture_hash).as_str(), FakeResult::OkSelect(vec![])),
+            (format!("UPDATE picture SET hash = '{}', ref_count = 1, mime_type = 'text/plain', width = 3, height = 1 WHERE rowid = 9", picture_hash).as_str(), FakeResult::Ok(1)),
+            ("SELECT picture_hash, version FROM item WHERE id = 4 AND deleted_at IS NULL", FakeResult::OkSelect(vec![get_picture_version_row(Some("old"), 2)])),
+            (format!("UPDATE item SET picture_hash = '{}', version = version + 1 WHERE id = 4 AND version = 2", picture_hash).as_str(), FakeResult::Ok(1)),
+            (get_audit_query(4, "update").as_str(), FakeResult::Ok(1)),
+            ("UPDATE picture SET ref_count = ref_count - 1 WHERE hash = 'old'", FakeResult::Ok(1)),
+            ("DELETE FROM picture WHERE hash = 'old' AND ref_count <= 0", FakeResult::Ok(1)),
+            ("COMMIT", FakeResult::Ok(0))
+        ]));
+        let storage = ItemStorageService::new(fake_db);
+        let mut writer = storage.open_picture_writer(4, 3).await.unwrap();
+        writer.write_all(b":-").await.unwrap();
+        writer.write_all(b")").await.unwrap();
+        assert_eq!(picture_hash, writer.finish().await.unwrap());
+    }
+
+    fn attach_picture_queries(picture_hash: &str, item_rows: Vec<DatabaseRow>) -> Vec<(String, FakeResult)> {
+        let mut item_row = DatabaseRow::new();
+        item_row.add_column("version", DatabaseRowValue::Integer(2));
+        vec![
+            ("SELECT version FROM item WHERE id = 4 AND deleted_at IS NULL".to_owned(), FakeResult::OkSelect(vec![item_row])),
+            ("INSERT INTO picture (hash, data, ref_count) VALUES('pending:' || hex(randomblob(16)), zeroblob(3), 0)".to_owned(), FakeResult::Ok(9)),
+            ("OPEN BLOB picture.data 9".to_owned(), FakeResult::Ok(3)),
+            ("BEGIN".to_owned(), FakeResult::Ok(0)),
+            (format!("SELECT rowid FROM picture WHERE hash = '{}'", picture_hash), FakeResult::OkSelect(vec![])),
+            (format!("UPDATE picture SET hash = '{}', ref_count = 1, mime_type = 'text/plain', width = 3, height = 1 WHERE rowid = 9", picture_hash), FakeResult::Ok(1)),
+            ("SELECT picture_hash, version FROM item WHERE id = 4 AND deleted_at IS NULL".to_owned(), FakeResult::OkSelect(item_rows)),
+            ("ROLLBACK".to_owned(), FakeResult::Ok(0)),
+            ("DELETE FROM picture WHERE rowid = 9".to_owned(), FakeResult::Ok(1))
+        ]
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_picture_writer_finish_with_item_changed_since_open_return_conflict() {
+        let picture_hash = compute_picture_hash(b":-)").unwrap();
+        let queries = attach_picture_queries(picture_hash.as_str(), vec![get_picture_version_row(None, 3)]);
+        let fake_db = Box::new(FakeDataService::with_queries(queries.iter().map(|(q, r)| (q.as_str(), r.clone())).collect()));
+        let storage = ItemStorageService::new(fake_db);
+        let mut writer = storage.open_picture_writer(4, 3).await.unwrap();
+        writer.write_all(b":-)").await.unwrap();
+        let err = writer.finish().await.unwrap_err();
+        assert_eq!(DatabaseServiceErrorKind::Conflict, err.kind());
+        assert_eq!("The item 4 was changed by someone else: expected version 2 but found version 3", err.to_string());
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_picture_writer_finish_with_item_deleted_since_open_return_err() {
+        let picture_hash = compute_picture_hash(b":-)").unwrap();
+        let queries = attach_picture_queries(picture_hash.as_str(), vec![]);
+        let fake_db = Box::new(FakeDataService::with_queries(queries.iter().map(|(q, r)| (q.as_str(), r.clone())).collect()));
+        let storage = ItemStorageService::new(fake_db);
+        let mut writer = storage.open_picture_writer(4, 3).await.unwrap();
+        writer.write_all(b":-)").await.unwrap();
+        assert_eq!("The item 4 does not exist", writer.finish().await.unwrap_err().to_string());
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_picture_writer_finish_with_missing_bytes_delete_staging_row() {
+        let mut item_row = DatabaseRow::new();
+        item_row.add_column("version", DatabaseRowValue::Integer(2));
+        let fake_db = Box::new(FakeDataService::with_queries(vec![
+            ("SELECT version FROM item WHERE id = 4 AND deleted_at IS NULL", FakeResult::OkSelect(vec![item_row])),
+            ("INSERT INTO picture (hash, data, ref_count) VALUES('pending:' || hex(randomblob(16)), zeroblob(3), 0)", FakeResult::Ok(9)),
+            ("OPEN BLOB picture.data 9", FakeResult::Ok(3)),
+            ("DELETE FROM picture WHERE rowid = 9", FakeResult::Ok(1))
+        ]));
+        let storage = ItemStorageService::new(fake_db);
+        let mut writer = storage.open_picture_writer(4, 3).await.unwrap();
+        writer.write_all(&[1]).await.unwrap();
+        assert_eq!("Only 1 of the 3 bytes of the picture were written", writer.finish().await.unwrap_err().to_string());
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_open_picture_writer_with_deleted_item_return_err() {
+        let fake_db = Box::new(FakeDataService::new("SELECT version FROM item WHERE id = 4 AND deleted_at IS NULL",
+            FakeResult::OkSelect(vec![])));
+        let storage = ItemStorageService::new(fake_db);
+        let err = storage.open_picture_writer(4, 3).await.err().unwrap();
+        assert_eq!("The item 4 does not exist", err.to_string());
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_add_item_with_unsupported_picture_return_err() {
+        let fake_db = Box::new(FakeDataService::with_queries(vec![
+            ("BEGIN", FakeResult::Ok(0)),
+            ("ROLLBACK", FakeResult::Ok(0))
+        ]));
+        let storage = ItemStorageService::new(fake_db);
+        let err = storage.add_item(&Item::new(-1, "test", 1.23, true, Some(vec![1, 2, 3]))).await.unwrap_err();
+        assert_eq!(DatabaseServiceErrorKind::InvalidPicture, err.kind());
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_get_picture_info_return_format_and_dimensions() {
+        let mut row = DatabaseRow::new();
+        row.add_column("mime_type", DatabaseRowValue::Text("image/png".to_owned()));
+        row.add_column("width", DatabaseRowValue::Integer(200));
+        row.add_column("height", DatabaseRowValue::Integer(100));
+        let fake_db = Box::new(FakeDataService::new("SELECT mime_type, width, height FROM picture WHERE hash = 'abc'",
+            FakeResult::OkSelect(vec![row])));
+        let storage = ItemStorageService::new(fake_db);
+        let info = storage.get_picture_info("abc").await.unwrap().unwrap();
+        assert_eq!(PictureInfo { format: PictureFormat::Png, width: 200, height: 100 }, info);
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_get_picture_thumbnail_without_cached_thumbnail_generate_it() {
+        let mut thumbnail_row = DatabaseRow::new();
+        thumbnail_row.add_column("thumbnail", DatabaseRowValue::Null);
+        let mut picture_row = DatabaseRow::new();
+        picture_row.add_column("data", DatabaseRowValue::Blob(b":-)".to_vec()));
+        let fake_db = Box::new(FakeDataService::with_queries(vec![
+            ("SELECT thumbnail FROM picture WHERE hash = 'abc'", FakeResult::OkSelect(vec![thumbnail_row])),
+            ("SELECT data FROM picture WHERE hash = 'abc'", FakeResult::OkSelect(vec![picture_row])),
+            ("UPDATE picture SET mime_type = 'text/plain', width = 3, height = 1, thumbnail = ?1 WHERE hash = 'abc'", FakeResult::Ok(1))
+        ]));
+        let storage = ItemStorageService::new(fake_db);
+        assert_eq!(Some(b":-)".to_vec()), storage.get_picture_thumbnail("abc").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_import_picture_file_stream_file_to_item() {
+        let file = NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), b":-)").unwrap();
+        let picture_hash = compute_picture_hash(b":-)").unwrap();
+        let mut item_row = DatabaseRow::new();
+        item_row.add_column("version", DatabaseRowValue::Integer(2));
+        let fake_db = Box::new(FakeDataService::with_queries(vec![
+            ("SELECT version FROM item WHERE id = 4 AND deleted_at IS NULL", FakeResult::OkSelect(vec![item_row])),
+            ("INSERT INTO picture (hash, data, ref_count) VALUES('pending:' || hex(randomblob(16)), zeroblob(3), 0)", FakeResult::Ok(9)),
+            ("OPEN BLOB picture.data 9", FakeResult::Ok(3)),
+            ("BEGIN", FakeResult::Ok(0)),
+            (format!("SELECT rowid FROM picture WHERE hash = '{}'", picture_hash).as_str(), FakeResult::OkSelect(vec![get_picture_hash_row(None)])),
+            ("DELETE FROM picture WHERE rowid = 9", FakeResult::Ok(1)),
+            (format!("UPDATE picture SET ref_count = ref_count + 1 WHERE hash = '{}'", picture_hash).as_str(), FakeResult::Ok(1)),
+            ("SELECT picture_hash, version FROM item WHERE id = 4 AND deleted_at IS NULL", FakeResult::OkSelect(vec![get_picture_version_row(None, 2)])),
+            (format!("UPDATE item SET picture_hash = '{}', version = version + 1 WHERE id = 4 AND version = 2", picture_hash).as_str(), FakeResult::Ok(1)),
+            (get_audit_query(4, "update").as_str(), FakeResult::Ok(1)),
+            ("COMMIT", FakeResult::Ok(0))
+        ]));
+        let storage = ItemStorageService::new(fake_db);
+        assert_eq!(picture_hash, storage.import_picture_file(4, file.path()).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_diff_item_versions_return_changed_fields() {
+        let get_audit_row = |id: i64, action: &str, new_values: &str| {
+            let mut row = DatabaseRow::new();
+            row.add_column("id", DatabaseRowValue::Integer(id));
+            row.add_column("item_id", DatabaseRowValue::Integer(5));
+            row.add_column("action", DatabaseRowValue::Text(action.to_owned()));
+            row.add_column("actor", DatabaseRowValue::Text("alice".to_owned()));
+            row.add_column("changed_at", DatabaseRowValue::Text("2024-05-01 10:00:00".to_owned()));
+            row.add_column("old_values", DatabaseRowValue::Null);
+            row.add_column("new_values", DatabaseRowValue::Text(new_values.to_owned()));
+            row
+        };
+        let fake_db = Box::new(FakeDataService::new("SELECT id, item_id, action, actor, changed_at, old_values, new_values FROM item_audit WHERE item_id = 5 ORDER BY id",
+            FakeResult::OkSelect(vec![
+                get_audit_row(1, "insert", "{\"descr\":\"pen\",\"amount\":1.5}"),
+                get_audit_row(2, "update", "{\"amount\":2.0}"),
+                get_audit_row(3, "update", "{\"descr\":\"red pen\"}")
+            ])));
+        let storage = ItemStorageService::new(fake_db);
+        let changes = storage.diff_item_versions(5, 1, 3).await.unwrap();
+        assert_eq!(vec![
+            FieldChange { field: "amount".to_owned(), old_value: serde_json::json!(1.5), new_value: serde_json::json!(2.0) },
+            FieldChange { field: "descr".to_owned(), old_value: serde_json::json!("pen"), new_value: serde_json::json!("red pen") }
+        ], changes);
+        assert!(storage.diff_item_versions(5, 1, 4).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_update_item_with_outdated_version_return_conflict() {
+        let mut row = get_item_row(1, "test", None);
+        row.add_column("version", DatabaseRowValue::Integer(2));
+        let fake_db = Box::new(FakeDataService::with_queries(vec![
+            ("BEGIN", FakeResult::Ok(0)),
+            ("SELECT id, descr, amount_minor, currency, active, picture_hash, attributes, deleted_at, version FROM item WHERE id = 1 AND deleted_at IS NULL", FakeResult::OkSelect(vec![row])),
+            ("ROLLBACK", FakeResult::Ok(0))
+        ]));
+        let storage = ItemStorageService::new(fake_db);
+        let err = storage.update_item(&Item::new(1, "test2", 1.23, true, None)).await.unwrap_err();
+        assert_eq!(DatabaseServiceErrorKind::Conflict, err.kind());
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_update_item_changed_concurrently_return_conflict() {
+        let fake_db = Box::new(FakeDataService::with_queries(vec![
+            ("BEGIN", FakeResult::Ok(0)),
+            ("SELECT id, descr, amount_minor, currency, active, picture_hash, attributes, deleted_at, version FROM item WHERE id = 1 AND deleted_at IS NULL", FakeResult::OkSelect(vec![get_item_row(1, "test", None)])),
+            ("UPDATE item SET descr = 'test2', amount = 1.23, amount_minor = 123, currency = 'USD', active = true, picture_hash = NULL, attributes = ?, version = version + 1 WHERE id = 1 AND version = 1", FakeResult::Ok(0)),
+            ("ROLLBACK", FakeResult::Ok(0))
+        ]));
+        let storage = ItemStorageService::new(fake_db);
+        let err = storage.update_item(&Item::new(1, "test2", 1.23, true, None)).await.unwrap_err();
+        assert_eq!(DatabaseServiceErrorKind::Conflict, err.kind());
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_delete_item_with_outdated_version_return_conflict() {
+        let mut row = DatabaseRow::new();
+        row.add_column("version", DatabaseRowValue::Integer(4));
+        let fake_db = Box::new(FakeDataService::with_queries(vec![
+            ("BEGIN", FakeResult::Ok(0)),
+            ("UPDATE item SET deleted_at = datetime('now'), version = version + 1 WHERE id = 2 AND deleted_at IS NULL AND version = 3", FakeResult::Ok(0)),
+            ("SELECT version FROM item WHERE id = 2 AND deleted_at IS NULL", FakeResult::OkSelect(vec![row])),
+            ("ROLLBACK", FakeResult::Ok(0))
+        ]));
+        let storage = ItemStorageService::new(fake_db);
+        let err = storage.delete_item(2, 3).await.unwrap_err();
+        assert_eq!(DatabaseServiceErrorKind::Conflict, err.kind());
+        assert_eq!("The item 2 was changed by someone else: expected version 3 but found version 4", err.to_string());
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_add_item_with_apostrophe_in_descr_round_trip_descr() {
+        let (db_service, _dir) = item_database().await;
+        let storage = ItemStorageService::new(Box::new(db_service));
+        let item_id = storage.add_item(&Item::new(0, "O'Reilly's 'pen'", 1.0, true, None)).await.unwrap();
+        assert_eq!("O'Reilly's 'pen'", storage.get_item(item_id).await.unwrap().unwrap().get_descr());
+        let filter = ItemFilter::parse("descr = 'O''Reilly''s ''pen'''").unwrap();
+        assert_eq!(1, storage.get_items(&filter).await.unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_add_items_with_apostrophe_in_descr_store_descr() {
+        let (db_service, _dir) = item_database().await;
+        let storage = ItemStorageService::new(Box::new(db_service));
+        let items = [Item::new(0, "O'Reilly", 1.0, true, None), Item::new(0, "it's", 2.0, true, None)];
+        let item_ids: Vec<i64> = storage.add_items(&items).await.unwrap().into_iter().map(|r| r.unwrap()).collect();
+        assert_eq!("O'Reilly", storage.get_item(item_ids[0]).await.unwrap().unwrap().get_descr());
+        assert_eq!("it's", storage.get_item(item_ids[1]).await.unwrap().unwrap().get_descr());
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_upsert_item_with_apostrophe_in_descr_store_descr() {
+        let (db_service, _dir) = item_database().await;
+        let storage = ItemStorageService::new(Box::new(db_service));
+        storage.create_natural_key("descr").await.unwrap();
+        let key = UpsertKey::Natural("descr".to_owned());
+        let UpsertOutcome::Inserted(item_id) = storage.upsert_item(&Item::new(0, "O'Reilly", 1.0, true, None), &key).await.unwrap() else {
+            panic!("the item should be inserted")
+        };
+        let outcome = storage.upsert_item(&Item::new(0, "O'Reilly", 2.0, true, None), &key).await.unwrap();
+        assert_eq!(UpsertOutcome::Updated(item_id), outcome);
+        let outcome = storage.upsert_item(&Item::new(item_id, "it's", 3.0, true, None), &UpsertKey::Id).await.unwrap();
+        assert_eq!(UpsertOutcome::Updated(item_id), outcome);
+        assert_eq!("it's", storage.get_item(item_id).await.unwrap().unwrap().get_descr());
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_update_and_patch_item_with_apostrophe_in_descr_store_descr() {
+        let (db_service, _dir) = item_database().await;
+        let storage = ItemStorageService::new(Box::new(db_service));
+        let item_id = storage.add_item(&Item::new(0, "item", 1.0, true, None)).await.unwrap();
+        let mut item = storage.get_item(item_id).await.unwrap().unwrap();
+        item.set_descr("O'Reilly");
+        assert_eq!(1, storage.update_item(&item).await.unwrap());
+        assert_eq!("O'Reilly", storage.get_item(item_id).await.unwrap().unwrap().get_descr());
+        let mut changes = ItemChanges::default();
+        changes.set_descr("it's");
+        assert_eq!(1, storage.patch_item(item_id, &changes).await.unwrap());
+        assert_eq!("it's", storage.get_item(item_id).await.unwrap().unwrap().get_descr());
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_create_attribute_index_with_similar_paths_filter_each_path() {
+        let (db_service, _dir) = item_database().await;
+        let storage = ItemStorageService::new(Box::new(db_service));
+        storage.create_attribute_index("size.width").await.unwrap();
+        storage.create_attribute_index("size_width").await.unwrap();
+        let mut item = Item::new(0, "item", 1.0, true, None);
+        item.set_attributes(serde_json::from_str(r#"{"size": {"width": 1}, "size_width": 2}"#).unwrap());
+        storage.add_item(&item).await.unwrap();
+        for (filter, count) in [("attributes.size.width = 1", 1), ("attributes.size.width = 2", 0), ("attributes.size_width = 2", 1)] {
+            assert_eq!(count, storage.get_items(&ItemFilter::parse(filter).unwrap()).await.unwrap().len(), "{}", filter);
+        }
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_picture_writer_with_sof_after_large_app1_describe_jpeg() {
+        let mut jpeg = vec![];
+        image::DynamicImage::ImageRgb8(image::RgbImage::new(30, 20)).write_to(&mut Cursor::new(&mut jpeg), image::ImageFormat::Jpeg).unwrap();
+        let mut picture = vec![0xFF, 0xD8, 0xFF, 0xE1, 0xFF, 0xFF];
+        picture.resize(picture.len() + 0xFFFF - 2, 0);
+        picture.extend_from_slice(&jpeg[2..]);
+        assert!(picture.len() > PICTURE_HEADER_SIZE + 2);
+        let (db_service, _dir) = item_database().await;
+        let storage = ItemStorageService::new(Box::new(db_service));
+        let item_id = storage.add_item(&Item::new(0, "item", 1.0, true, None)).await.unwrap();
+        let mut writer = storage.open_picture_writer(item_id, picture.len() as u64).await.unwrap();
+        writer.write_all(&picture).await.unwrap();
+        let picture_hash = writer.finish().await.unwrap();
+        let info = storage.get_picture_info(picture_hash.as_str()).await.unwrap().unwrap();
+        assert_eq!(PictureInfo { format: PictureFormat::Jpeg, width: 30, height: 20 }, info);
+    }
+
+    // A file database with the schema of the migrations, shared by the
+    // connections of the pool. It is deleted when the directory is dropped
+    async fn item_database() -> (DatabaseService, TempDir) {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("sqlxusage.db");
+        let options = DatabaseOptions { create_if_missing: true, ..DatabaseOptions::default() };
+        let mut db_service = DatabaseService::with_options(format!("sqlite://{}", path.display()).as_str(), options);
+        db_service.connect().await.unwrap();
+        for migration in [include_str!("../../migrations/initial_db_creation.sql"),
+                          include_str!("../../migrations/add_item_attributes.sql"),
+                          include_str!("../../migrations/add_picture_storage.sql"),
+                          include_str!("../../migrations/add_picture_metadata.sql"),
+                          include_str!("../../migrations/add_item_soft_delete.sql"),
+                          include_str!("../../migrations/add_item_audit.sql"),
+                          include_str!("../../migrations/add_item_version.sql"),
+                          include_str!("../../migrations/add_item_money.sql")] {
+            db_service.execute(migration).await.unwrap();
+        }
+        (db_service, dir)
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_add_items_with_trigger_inserting_items_return_inserted_ids() {
+        let (db_service, _dir) = item_database().await;
+        let storage = ItemStorageService::new(Box::new(db_service));
+        storage.db_service.execute("CREATE TRIGGER copy_item AFTER INSERT ON item WHEN NEW.descr NOT LIKE 'copy of %' BEGIN \
+                                    INSERT INTO item (descr) VALUES('copy of ' || NEW.descr); END").await.unwrap();
+        let items = [Item::new(0, "a", 1.0, true, None), Item::new(0, "b", 2.0, true, None)];
+        let item_ids: Vec<i64> = storage.add_items(&items).await.unwrap().into_iter().map(|r| r.unwrap()).collect();
+        assert_eq!("a", storage.get_item(item_ids[0]).await.unwrap().unwrap().get_descr());
+        assert_eq!("b", storage.get_item(item_ids[1]).await.unwrap().unwrap().get_descr());
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_clones_add_items_from_concurrent_tasks() {
+        let (db_service, _dir) = item_database().await;
+        let storage = ItemStorageService::new(Box::new(db_service));
+        let tasks: Vec<_> = (0..8)
+            .map(|n| {
+                let storage = storage.clone();
+                tokio::spawn(async move { storage.add_item(&Item::new(0, format!("item {}", n).as_str(), 1.0, true, None)).await })
+            })
+            .collect();
+        let mut ids = HashSet::new();
+        for task in tasks {
+            ids.insert(task.await.unwrap().unwrap());
+        }
+        assert_eq!(8, ids.len());
+        assert_eq!(8, storage.get_items(&ItemFilter::new()).await.unwrap().len());
+    }
+
+    async fn count_pictures(storage: &ItemStorageService) -> i64 {
+        let rows = storage.db_service.select("SELECT count(*) AS count FROM picture").await.unwrap();
+        rows[0].get::<i64>("count").unwrap()
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_drop_unfinished_picture_writer_delete_staging_row() {
+        let (db_service, _dir) = item_database().await;
+        let storage = ItemStorageService::new(Box::new(db_service));
+        let item_id = storage.add_item(&Item::new(0, "item", 1.0, true, None)).await.unwrap();
+        let mut writer = storage.open_picture_writer(item_id, 3).await.unwrap();
+        writer.write_all(&[1]).await.unwrap();
+        drop(writer);
+        for _ in 0..100 {
+            if count_pictures(&storage).await == 0 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(0, count_pictures(&storage).await);
+    }
+
+    #[tokio::test]
+    async fn itemstorageservice_delete_pending_pictures_keep_finished_pictures() {
+        let (db_service, _dir) = item_database().await;
+        let storage = ItemStorageService::new(Box::new(db_service));
+        let item_id = storage.add_item(&Item::new(0, "item", 1.0, true, None)).await.unwrap();
+        let mut writer = storage.open_picture_writer(item_id, 3).await.unwrap();
+        writer.write_all(b":-)").await.unwrap();
+        writer.finish().await.unwrap();
+        storage.db_service.insert("INSERT INTO picture (hash, data, ref_count) VALUES('pending:abc', zeroblob(3), 0)", None).await.unwrap();
+        assert_eq!(1, storage.delete_pending_pictures().await.unwrap());
+        assert_eq!(1, count_pictures(&storage).await);
+    }
+}
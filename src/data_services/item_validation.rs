@@ -0,0 +1,153 @@
+use serde::Deserialize;
+use crate::data_services::database_service_error::{DatabaseServiceError, FieldError};
+use crate::data_services::picture_processing::PictureLimits;
+use crate::models::item::Item;
+
+// Rules are plain data so that they can be loaded from the configuration of
+// a deployment, e.g. `[{"rule": "descr_max_length", "max": 80}]`.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(tag = "rule", rename_all = "snake_case")]
+pub enum ItemRule {
+    DescrNotEmpty,
+    DescrMaxLength { max: usize },
+    // The amount was not NaN, infinite or out of range when set as an f64
+    AmountValid,
+    AmountNotNegative,
+    CurrencyIn { currencies: Vec<String> },
+    // Only checked when the picture bytes are loaded
+    PictureMaxSize { max_size: u64 },
+    RequiredAttribute { key: String }
+}
+
+impl ItemRule {
+    fn check(&self, item: &Item) -> Option<FieldError> {
+        let (field, message) = match self {
+            ItemRule::DescrNotEmpty if item.get_descr().trim().is_empty() =>
+                ("descr".to_owned(), "must not be empty".to_owned()),
+            ItemRule::DescrMaxLength { max } if item.get_descr().chars().count() > *max =>
+                ("descr".to_owned(), format!("must not be longer than {} characters", max)),
+            ItemRule::AmountValid if !item.has_valid_amount() =>
+                ("amount".to_owned(), format!("{} is not a valid amount", item.get_amount())),
+            ItemRule::AmountNotNegative if item.get_money().is_negative() =>
+                ("amount".to_owned(), "must not be negative".to_owned()),
+            ItemRule::CurrencyIn { currencies } if !currencies.iter().any(|c| c == item.get_money().currency().code()) =>
+                ("currency".to_owned(), format!("must be one of {}", currencies.join(", "))),
+            ItemRule::PictureMaxSize { max_size } if item.get_picture().len() as u64 > *max_size =>
+                ("picture".to_owned(), format!("must not be larger than {} bytes", max_size)),
+            ItemRule::RequiredAttribute { key } if !item.get_attributes().contains_key(key) =>
+                (format!("attributes.{}", key), "is required".to_owned()),
+            _ => return None
+        };
+        Some(FieldError {
+            field,
+            message
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ItemValidator {
+    rules: Vec<ItemRule>
+}
+
+impl ItemValidator {
+    pub fn new(rules: Vec<ItemRule>) -> Self {
+        ItemValidator {
+            rules
+        }
+    }
+
+    // Reads a JSON array of rules.
+    pub fn from_json(rules: &str) -> Result<Self, DatabaseServiceError> {
+        serde_json::from_str(rules)
+            .map(ItemValidator::new)
+            .map_err(|e| DatabaseServiceError::new(format!("The validation rules cannot be read: {}", e).as_str()))
+    }
+
+    pub fn get_rules(&self) -> &[ItemRule] {
+        &self.rules
+    }
+
+    // Returns the errors of all the rules the item breaks, in rule order.
+    // AmountValid keeps NaN and infinite amounts out of the SQL statements,
+    // so it is checked first when the rules leave it out.
+    pub fn validate(&self, item: &Item) -> Vec<FieldError> {
+        let mandatory_rule = (!self.rules.contains(&ItemRule::AmountValid)).then_some(&ItemRule::AmountValid);
+        mandatory_rule.into_iter()
+            .chain(self.rules.iter())
+            .filter_map(|rule| rule.check(item))
+            .collect()
+    }
+}
+
+impl Default for ItemValidator {
+    fn default() -> Self {
+        ItemValidator::new(vec![
+            ItemRule::DescrNotEmpty,
+            ItemRule::AmountValid,
+            ItemRule::AmountNotNegative,
+            ItemRule::PictureMaxSize { max_size: PictureLimits::default().max_size }
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(errors: Vec<FieldError>) -> Vec<String> {
+        errors.into_iter().map(|e| e.field).collect()
+    }
+
+    #[test]
+    fn validate_with_valid_item_return_no_error() {
+        let item = Item::new(0, "pen", 1.5, true, Some(b":-)".to_vec()));
+        assert!(ItemValidator::default().validate(&item).is_empty());
+    }
+
+    #[test]
+    fn validate_with_default_rules_return_field_errors() {
+        let item = Item::new(0, " ", f64::NAN, true, None);
+        assert_eq!(vec!["descr", "amount"], fields(ItemValidator::default().validate(&item)));
+        let item = Item::new(0, "pen", -1.0, true, None);
+        let errors = ItemValidator::default().validate(&item);
+        assert_eq!("amount: must not be negative", errors[0].to_string());
+    }
+
+    #[test]
+    fn validate_with_apostrophe_in_descr_return_no_error() {
+        let item = Item::new(0, "O'Reilly", 1.5, true, None);
+        assert!(ItemValidator::default().validate(&item).is_empty());
+    }
+
+    #[test]
+    fn validate_without_amount_valid_rule_still_check_amount() {
+        let validator = ItemValidator::new(vec![ItemRule::DescrNotEmpty]);
+        let item = Item::new(0, " ", f64::INFINITY, true, None);
+        assert_eq!(vec!["amount", "descr"], fields(validator.validate(&item)));
+        assert!(validator.validate(&Item::new(0, "pen", 1.5, true, None)).is_empty());
+    }
+
+    #[test]
+    fn validate_with_oversized_picture_return_picture_error() {
+        let validator = ItemValidator::new(vec![ItemRule::PictureMaxSize { max_size: 2 }]);
+        let item = Item::new(0, "pen", 1.5, true, Some(b":-)".to_vec()));
+        assert_eq!(vec!["picture"], fields(validator.validate(&item)));
+    }
+
+    #[test]
+    fn from_json_return_configured_rules() {
+        let validator = ItemValidator::from_json(r#"[
+            {"rule": "descr_max_length", "max": 3},
+            {"rule": "currency_in", "currencies": ["EUR"]},
+            {"rule": "required_attribute", "key": "color"}
+        ]"#).unwrap();
+        let item = Item::new(0, "pencil", 1.5, true, None);
+        assert_eq!(vec!["descr", "currency", "attributes.color"], fields(validator.validate(&item)));
+    }
+
+    #[test]
+    fn from_json_with_unknown_rule_return_err() {
+        assert!(ItemValidator::from_json(r#"[{"rule": "descr_is_nice"}]"#).is_err());
+    }
+}
@@ -0,0 +1,407 @@
+use std::io::Cursor;
+use image::imageops::FilterType;
+use image::{ImageFormat, ImageReader, Limits};
+use crate::data_services::database_service_base::Blob;
+use crate::data_services::database_service_error::{DatabaseServiceError, DatabaseServiceErrorKind};
+
+// Characters from the darkest to the brightest
+const ASCII_RAMP: &[u8] = b" .:-=+*#%@";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PictureFormat {
+    Png,
+    Jpeg,
+    Gif,
+    WebP,
+    // UTF-8 text art, its dimensions are counted in characters and lines
+    Text
+}
+
+impl PictureFormat {
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            PictureFormat::Png => "image/png",
+            PictureFormat::Jpeg => "image/jpeg",
+            PictureFormat::Gif => "image/gif",
+            PictureFormat::WebP => "image/webp",
+            PictureFormat::Text => "text/plain"
+        }
+    }
+
+    pub fn from_mime_type(mime_type: &str) -> Option<Self> {
+        [PictureFormat::Png, PictureFormat::Jpeg, PictureFormat::Gif, PictureFormat::WebP, PictureFormat::Text]
+            .into_iter()
+            .find(|format| format.mime_type() == mime_type)
+    }
+
+    fn image_format(self) -> Option<ImageFormat> {
+        match self {
+            PictureFormat::Png => Some(ImageFormat::Png),
+            PictureFormat::Jpeg => Some(ImageFormat::Jpeg),
+            PictureFormat::Gif => Some(ImageFormat::Gif),
+            PictureFormat::WebP => Some(ImageFormat::WebP),
+            PictureFormat::Text => None
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PictureInfo {
+    pub format: PictureFormat,
+    pub width: u32,
+    pub height: u32
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PictureLimits {
+    pub max_size: u64,
+    pub max_width: u32,
+    pub max_height: u32,
+    // Largest side of the generated thumbnails
+    pub thumbnail_size: u32
+}
+
+impl Default for PictureLimits {
+    fn default() -> Self {
+        PictureLimits {
+            max_size: 10 * 1024 * 1024,
+            max_width: 4096,
+            max_height: 4096,
+            thumbnail_size: 64
+        }
+    }
+}
+
+pub fn detect_picture_format(picture: &[u8]) -> Option<PictureFormat> {
+    if picture.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some(PictureFormat::Png)
+    } else if picture.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some(PictureFormat::Jpeg)
+    } else if picture.starts_with(b"GIF87a") || picture.starts_with(b"GIF89a") {
+        Some(PictureFormat::Gif)
+    } else if picture.len() >= 12 && picture.starts_with(b"RIFF") && &picture[8..12] == b"WEBP" {
+        Some(PictureFormat::WebP)
+    } else if is_text_art(picture) {
+        Some(PictureFormat::Text)
+    } else {
+        None
+    }
+}
+
+// Detects the picture format and reads its dimensions, rejecting pictures
+// that are not supported or exceed the limits. Raster pictures only have
+// their header decoded.
+pub fn describe_picture(picture: &[u8], limits: &PictureLimits) -> Result<PictureInfo, DatabaseServiceError> {
+    if picture.len() as u64 > limits.max_size {
+        return Err(invalid_picture(format!("The picture size of {} bytes exceeds the limit of {} bytes",
+                                           picture.len(),
+                                           limits.max_size).as_str()));
+    }
+    let format = detect_picture_format(picture)
+        .ok_or_else(|| invalid_picture("The picture format is not supported"))?;
+    let (width, height) = match format.image_format() {
+        Some(image_format) => image_reader(picture, image_format, limits).into_dimensions()
+            .map_err(|e| invalid_picture(format!("The picture cannot be read: {}", e).as_str()))?,
+        None => text_dimensions(picture)
+    };
+    describe_dimensions(format, width, height, limits)
+}
+
+// Checks the dimensions of a picture read elsewhere, e.g. by a
+// JpegSizeScanner, against the limits.
+pub fn describe_dimensions(format: PictureFormat,
+                           width: u32,
+                           height: u32,
+                           limits: &PictureLimits) -> Result<PictureInfo, DatabaseServiceError> {
+    if width > limits.max_width || height > limits.max_height {
+        return Err(invalid_picture(format!("The picture dimensions {}x{} exceed the limit of {}x{}",
+                                           width,
+                                           height,
+                                           limits.max_width,
+                                           limits.max_height).as_str()));
+    }
+    Ok(PictureInfo {
+        format,
+        width,
+        height
+    })
+}
+
+// Finds the dimensions of a JPEG fed in chunks. They are in its SOF segment,
+// which may come after EXIF or ICC segments of hundreds of KiB, so only the
+// markers and segment lengths are read and the segments in between skipped.
+#[derive(Default)]
+pub struct JpegSizeScanner {
+    // Offset of the next byte fed
+    position: u64,
+    // Offset of the next marker, the first one following SOI
+    next_marker: u64,
+    // The marker and the start of its segment read so far
+    segment: Vec<u8>,
+    dimensions: Option<(u32, u32)>,
+    done: bool
+}
+
+impl JpegSizeScanner {
+    pub fn new() -> Self {
+        JpegSizeScanner {
+            next_marker: 2,
+            ..JpegSizeScanner::default()
+        }
+    }
+
+    pub fn feed(&mut self, bytes: &[u8]) {
+        let mut index = 0;
+        while !self.done && index < bytes.len() {
+            let position = self.position + index as u64;
+            if position < self.next_marker {
+                index += (self.next_marker - position).min((bytes.len() - index) as u64) as usize;
+                continue;
+            }
+            self.segment.push(bytes[index]);
+            index += 1;
+            self.read_segment(self.position + index as u64);
+        }
+        self.position += bytes.len() as u64;
+    }
+
+    // The width and height, once the SOF segment is fed
+    pub fn dimensions(&self) -> Option<(u32, u32)> {
+        self.dimensions
+    }
+
+    fn read_segment(&mut self, position: u64) {
+        match self.segment[..] {
+            [0xFF] => (),
+            // A fill byte before the marker
+            [0xFF, 0xFF] => {
+                self.segment.pop();
+            },
+            // The image data starts at SOS, EOI ends the picture: no SOF
+            [0xFF, 0xDA | 0xD9] => self.done = true,
+            // Markers without segment
+            [0xFF, 0x01 | 0xD0..=0xD7] => {
+                self.segment.clear();
+                self.next_marker = position;
+            },
+            [0xFF, _] | [0xFF, _, _] => (),
+            [0xFF, marker, length_high, length_low, ..] => {
+                let length = u16::from_be_bytes([length_high, length_low]) as u64;
+                let is_sof = matches!(marker, 0xC0..=0xCF) && !matches!(marker, 0xC4 | 0xC8 | 0xCC);
+                if length < 2 {
+                    self.done = true;
+                } else if !is_sof {
+                    self.segment.clear();
+                    self.next_marker = position + length - 2;
+                } else if let [_, _, _, _, _precision, height_high, height_low, width_high, width_low] = self.segment[..] {
+                    self.dimensions = Some((u16::from_be_bytes([width_high, width_low]) as u32,
+                                            u16::from_be_bytes([height_high, height_low]) as u32));
+                    self.done = true;
+                }
+            },
+            // Not a marker: not a JPEG
+            _ => self.done = true
+        }
+    }
+}
+
+// Raster pictures are scaled down to a PNG fitting in a square of
+// thumbnail_size pixels. Text art is cropped to thumbnail_size columns and
+// half as many lines, since characters are about twice as high as wide.
+pub fn make_thumbnail(picture: &[u8], info: &PictureInfo, limits: &PictureLimits) -> Result<Blob, DatabaseServiceError> {
+    let image_format = match info.format.image_format() {
+        Some(image_format) => image_format,
+        None => return Ok(crop_text_art(picture, limits.thumbnail_size as usize, (limits.thumbnail_size / 2) as usize))
+    };
+    let image = image_reader(picture, image_format, limits).decode()
+        .map_err(|e| invalid_picture(format!("The picture cannot be decoded: {}", e).as_str()))?;
+    let mut retval = vec![];
+    image.thumbnail(limits.thumbnail_size, limits.thumbnail_size)
+        .write_to(&mut Cursor::new(&mut retval), ImageFormat::Png)
+        .map_err(|e| invalid_picture(format!("The thumbnail cannot be encoded: {}", e).as_str()))?;
+    Ok(retval)
+}
+
+// Renders a picture as text at most width characters wide. Raster pictures
+// are scaled, each character covering a cell twice as high as wide, and
+// mapped to ASCII_RAMP by luminance. With colored set, each character also
+// gets its cell color as an ANSI 24-bit escape sequence. Text art is
+// returned as is, cropped to width.
+pub fn render_picture_as_text(picture: &[u8],
+                              width: u32,
+                              colored: bool,
+                              limits: &PictureLimits) -> Result<String, DatabaseServiceError> {
+    let info = describe_picture(picture, limits)?;
+    let image_format = match info.format.image_format() {
+        Some(image_format) => image_format,
+        None => return Ok(String::from_utf8_lossy(&crop_text_art(picture, width as usize, usize::MAX)).into_owned())
+    };
+    let image = image_reader(picture, image_format, limits).decode()
+        .map_err(|e| invalid_picture(format!("The picture cannot be decoded: {}", e).as_str()))?;
+    let columns = width.clamp(1, info.width.max(1));
+    let rows = ((info.height as u64 * columns as u64) / (info.width.max(1) as u64 * 2)).max(1) as u32;
+    let cells = image.resize_exact(columns, rows, FilterType::Triangle).to_rgba8();
+    let mut retval = String::new();
+    for row in cells.rows() {
+        for pixel in row {
+            let [r, g, b, a] = pixel.0;
+            let luminance = (0.2126 * r as f64 + 0.7152 * g as f64 + 0.0722 * b as f64) * a as f64 / 255.0;
+            let index = (luminance / 256.0 * ASCII_RAMP.len() as f64) as usize;
+            let character = ASCII_RAMP[index.min(ASCII_RAMP.len() - 1)] as char;
+            if colored {
+                retval.push_str(format!("\x1b[38;2;{};{};{}m{}", r, g, b, character).as_str());
+            } else {
+                retval.push(character);
+            }
+        }
+        if colored {
+            retval.push_str("\x1b[0m");
+        }
+        retval.push('\n');
+    }
+    Ok(retval)
+}
+
+fn image_reader<'a>(picture: &'a [u8], image_format: ImageFormat, limits: &PictureLimits) -> ImageReader<Cursor<&'a [u8]>> {
+    let mut decoder_limits = Limits::default();
+    decoder_limits.max_image_width = Some(limits.max_width);
+    decoder_limits.max_image_height = Some(limits.max_height);
+    let mut reader = ImageReader::with_format(Cursor::new(picture), image_format);
+    reader.limits(decoder_limits);
+    reader
+}
+
+fn is_text_art(picture: &[u8]) -> bool {
+    match std::str::from_utf8(picture) {
+        Ok(text) => !text.trim().is_empty()
+            && text.chars().all(|c| !c.is_control() || matches!(c, '\n' | '\r' | '\t')),
+        Err(_) => false
+    }
+}
+
+fn text_dimensions(picture: &[u8]) -> (u32, u32) {
+    let text = String::from_utf8_lossy(picture);
+    let width = text.lines().map(|line| line.chars().count()).max().unwrap_or_default();
+    (width as u32, text.lines().count() as u32)
+}
+
+fn crop_text_art(picture: &[u8], columns: usize, lines: usize) -> Blob {
+    String::from_utf8_lossy(picture).lines()
+        .take(lines)
+        .map(|line| line.chars().take(columns).collect::<String>())
+        .collect::<Vec<String>>()
+        .join("\n")
+        .into_bytes()
+}
+
+fn invalid_picture(message: &str) -> DatabaseServiceError {
+    DatabaseServiceError::with_kind(DatabaseServiceErrorKind::InvalidPicture, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, RgbImage};
+
+    fn get_png(width: u32, height: u32) -> Blob {
+        let mut retval = vec![];
+        DynamicImage::ImageRgb8(RgbImage::new(width, height))
+            .write_to(&mut Cursor::new(&mut retval), ImageFormat::Png)
+            .unwrap();
+        retval
+    }
+
+    #[test]
+    fn describe_picture_with_png_return_dimensions() {
+        let info = describe_picture(&get_png(200, 100), &PictureLimits::default()).unwrap();
+        assert_eq!(PictureInfo { format: PictureFormat::Png, width: 200, height: 100 }, info);
+        assert_eq!("image/png", info.format.mime_type());
+    }
+
+    #[test]
+    fn describe_picture_with_text_art_return_characters_and_lines() {
+        let info = describe_picture(b"\n /\\_/\\\n( o.o )", &PictureLimits::default()).unwrap();
+        assert_eq!(PictureInfo { format: PictureFormat::Text, width: 7, height: 3 }, info);
+    }
+
+    #[test]
+    fn describe_picture_with_unknown_bytes_return_err() {
+        let err = describe_picture(&[1, 2, 3], &PictureLimits::default()).unwrap_err();
+        assert_eq!(DatabaseServiceErrorKind::InvalidPicture, err.kind());
+    }
+
+    #[test]
+    fn describe_picture_with_oversized_picture_return_err() {
+        let limits = PictureLimits { max_width: 100, ..PictureLimits::default() };
+        assert!(describe_picture(&get_png(200, 100), &limits).is_err());
+        let limits = PictureLimits { max_size: 4, ..PictureLimits::default() };
+        assert!(describe_picture(b"hello", &limits).is_err());
+    }
+
+    // A JPEG whose SOF segment comes after an APP1 segment of 64 KiB
+    fn get_jpeg_with_large_app1(width: u32, height: u32) -> Blob {
+        let mut jpeg = vec![];
+        DynamicImage::ImageRgb8(RgbImage::new(width, height))
+            .write_to(&mut Cursor::new(&mut jpeg), ImageFormat::Jpeg)
+            .unwrap();
+        let mut retval = vec![0xFF, 0xD8, 0xFF, 0xE1, 0xFF, 0xFF];
+        retval.resize(retval.len() + 0xFFFF - 2, 0);
+        retval.extend_from_slice(&jpeg[2..]);
+        retval
+    }
+
+    #[test]
+    fn jpeg_size_scanner_with_large_app1_in_chunks_return_dimensions() {
+        let picture = get_jpeg_with_large_app1(30, 20);
+        let mut scanner = JpegSizeScanner::new();
+        for chunk in picture.chunks(1000) {
+            scanner.feed(chunk);
+        }
+        assert_eq!(Some((30, 20)), scanner.dimensions());
+        assert_eq!(PictureInfo { format: PictureFormat::Jpeg, width: 30, height: 20 },
+                   describe_picture(&picture, &PictureLimits::default()).unwrap());
+    }
+
+    #[test]
+    fn jpeg_size_scanner_with_png_return_none() {
+        let mut scanner = JpegSizeScanner::new();
+        scanner.feed(&get_png(30, 20));
+        assert_eq!(None, scanner.dimensions());
+    }
+
+    #[test]
+    fn make_thumbnail_with_png_return_scaled_png() {
+        let picture = get_png(200, 100);
+        let limits = PictureLimits::default();
+        let info = describe_picture(&picture, &limits).unwrap();
+        let thumbnail = make_thumbnail(&picture, &info, &limits).unwrap();
+        assert_eq!(PictureInfo { format: PictureFormat::Png, width: 64, height: 32 }, describe_picture(&thumbnail, &limits).unwrap());
+    }
+
+    #[test]
+    fn render_picture_as_text_with_png_return_scaled_ascii() {
+        let image = RgbImage::from_pixel(8, 8, image::Rgb([255, 255, 255]));
+        let mut picture = vec![];
+        DynamicImage::ImageRgb8(image).write_to(&mut Cursor::new(&mut picture), ImageFormat::Png).unwrap();
+        assert_eq!("@@@@\n@@@@\n", render_picture_as_text(&picture, 4, false, &PictureLimits::default()).unwrap());
+    }
+
+    #[test]
+    fn render_picture_as_text_colored_return_ansi_sequences() {
+        let text = render_picture_as_text(&get_png(2, 2), 2, true, &PictureLimits::default()).unwrap();
+        assert_eq!("\x1b[38;2;0;0;0m \x1b[38;2;0;0;0m \x1b[0m\n", text);
+    }
+
+    #[test]
+    fn render_picture_as_text_with_text_art_crop_lines() {
+        assert_eq!("ab\ncd", render_picture_as_text(b"abc\ncde", 2, false, &PictureLimits::default()).unwrap());
+    }
+
+    #[test]
+    fn make_thumbnail_with_text_art_crop_text() {
+        let limits = PictureLimits { thumbnail_size: 4, ..PictureLimits::default() };
+        let picture = b"abcdef\nghijkl\nmnopqr";
+        let info = describe_picture(picture, &limits).unwrap();
+        assert_eq!(b"abcd\nghij".to_vec(), make_thumbnail(picture, &info, &limits).unwrap());
+    }
+}
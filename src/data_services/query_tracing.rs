@@ -0,0 +1,173 @@
+use std::future::Future;
+use std::time::Instant;
+use tracing::field::Empty;
+use tracing::{Instrument, Span};
+use crate::data_services::database_service_base::{DatabaseRow, DatabaseRowValue};
+use crate::data_services::database_service_error::DatabaseServiceError;
+
+// Target of the spans and events, e.g. RUST_LOG=sqlxusage::db=debug
+pub const TRACING_TARGET: &str = "sqlxusage::db";
+
+// What a statement returned, as recorded in db.rows
+pub(crate) trait QueryOutcome {
+    fn rows(&self) -> u64;
+}
+
+// The id of the inserted row
+impl QueryOutcome for i64 {
+    fn rows(&self) -> u64 {
+        1
+    }
+}
+
+// The number of rows affected
+impl QueryOutcome for u64 {
+    fn rows(&self) -> u64 {
+        *self
+    }
+}
+
+impl QueryOutcome for Vec<DatabaseRow> {
+    fn rows(&self) -> u64 {
+        self.len() as u64
+    }
+}
+
+impl QueryOutcome for () {
+    fn rows(&self) -> u64 {
+        0
+    }
+}
+
+// A span for one operation of a service or a transaction. The values bound
+// to the statement are only counted unless trace_values is set, in which
+// case BLOBs are still reduced to their size. Values written as literals in
+// the SQL text are not redacted.
+pub(crate) fn query_span(operation: &str,
+                         query: &str,
+                         values: &[DatabaseRowValue],
+                         in_transaction: bool,
+                         trace_values: bool) -> Span {
+    let span = tracing::debug_span!(target: TRACING_TARGET,
+                                    "db.query",
+                                    db.operation = operation,
+                                    db.statement = query,
+                                    db.params = values.len(),
+                                    db.values = Empty,
+                                    db.in_transaction = in_transaction,
+                                    db.rows = Empty,
+                                    db.duration_ms = Empty,
+                                    db.retries = Empty,
+                                    db.error_kind = Empty);
+    if trace_values && !values.is_empty() {
+        span.record("db.values", format_values(values).as_str());
+    }
+    span
+}
+
+// Runs the operation within the span, then records its outcome on the span
+// and as an event: debug when it succeeds, warn when it fails.
+pub(crate) async fn trace_query<T, F>(span: Span, operation: F) -> Result<T, DatabaseServiceError>
+    where T: QueryOutcome,
+          F: Future<Output = Result<T, DatabaseServiceError>> {
+    let started_at = Instant::now();
+    let result = operation.instrument(span.clone()).await;
+    let duration_ms = started_at.elapsed().as_secs_f64() * 1000.0;
+    span.record("db.duration_ms", duration_ms);
+    match &result {
+        Ok(outcome) => {
+            span.record("db.rows", outcome.rows());
+            tracing::debug!(target: TRACING_TARGET, parent: &span, rows = outcome.rows(), duration_ms, "query succeeded");
+        },
+        Err(e) => {
+            span.record("db.error_kind", tracing::field::debug(e.kind()));
+            span.record("db.retries", e.retries());
+            tracing::warn!(target: TRACING_TARGET,
+                           parent: &span,
+                           error_kind = ?e.kind(),
+                           retries = e.retries(),
+                           duration_ms,
+                           "query failed: {}", e);
+        }
+    }
+    result
+}
+
+fn format_values(values: &[DatabaseRowValue]) -> String {
+    let values: Vec<String> = values.iter()
+        .map(|value| match value {
+            DatabaseRowValue::Blob(blob) => format!("Blob({} bytes)", blob.len()),
+            value => format!("{:?}", value)
+        })
+        .collect();
+    format!("[{}]", values.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use crate::data_services::database_service_error::DatabaseServiceErrorKind;
+
+    // Collects the formatted events of a test
+    #[derive(Clone, Default)]
+    struct CapturedLog(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for CapturedLog {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl CapturedLog {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    async fn traced_queries(trace_values: bool) -> String {
+        let log = CapturedLog::default();
+        let writer = log.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let values = vec![DatabaseRowValue::Text("secret".to_owned())];
+        let span = query_span("update", "UPDATE item SET descr = ?1", &values, false, trace_values);
+        let _ = trace_query(span, async { Ok(3_u64) }).await;
+        let span = query_span("select", "SELECT * FROM item", &[], true, trace_values);
+        let _ = trace_query::<Vec<DatabaseRow>, _>(span, async {
+            Err(DatabaseServiceError::with_kind(DatabaseServiceErrorKind::Busy, "database is locked").with_retries(2))
+        }).await;
+        log.contents()
+    }
+
+    #[tokio::test]
+    async fn trace_query_record_outcome_without_values() {
+        let log = traced_queries(false).await;
+        assert!(log.contains("db.statement=\"UPDATE item SET descr = ?1\" db.params=1"), "{}", log);
+        assert!(log.contains("query succeeded rows=3"), "{}", log);
+        assert!(log.contains("WARN"), "{}", log);
+        assert!(log.contains("query failed: database is locked (gave up after 2 retries) error_kind=Busy retries=2"), "{}", log);
+        assert!(!log.contains("secret"), "{}", log);
+    }
+
+    #[tokio::test]
+    async fn trace_query_with_trace_values_record_values() {
+        assert!(traced_queries(true).await.contains(r#"db.values="[Text(\"secret\")]""#));
+    }
+
+    #[test]
+    fn format_values_reduce_blobs_to_their_size() {
+        let values = vec![DatabaseRowValue::Text("pen".to_owned()), DatabaseRowValue::Blob(vec![0; 2048]), DatabaseRowValue::Null];
+        assert_eq!(r#"[Text("pen"), Blob(2048 bytes), Null]"#, format_values(&values));
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, Instant};
+use crate::data_services::database_service_error::{DatabaseServiceError, DatabaseServiceErrorKind};
+
+// Kinds of statements a retry policy can be set for
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DatabaseOperation {
+    Insert,
+    Update,
+    Delete,
+    Select,
+    Execute
+}
+
+impl DatabaseOperation {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DatabaseOperation::Insert => "insert",
+            DatabaseOperation::Update => "update",
+            DatabaseOperation::Delete => "delete",
+            DatabaseOperation::Select => "select",
+            DatabaseOperation::Execute => "execute"
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        [DatabaseOperation::Insert, DatabaseOperation::Update, DatabaseOperation::Delete, DatabaseOperation::Select, DatabaseOperation::Execute]
+            .into_iter()
+            .find(|operation| operation.as_str().eq_ignore_ascii_case(value))
+    }
+}
+
+// How statements failing because the database is busy or locked are
+// retried. Other errors are returned at once. The delay before the n-th
+// retry is initial_backoff * 2^(n-1), capped to max_backoff, of which a
+// random part of up to a half is removed when jitter is set so that
+// competing writers do not retry in lockstep.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    // Including the first one, 1 disables the retries
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    // No retry is started past this delay since the first attempt
+    pub deadline: Option<Duration>,
+    pub jitter: bool
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(20),
+            max_backoff: Duration::from_secs(1),
+            deadline: Some(Duration::from_secs(10)),
+            jitter: true
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn none() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            ..RetryPolicy::default()
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), DatabaseServiceError> {
+        if self.max_attempts == 0 {
+            return Err(DatabaseServiceError::new("The retry policy needs at least one attempt"));
+        }
+        if self.initial_backoff > self.max_backoff {
+            return Err(DatabaseServiceError::new("The initial backoff of the retry policy exceeds its maximum backoff"));
+        }
+        Ok(())
+    }
+
+    // Delay before the given retry, starting at 1
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let exponential = self.initial_backoff
+            .checked_mul(2_u32.saturating_pow(retry.saturating_sub(1)))
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff);
+        if !self.jitter {
+            return exponential;
+        }
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u32(retry);
+        let random_nanos = hasher.finish() % (exponential.as_nanos() as u64 / 2 + 1);
+        exponential - Duration::from_nanos(random_nanos)
+    }
+}
+
+pub fn is_transient(error: &DatabaseServiceError) -> bool {
+    error.kind() == DatabaseServiceErrorKind::Busy
+}
+
+// Attempts of one operation under a policy:
+//
+//     let mut retry = Retry::new(policy);
+//     loop {
+//         match operation().await {
+//             Err(e) if retry.should_retry(&e) => retry.wait().await,
+//             result => return retry.finish(result)
+//         }
+//     }
+pub(crate) struct Retry {
+    policy: RetryPolicy,
+    started_at: Instant,
+    retries: u32
+}
+
+impl Retry {
+    pub(crate) fn new(policy: RetryPolicy) -> Self {
+        Retry {
+            policy,
+            started_at: Instant::now(),
+            retries: 0
+        }
+    }
+
+    pub(crate) fn should_retry(&self, error: &DatabaseServiceError) -> bool {
+        if !is_transient(error) || self.retries + 1 >= self.policy.max_attempts {
+            return false;
+        }
+        match self.policy.deadline {
+            Some(deadline) => self.started_at.elapsed() + self.policy.backoff(self.retries + 1) <= deadline,
+            None => true
+        }
+    }
+
+    pub(crate) async fn wait(&mut self) {
+        self.retries += 1;
+        tokio::time::sleep(self.policy.backoff(self.retries)).await;
+    }
+
+    // Counts the retries in the error of the last attempt
+    pub(crate) fn finish<T>(&self, result: Result<T, DatabaseServiceError>) -> Result<T, DatabaseServiceError> {
+        match result {
+            Err(e) if self.retries > 0 => Err(e.with_retries(self.retries)),
+            result => result
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn busy_error() -> DatabaseServiceError {
+        DatabaseServiceError::with_kind(DatabaseServiceErrorKind::Busy, "database is locked")
+    }
+
+    #[test]
+    fn backoff_without_jitter_double_up_to_max() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(300),
+            jitter: false,
+            ..RetryPolicy::default()
+        };
+        assert_eq!(Duration::from_millis(100), policy.backoff(1));
+        assert_eq!(Duration::from_millis(200), policy.backoff(2));
+        assert_eq!(Duration::from_millis(300), policy.backoff(3));
+        assert_eq!(Duration::from_millis(300), policy.backoff(40));
+    }
+
+    #[test]
+    fn backoff_with_jitter_stay_within_half_of_delay() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(100),
+            ..RetryPolicy::default()
+        };
+        for _ in 0..100 {
+            let backoff = policy.backoff(2);
+            assert!(backoff >= Duration::from_millis(100) && backoff <= Duration::from_millis(200));
+        }
+    }
+
+    #[test]
+    fn should_retry_only_transient_errors_within_limits() {
+        let retry = Retry::new(RetryPolicy::default());
+        assert!(retry.should_retry(&busy_error()));
+        assert!(!retry.should_retry(&DatabaseServiceError::new("no such table: item")));
+        assert!(!Retry::new(RetryPolicy::none()).should_retry(&busy_error()));
+        let retry = Retry::new(RetryPolicy { deadline: Some(Duration::ZERO), ..RetryPolicy::default() });
+        assert!(!retry.should_retry(&busy_error()));
+    }
+
+    #[tokio::test]
+    async fn finish_after_retries_count_them_in_error() {
+        let mut retry = Retry::new(RetryPolicy { initial_backoff: Duration::from_millis(1), ..RetryPolicy::default() });
+        retry.wait().await;
+        retry.wait().await;
+        let err = retry.finish::<()>(Err(busy_error())).unwrap_err();
+        assert_eq!(2, err.retries());
+        assert_eq!("database is locked (gave up after 2 retries)", err.to_string());
+    }
+
+    #[test]
+    fn validate_with_inconsistent_policy_return_err() {
+        assert!(RetryPolicy::default().validate().is_ok());
+        assert!(RetryPolicy { max_attempts: 0, ..RetryPolicy::default() }.validate().is_err());
+        assert!(RetryPolicy { initial_backoff: Duration::from_secs(2), ..RetryPolicy::default() }.validate().is_err());
+    }
+}
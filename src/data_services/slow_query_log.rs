@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use sqlx::sqlite::SqliteConnection;
+use sqlx::Row;
+use crate::data_services::query_tracing::TRACING_TARGET;
+
+// Created by migrations/add_slow_query_log.sql
+pub const SLOW_QUERY_TABLE: &str = "Slow_Query";
+
+// A statement that took longer than the slow_query_threshold option
+#[derive(Clone, Debug, PartialEq)]
+pub struct SlowQuery {
+    pub operation: String,
+    pub statement: String,
+    pub duration: Duration,
+    pub in_transaction: bool,
+    // Output of EXPLAIN QUERY PLAN, nested steps being indented
+    pub query_plan: Vec<String>,
+    // Tables read row by row without an index
+    pub full_scans: Vec<String>
+}
+
+impl SlowQuery {
+    // Explains the statement on the connection that ran it so that temporary
+    // objects of the connection are visible. Statements that cannot be
+    // explained get an empty plan.
+    pub(crate) async fn explain(conn: &mut SqliteConnection,
+                                operation: &str,
+                                statement: &str,
+                                duration: Duration,
+                                in_transaction: bool) -> Self {
+        let query_plan = match sqlx::query(format!("EXPLAIN QUERY PLAN {}", statement).as_str()).fetch_all(&mut *conn).await {
+            Ok(rows) => format_query_plan(rows.iter()
+                .map(|row| (row.try_get("id").unwrap_or_default(),
+                            row.try_get("parent").unwrap_or_default(),
+                            row.try_get("detail").unwrap_or_default()))
+                .collect()),
+            Err(e) => {
+                tracing::debug!(target: TRACING_TARGET, "the slow query cannot be explained: {}", e);
+                vec![]
+            }
+        };
+        SlowQuery {
+            operation: operation.to_owned(),
+            statement: statement.to_owned(),
+            duration,
+            in_transaction,
+            full_scans: full_table_scans(&query_plan),
+            query_plan
+        }
+    }
+
+    // Logs the query as a warning and, when to_table is set, inserts it in
+    // the Slow_Query table through the same connection: inside a transaction,
+    // the entry is rolled back with it.
+    pub(crate) async fn record(&self, conn: &mut SqliteConnection, to_table: bool) {
+        tracing::warn!(target: TRACING_TARGET,
+                       operation = self.operation.as_str(),
+                       statement = self.statement.as_str(),
+                       duration_ms = self.duration.as_secs_f64() * 1000.0,
+                       full_scans = self.full_scans.join(", ").as_str(),
+                       "slow query, plan: {}", self.query_plan.join(" | "));
+        if !to_table {
+            return;
+        }
+        let result = sqlx::query(format!("INSERT INTO {} (operation, statement, duration_ms, in_transaction, query_plan, full_scans) \
+                                          VALUES(?1, ?2, ?3, ?4, ?5, ?6)", SLOW_QUERY_TABLE).as_str())
+            .bind(self.operation.as_str())
+            .bind(self.statement.as_str())
+            .bind(self.duration.as_secs_f64() * 1000.0)
+            .bind(self.in_transaction)
+            .bind(self.query_plan.join("\n"))
+            .bind(self.full_scans.join(","))
+            .execute(&mut *conn).await;
+        if let Err(e) = result {
+            tracing::warn!(target: TRACING_TARGET, "the slow query cannot be recorded in {}: {}", SLOW_QUERY_TABLE, e);
+        }
+    }
+}
+
+// Indents each step of the plan by its depth, from (id, parent, detail) rows
+fn format_query_plan(steps: Vec<(i64, i64, String)>) -> Vec<String> {
+    let mut depths: HashMap<i64, usize> = HashMap::new();
+    steps.into_iter()
+        .map(|(id, parent, detail)| {
+            let depth = depths.get(&parent).map(|depth| depth + 1).unwrap_or(0);
+            depths.insert(id, depth);
+            format!("{}{}", "  ".repeat(depth), detail)
+        })
+        .collect()
+}
+
+// "SCAN item" or, before SQLite 3.36, "SCAN TABLE item". Scans using an
+// index and of constant rows, e.g. "SCAN 3 CONSTANT ROWS" for a multi-row
+// VALUES, are not full table scans.
+fn full_table_scans(query_plan: &[String]) -> Vec<String> {
+    let mut retval: Vec<String> = vec![];
+    for step in query_plan {
+        let scanned = match step.trim_start().strip_prefix("SCAN ") {
+            Some(scanned) if !scanned.contains(" USING ") && !scanned.contains("CONSTANT ROW") => scanned,
+            _ => continue
+        };
+        let table = scanned.strip_prefix("TABLE ").unwrap_or(scanned);
+        let table = table.split_whitespace().next().unwrap_or_default().to_owned();
+        if !table.is_empty() && !retval.contains(&table) {
+            retval.push(table);
+        }
+    }
+    retval
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_query_plan_indent_nested_steps() {
+        let plan = format_query_plan(vec![(2, 0, "SCAN item".to_owned()),
+                                          (5, 0, "CORRELATED SCALAR SUBQUERY 1".to_owned()),
+                                          (9, 5, "SEARCH picture USING INDEX sqlite_autoindex_picture_1 (hash=?)".to_owned())]);
+        assert_eq!(vec!["SCAN item", "CORRELATED SCALAR SUBQUERY 1", "  SEARCH picture USING INDEX sqlite_autoindex_picture_1 (hash=?)"], plan);
+    }
+
+    #[test]
+    fn full_table_scans_ignore_index_scans() {
+        let plan: Vec<String> = ["SCAN item", "SCAN TABLE picture AS p", "SCAN item USING INDEX idx_item_descr", "SCAN CONSTANT ROW", "SCAN 3 CONSTANT ROWS", "  SCAN item"]
+            .iter()
+            .map(|step| step.to_string())
+            .collect();
+        assert_eq!(vec!["item", "picture"], full_table_scans(&plan));
+    }
+}
@@ -1,29 +1,110 @@
+pub mod configuration {
+    pub mod app_config;
+}
 pub mod data_services {
+    pub mod custom_type_conversions;
+    pub mod database_blob;
+    pub mod database_health;
+    pub mod database_metrics;
+    pub mod database_options;
     pub mod database_service;
     pub mod database_service_base;
     pub mod database_service_error;
+    pub mod item_cache;
+    pub mod item_filter;
     pub mod item_storage_service;
+    pub mod item_validation;
+    pub mod picture_processing;
+    pub mod query_tracing;
+    pub mod retry_policy;
+    pub mod slow_query_log;
 }
 pub mod models {
     pub mod item;
+    pub mod item_audit;
+    pub mod money;
 }
 
-use crate::models::item::Item;
+use crate::configuration::app_config::AppConfig;
+use crate::models::item::{Item, ItemChanges};
+use crate::data_services::database_metrics::DatabaseMetrics;
 use crate::data_services::database_service::DatabaseService;
+use crate::data_services::database_service_error::DatabaseServiceError;
+use crate::data_services::item_cache::{CachedItemStorageService, ItemCacheOptions};
+use crate::data_services::item_filter::ItemFilter;
 use crate::data_services::item_storage_service::ItemStorageService;
+use crate::data_services::picture_processing::{render_picture_as_text, PictureLimits};
 use std::env;
-use dotenv::dotenv;
-use std::io::BufRead;
+use std::path::Path;
+use std::sync::Arc;
+use std::io::{BufRead, IsTerminal};
+use tokio::io::AsyncWriteExt;
+use tracing_subscriber::EnvFilter;
 
 #[tokio::main]
 async fn main() {
-    dotenv().ok();
-    let db_url = &env::var("DATABASE_URL").unwrap();
-    let mut db_service = DatabaseService::new(db_url);
-    let _ = db_service.connect().await
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (config, args) = match AppConfig::load(&args) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            if e.field_errors().is_empty() {
+                eprintln!("Unable to load the configuration : {}", e);
+            } else {
+                eprintln!("Unable to load the configuration :");
+                for field_error in e.field_errors() {
+                    eprintln!("  {}", field_error);
+                }
+            }
+            return;
+        }
+    };
+    // After the configuration, which loads .env. Database operations are
+    // traced under sqlxusage::db, e.g. RUST_LOG=sqlxusage::db=debug shows
+    // every query
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("warn")))
+        .with_writer(std::io::stderr)
+        .init();
+    let mut db_service = DatabaseService::with_options(config.database_url.as_str(), config.database_options.clone());
+    let metrics = Arc::new(DatabaseMetrics::new());
+    db_service.set_metrics(metrics.clone());
+    db_service.connect().await
         .unwrap_or_else(|e| { eprintln!("Unable to connect to the database : {}", e);});
 
+    if args.first().is_some_and(|command| command == "health") {
+        let health = db_service.health().await;
+        match &args[1..] {
+            [format] if format == "json" => println!("{}", health.to_json()),
+            _ => println!("{}", health)
+        }
+        if !health.reachable {
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let mut item_service = ItemStorageService::new(Box::new(db_service));
+    let actor = config.actor.clone()
+        .unwrap_or_else(|| env::var("USER").unwrap_or_else(|_| "demo".to_owned()));
+    item_service.set_actor(actor.as_str());
+    if let Some(trash_retention) = config.trash_retention {
+        item_service.set_trash_retention(trash_retention);
+    }
+    if let Some(item_validator) = config.item_validator {
+        item_service.set_item_validator(item_validator);
+    }
+    item_service.migrate_legacy_pictures().await
+        .unwrap_or_else(|e| { eprintln!("Unable to migrate the legacy pictures : {}", e); 0});
+    item_service.delete_pending_pictures().await
+        .unwrap_or_else(|e| { eprintln!("Unable to delete the unfinished pictures : {}", e); 0});
+
+    if !args.is_empty() {
+        run_picture_command(&item_service, &args).await;
+        return;
+    }
+
+    // Repeated reads of the same items are served from memory
+    let cached_items = CachedItemStorageService::new(item_service.clone(), ItemCacheOptions::default());
 
     println!("Here is a sample database operations using sqlx");
     // Add an item
@@ -31,7 +112,8 @@ async fn main() {
     println!("Description: Test1");
     println!("Amount: 2.34");
     println!("Active: true");
-    println!("Picture: An ascii art :)\n");
+    println!("Picture: An ascii art :)");
+    println!("Attributes: {{\"color\": \"red\"}}\n");
     println!("Press enter to proceed...");
     wait_for_enter();
     let mut item = Item::new(-1, "Test1", 2.34, true, Some(vec![10, 32, 95, 95,
@@ -40,6 +122,7 @@ async fn main() {
 32, 32, 47, 10, 32, 124, 32, 32, 32, 111, 32, 32, 32, 111, 32, 32, 32, 124, 10,
 32, 92, 32, 32, 46, 45, 39, 39, 39, 45, 46, 32, 32, 47, 10, 32, 32, 39, 45, 92,
 95, 95, 89, 95, 95, 47, 45, 39, 10, 32, 32, 32, 32, 32, 96, 45, 45, 45, 96]));
+    item.set_attribute("color", "red").unwrap();
     let new_item_id = item_service.add_item(&item).await
         .unwrap_or_else(|e| { eprintln!("Error while adding the item : {}", e); -1});
     if new_item_id == -1 {
@@ -51,7 +134,49 @@ async fn main() {
     // Showing the current rows
     println!("Press enter to show the database item table rows...");
     wait_for_enter();
-    print_rows(&mut item_service).await;
+    print_rows(&item_service).await;
+    wait_for_enter();
+
+    // Filter the items on an attribute
+    println!("Press enter to show the red items (filter: attributes.color = 'red')...");
+    wait_for_enter();
+    let red_filter = ItemFilter::parse("attributes.color = 'red'").unwrap();
+    let red_items = cached_items.get_items(&red_filter).await
+        .unwrap_or_else(|e| { eprintln!("Error while getting rows : {}", e); vec![]});
+    for red_item in red_items {
+        print_item(&red_item);
+    }
+    wait_for_enter();
+
+    // Bulk operations
+    println!("Adding 3 items in one batch, then deactivating those below 1.00 and trashing them all");
+    println!("Press enter to proceed...");
+    wait_for_enter();
+    let bulk_items = vec![
+        Item::new(-1, "Bulk1", 0.5, true, None),
+        Item::new(-1, "Bulk2", 0.75, true, None),
+        Item::new(-1, "Bulk3", 3.0, true, None)
+    ];
+    match item_service.add_items(&bulk_items).await {
+        Ok(results) => for result in results {
+            match result {
+                Ok(item_id) => println!("Item added with id {}", item_id),
+                Err(e) => println!("Item refused: {}", e)
+            }
+        },
+        Err(e) => eprintln!("Error while adding the items : {}", e)
+    }
+    let mut deactivation = ItemChanges::default();
+    deactivation.set_active(false);
+    let cheap_filter = ItemFilter::parse("descr LIKE 'Bulk%' AND amount < 1").unwrap();
+    match item_service.update_items_where(&cheap_filter, &deactivation).await {
+        Ok(count) => println!("Items deactivated: {}", count),
+        Err(e) => eprintln!("Error while deactivating the items : {}", e)
+    }
+    match item_service.delete_items_where(&ItemFilter::parse("descr LIKE 'Bulk%'").unwrap()).await {
+        Ok(count) => println!("Items moved to the trash: {}\n", count),
+        Err(e) => eprintln!("Error while deleting the items : {}", e)
+    }
     wait_for_enter();
 
     // Update an item
@@ -64,7 +189,7 @@ async fn main() {
     item.set_descr("Test2");
     item.set_amount(1.45);
     item.set_active(false);
-    item.set_picture(&vec![10, 32, 32, 32, 32, 92, 92, 95, 47, 47, 10, 32, 32,
+    item.set_picture(&[10, 32, 32, 32, 32, 92, 92, 95, 47, 47, 10, 32, 32,
                      32, 95, 95, 47, 34, 46, 10, 32, 32, 47, 95, 95, 32, 124,
                      10, 32, 32, 124, 124, 32, 124, 124]);
     wait_for_enter();
@@ -72,31 +197,149 @@ async fn main() {
         .unwrap_or_else(|e| { eprintln!("{}", e); 0});
     println!("Rows affected: {}\n", rows_affected);
 
+    // Updating again with the version read before the first update fails
+    println!("Updating the item again with the outdated version {}", item.get_version());
+    println!("Press enter to proceed...");
+    wait_for_enter();
+    match item_service.update_item(&item).await {
+        Ok(rows_affected) => println!("Rows affected: {}\n", rows_affected),
+        Err(e) => println!("Update refused: {}\n", e)
+    }
+
     // Showing the current rows
     println!("Press enter to show the database item table rows...");
     wait_for_enter();
-    print_rows(&mut item_service).await;
+    print_rows(&item_service).await;
     wait_for_enter();
 
+    // Stream a picture
+    println!("Streaming a new picture to the item with id {} in chunks", new_item_id);
+    println!("Press enter to proceed...");
+    wait_for_enter();
+    let streamed_picture = b"\n  (\\_/)\n  (o.o)\n  (> <)";
+    match stream_picture(&item_service, new_item_id, streamed_picture).await {
+        Ok(picture_hash) => println!("Picture stored with hash {}\n", picture_hash),
+        Err(e) => eprintln!("Error while streaming the picture : {}", e)
+    }
+
+    // Showing the current rows
+    println!("Press enter to show the database item table rows...");
+    wait_for_enter();
+    print_rows(&item_service).await;
+    wait_for_enter();
+
+    // Patch an item
+    println!("Reactivating the item with id {} without loading it", new_item_id);
+    println!("Press enter to proceed...");
+    wait_for_enter();
+    let mut changes = ItemChanges::default();
+    changes.set_active(true);
+    rows_affected = item_service.patch_item(new_item_id, &changes).await
+        .unwrap_or_else(|e| { eprintln!("{}", e); 0});
+    println!("Rows affected: {}\n", rows_affected);
+
     // Delete an item
-    println!("Deleting the itemwith id {}", new_item_id);
+    println!("Moving the item with id {} to the trash", new_item_id);
     println!("Press enter to proceed...");
     wait_for_enter();
-    rows_affected = item_service.delete_item(new_item_id).await
+    let current_version = match cached_items.get_item(new_item_id).await {
+        Ok(Some(current_item)) => current_item.get_version(),
+        Ok(None) => item.get_version(),
+        Err(e) => { eprintln!("Error while getting the item : {}", e); item.get_version() }
+    };
+    rows_affected = item_service.delete_item(new_item_id, current_version).await
         .unwrap_or_else(|e| { eprintln!("{}", e); 0});
     println!("Rows affected: {}\n", rows_affected);
 
     // Showing the current rows
     println!("Press enter to show the database item table rows...");
     wait_for_enter();
-    print_rows(&mut item_service).await;
+    print_rows(&item_service).await;
+    wait_for_enter();
+
+    // Showing the trash
+    println!("Press enter to show the trash...");
+    wait_for_enter();
+    let trashed_items = item_service.list_trash().await
+        .unwrap_or_else(|e| { eprintln!("Error while getting the trash : {}", e); vec![]});
+    for trashed_item in trashed_items {
+        println!("id: {}, descr: {}, deleted at: {}",
+                 trashed_item.get_id(),
+                 trashed_item.get_descr(),
+                 trashed_item.get_deleted_at().unwrap_or_default());
+    }
     wait_for_enter();
+
+    // Showing the item history
+    println!("Press enter to show the history of the item with id {}...", new_item_id);
+    wait_for_enter();
+    let history = item_service.item_history(new_item_id).await
+        .unwrap_or_else(|e| { eprintln!("Error while getting the history : {}", e); vec![]});
+    for (version, entry) in history.iter().enumerate() {
+        println!("version {}: {} by {} at {}, {} -> {}",
+                 version + 1,
+                 entry.action.as_str(),
+                 entry.actor,
+                 entry.changed_at,
+                 serde_json::Value::Object(entry.old_values.clone()),
+                 serde_json::Value::Object(entry.new_values.clone()));
+    }
+    wait_for_enter();
+
+    // Showing the metrics
+    println!("Press enter to show the database metrics of the demo...");
+    wait_for_enter();
+    print!("{}", metrics.render_prometheus());
+    let cache_stats = cached_items.stats();
+    println!("item cache: {} hits, {} misses ({:.0}% hit ratio), {} entries",
+             cache_stats.hits,
+             cache_stats.misses,
+             cache_stats.hit_ratio() * 100.0,
+             cache_stats.entries);
 }
 
-async fn print_rows(item_service: &mut ItemStorageService) {
-    let items = item_service.get_all_items().await
+async fn stream_picture(item_service: &ItemStorageService,
+                        item_id: i64,
+                        picture: &[u8]) -> Result<String, DatabaseServiceError> {
+    let mut writer = item_service.open_picture_writer(item_id, picture.len() as u64).await?;
+    for chunk in picture.chunks(8) {
+        writer.write_all(chunk).await
+            .map_err(|e| DatabaseServiceError::new(e.to_string().as_str()))?;
+    }
+    writer.finish().await
+}
+
+// Usage:
+//   import-picture <item id> <image file>
+//   show-picture <picture hash> [width]
+async fn run_picture_command(item_service: &ItemStorageService, args: &[String]) {
+    match args {
+        [command, item_id, file] if command == "import-picture" => {
+            let item_id = match item_id.parse::<i64>() {
+                Ok(item_id) => item_id,
+                Err(_) => { eprintln!("The item id {} is not valid", item_id); return; }
+            };
+            match item_service.import_picture_file(item_id, Path::new(file)).await {
+                Ok(picture_hash) => println!("Picture imported with hash {}", picture_hash),
+                Err(e) => eprintln!("Error while importing the picture : {}", e)
+            }
+        },
+        [command, picture_hash, width @ ..] if command == "show-picture" && width.len() <= 1 => {
+            let width = width.first().and_then(|w| w.parse::<u32>().ok()).unwrap_or(PICTURE_WIDTH);
+            match item_service.get_picture(picture_hash).await {
+                Ok(Some(picture)) => println!("{}", picture_as_text(&picture, width)),
+                Ok(None) => eprintln!("The picture {} does not exist", picture_hash),
+                Err(e) => eprintln!("Error while getting the picture : {}", e)
+            }
+        },
+        _ => eprintln!("Usage: sqlxusage [import-picture <item id> <image file> | show-picture <picture hash> [width] | health [json]]")
+    }
+}
+
+async fn print_rows(item_service: &ItemStorageService) {
+    let items = item_service.get_all_items_with_pictures().await
         .unwrap_or_else(|e| { eprintln!("Error while getting rows : {}", e); vec![]});
-    if items.len() == 0 {
+    if items.is_empty() {
         println!("No row to display!");
     }
     for item in items {
@@ -105,13 +348,24 @@ async fn print_rows(item_service: &mut ItemStorageService) {
 }
 
 fn print_item(item: &Item) {
-    println!("id: {}, descr: {}, amount: {}, active: {}",
+    println!("id: {}, descr: {}, amount: {}, active: {}, attributes: {}",
              item.get_id(),
              item.get_descr(),
-             item.get_amount(),
-             item.get_active());
-    println!("picture: \n{}", String::from_utf8(item.get_picture().to_vec()).unwrap());
+             item.get_money(),
+             item.get_active(),
+             serde_json::Value::Object(item.get_attributes().clone()));
+    if item.get_picture().is_empty() {
+        println!("picture: none");
+    } else {
+        println!("picture: \n{}", picture_as_text(item.get_picture(), PICTURE_WIDTH));
+    }
+}
+
+const PICTURE_WIDTH: u32 = 60;
 
+fn picture_as_text(picture: &[u8], width: u32) -> String {
+    render_picture_as_text(picture, width, std::io::stdout().is_terminal(), &PictureLimits::default())
+        .unwrap_or_else(|e| format!("<{}>", e))
 }
 
 fn wait_for_enter() {
@@ -1,20 +1,67 @@
-#[derive(Debug)]
+use std::collections::HashSet;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use crate::models::money::{Money, DEFAULT_CURRENCY};
+
+pub type ItemAttributes = Map<String, Value>;
+
+// Fields of an item that can be changed after it is stored
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ItemField {
+    Descr,
+    // Amount and currency
+    Amount,
+    Active,
+    Picture,
+    Attributes
+}
+
+impl ItemField {
+    pub const ALL: [ItemField; 5] = [ItemField::Descr, ItemField::Amount, ItemField::Active, ItemField::Picture, ItemField::Attributes];
+}
+
+#[derive(Clone, Debug)]
 pub struct Item {
     id: i64,
     descr: String,
-    amount: f64,
+    amount: Money,
+    // Value given to new or set_amount that no amount can hold (NaN,
+    // infinite or out of range), kept so that validation can report it
+    rejected_amount: Option<f64>,
     active: bool,
-    picture: Vec<u8>
+    picture: Vec<u8>,
+    // SHA-256 of the picture, which is the key of the picture in storage.
+    // It can be set while the picture bytes are not loaded.
+    picture_hash: Option<String>,
+    attributes: ItemAttributes,
+    // UTC date and time ('YYYY-MM-DD HH:MM:SS') the item was moved to the
+    // trash, None for live items
+    deleted_at: Option<String>,
+    // Incremented on every change of the stored item, starting at 1
+    version: i64,
+    // Fields changed through the setters since the item was read from
+    // storage. All the fields of a new item are dirty.
+    dirty_fields: HashSet<ItemField>
 }
 
 impl Item {
+    // The amount is taken in DEFAULT_CURRENCY, see set_amount.
     pub fn new(id: i64, descr: &str, amount: f64, active: bool, picture: Option<Vec<u8>>) -> Self {
+        let picture = picture.unwrap_or_default();
         Item {
             id,
             descr: descr.to_owned(),
-            amount,
+            amount: Money::from_f64(amount, DEFAULT_CURRENCY).unwrap_or(Money::zero(DEFAULT_CURRENCY)),
+            rejected_amount: Money::from_f64(amount, DEFAULT_CURRENCY).is_none().then_some(amount),
             active,
-            picture: picture.unwrap_or_default()
+            picture_hash: compute_picture_hash(&picture),
+            picture,
+            attributes: ItemAttributes::new(),
+            deleted_at: None,
+            version: 1,
+            dirty_fields: ItemField::ALL.into_iter().collect()
         }
     }
 
@@ -26,7 +73,13 @@ impl Item {
         self.descr.as_str()
     }
 
+    // Nearest f64 of the amount, kept for backwards compatibility. Returns
+    // the rejected value when the last one set could not be held.
     pub fn get_amount(&self) -> f64 {
+        self.rejected_amount.unwrap_or(self.amount.to_f64())
+    }
+
+    pub fn get_money(&self) -> Money {
         self.amount
     }
 
@@ -38,23 +91,197 @@ impl Item {
         self.picture.as_ref()
     }
 
+    pub fn get_picture_hash(&self) -> Option<&str> {
+        self.picture_hash.as_deref()
+    }
+
+    pub fn has_picture(&self) -> bool {
+        self.picture_hash.is_some()
+    }
+
+    // True when the item has a picture whose bytes were not fetched.
+    pub fn is_picture_loaded(&self) -> bool {
+        self.picture_hash.is_none() || !self.picture.is_empty()
+    }
+
+    pub fn get_attributes(&self) -> &ItemAttributes {
+        &self.attributes
+    }
+
+    // Returns None when the attribute is absent or does not deserialize to T.
+    pub fn get_attribute<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        self.attributes.get(key)
+            .and_then(|value| serde_json::from_value(value.clone()).ok())
+    }
+
+    pub fn get_deleted_at(&self) -> Option<&str> {
+        self.deleted_at.as_deref()
+    }
+
+    pub fn is_deleted(&self) -> bool {
+        self.deleted_at.is_some()
+    }
+
+    pub fn get_version(&self) -> i64 {
+        self.version
+    }
+
+    pub fn is_dirty(&self, field: ItemField) -> bool {
+        self.dirty_fields.contains(&field)
+    }
+
+    pub fn get_dirty_fields(&self) -> &HashSet<ItemField> {
+        &self.dirty_fields
+    }
+
+    // Marks the item as matching its stored state.
+    pub fn clear_dirty_fields(&mut self) {
+        self.dirty_fields.clear();
+    }
+
     pub fn set_id(&mut self, id: i64) {
         self.id = id;
     }
 
     pub fn set_descr(&mut self, value: &str) {
         self.descr = value.to_owned();
+        self.dirty_fields.insert(ItemField::Descr);
     }
 
+    // Rounds the value to the minor unit of the current currency. Values
+    // that no amount can hold (NaN, infinite or out of range) set the amount
+    // to zero and are reported by has_valid_amount.
     pub fn set_amount(&mut self, value: f64) {
+        let currency = self.amount.currency();
+        let amount = Money::from_f64(value, currency);
+        self.rejected_amount = amount.is_none().then_some(value);
+        self.amount = amount.unwrap_or(Money::zero(currency));
+        self.dirty_fields.insert(ItemField::Amount);
+    }
+
+    pub fn has_valid_amount(&self) -> bool {
+        self.rejected_amount.is_none()
+    }
+
+    pub fn set_money(&mut self, value: Money) {
         self.amount = value;
+        self.rejected_amount = None;
+        self.dirty_fields.insert(ItemField::Amount);
     }
 
     pub fn set_active(&mut self, value: bool) {
         self.active = value;
+        self.dirty_fields.insert(ItemField::Active);
     }
 
-    pub fn set_picture(&mut self, value: &Vec<u8>) {
+    pub fn set_picture(&mut self, value: &[u8]) {
         self.picture = value.to_vec();
+        self.picture_hash = compute_picture_hash(value);
+        self.dirty_fields.insert(ItemField::Picture);
+    }
+
+    // References a stored picture without holding its bytes.
+    pub fn set_picture_reference(&mut self, hash: Option<&str>) {
+        self.picture = vec![];
+        self.picture_hash = hash.map(|h| h.to_owned());
+        self.dirty_fields.insert(ItemField::Picture);
+    }
+
+    pub fn set_attributes(&mut self, value: ItemAttributes) {
+        self.attributes = value;
+        self.dirty_fields.insert(ItemField::Attributes);
     }
+
+    pub fn set_attribute<T: Serialize>(&mut self, key: &str, value: T) -> Result<(), serde_json::Error> {
+        self.attributes.insert(key.to_owned(), serde_json::to_value(value)?);
+        self.dirty_fields.insert(ItemField::Attributes);
+        Ok(())
+    }
+
+    pub fn remove_attribute(&mut self, key: &str) -> Option<Value> {
+        let retval = self.attributes.remove(key);
+        if retval.is_some() {
+            self.dirty_fields.insert(ItemField::Attributes);
+        }
+        retval
+    }
+
+    pub fn set_deleted_at(&mut self, value: Option<&str>) {
+        self.deleted_at = value.map(|v| v.to_owned());
+    }
+
+    pub fn set_version(&mut self, value: i64) {
+        self.version = value;
+    }
+}
+
+// Field values to change on a stored item, see
+// ItemStorageService::patch_item. Fields left to None are not changed.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ItemChanges {
+    descr: Option<String>,
+    amount: Option<Money>,
+    active: Option<bool>,
+    // An empty picture removes the picture
+    picture: Option<Vec<u8>>,
+    attributes: Option<ItemAttributes>
+}
+
+impl ItemChanges {
+    pub fn set_descr(&mut self, value: &str) {
+        self.descr = Some(value.to_owned());
+    }
+
+    pub fn set_money(&mut self, value: Money) {
+        self.amount = Some(value);
+    }
+
+    pub fn set_active(&mut self, value: bool) {
+        self.active = Some(value);
+    }
+
+    pub fn set_picture(&mut self, value: &[u8]) {
+        self.picture = Some(value.to_vec());
+    }
+
+    pub fn set_attributes(&mut self, value: ItemAttributes) {
+        self.attributes = Some(value);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == ItemChanges::default()
+    }
+
+    // Applies the changes through the item setters, which marks the changed
+    // fields as dirty.
+    pub fn apply_to(&self, item: &mut Item) {
+        if let Some(descr) = &self.descr {
+            item.set_descr(descr);
+        }
+        if let Some(amount) = self.amount {
+            item.set_money(amount);
+        }
+        if let Some(active) = self.active {
+            item.set_active(active);
+        }
+        if let Some(picture) = &self.picture {
+            item.set_picture(picture);
+        }
+        if let Some(attributes) = &self.attributes {
+            item.set_attributes(attributes.clone());
+        }
+    }
+}
+
+pub fn compute_picture_hash(picture: &[u8]) -> Option<String> {
+    if picture.is_empty() {
+        return None;
+    }
+    Some(format_picture_hash(&Sha256::digest(picture)))
+}
+
+pub fn format_picture_hash(digest: &[u8]) -> String {
+    digest.iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
 }
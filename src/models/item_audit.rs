@@ -0,0 +1,134 @@
+use serde_json::{Map, Value};
+use crate::models::item::{Item, ItemField};
+
+// Field values of an item keyed by field name, as recorded in the audit log
+pub type ItemFields = Map<String, Value>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ItemAuditAction {
+    Insert,
+    Update,
+    Delete,
+    Restore,
+    Purge
+}
+
+impl ItemAuditAction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ItemAuditAction::Insert => "insert",
+            ItemAuditAction::Update => "update",
+            ItemAuditAction::Delete => "delete",
+            ItemAuditAction::Restore => "restore",
+            ItemAuditAction::Purge => "purge"
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        [ItemAuditAction::Insert, ItemAuditAction::Update, ItemAuditAction::Delete, ItemAuditAction::Restore, ItemAuditAction::Purge]
+            .into_iter()
+            .find(|action| action.as_str() == value)
+    }
+}
+
+// One change of an item. old_values and new_values only hold the fields
+// that changed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ItemAuditEntry {
+    pub id: i64,
+    pub item_id: i64,
+    pub action: ItemAuditAction,
+    pub actor: String,
+    // UTC date and time ('YYYY-MM-DD HH:MM:SS') of the change
+    pub changed_at: String,
+    pub old_values: ItemFields,
+    pub new_values: ItemFields
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FieldChange {
+    pub field: String,
+    pub old_value: Value,
+    pub new_value: Value
+}
+
+pub fn item_fields(item: &Item) -> ItemFields {
+    let mut retval = ItemFields::new();
+    retval.insert("descr".to_owned(), Value::from(item.get_descr()));
+    // The exact decimal amount, a JSON number could lose precision
+    retval.insert("amount".to_owned(), Value::from(item.get_money().to_decimal_string()));
+    retval.insert("currency".to_owned(), Value::from(item.get_money().currency().code()));
+    retval.insert("active".to_owned(), Value::from(item.get_active()));
+    retval.insert("picture_hash".to_owned(), Value::from(item.get_picture_hash()));
+    retval.insert("attributes".to_owned(), Value::Object(item.get_attributes().clone()));
+    retval
+}
+
+// The fields of updated_item for its dirty fields and of previous_item for
+// the others, i.e. the stored item after a partial update.
+pub fn merged_item_fields(previous_item: &Item, updated_item: &Item) -> ItemFields {
+    let mut retval = item_fields(previous_item);
+    let updated_fields = item_fields(updated_item);
+    for field in updated_item.get_dirty_fields() {
+        for key in audit_keys(*field) {
+            if let Some(value) = updated_fields.get(*key) {
+                retval.insert((*key).to_owned(), value.clone());
+            }
+        }
+    }
+    retval
+}
+
+fn audit_keys(field: ItemField) -> &'static [&'static str] {
+    match field {
+        ItemField::Descr => &["descr"],
+        ItemField::Amount => &["amount", "currency"],
+        ItemField::Active => &["active"],
+        ItemField::Picture => &["picture_hash"],
+        ItemField::Attributes => &["attributes"]
+    }
+}
+
+// Returns the old and new values of the fields that differ, a field missing
+// on one side being null.
+pub fn diff_fields(old_fields: &ItemFields, new_fields: &ItemFields) -> (ItemFields, ItemFields) {
+    let mut old_values = ItemFields::new();
+    let mut new_values = ItemFields::new();
+    for field in old_fields.keys().chain(new_fields.keys()) {
+        let old_value = old_fields.get(field).cloned().unwrap_or(Value::Null);
+        let new_value = new_fields.get(field).cloned().unwrap_or(Value::Null);
+        if old_value != new_value {
+            old_values.insert(field.to_owned(), old_value);
+            new_values.insert(field.to_owned(), new_value);
+        }
+    }
+    (old_values, new_values)
+}
+
+// Versions are numbered from 1 in the order of the history entries. The
+// state of the item at a version is the result of applying the changes of
+// all the entries up to it.
+pub fn item_fields_at_version(history: &[ItemAuditEntry], version: usize) -> Option<ItemFields> {
+    if version == 0 || version > history.len() {
+        return None;
+    }
+    let mut retval = ItemFields::new();
+    for entry in &history[..version] {
+        for (field, value) in entry.new_values.iter() {
+            retval.insert(field.to_owned(), value.clone());
+        }
+    }
+    Some(retval)
+}
+
+pub fn diff_versions(history: &[ItemAuditEntry], from_version: usize, to_version: usize) -> Option<Vec<FieldChange>> {
+    let (old_values, new_values) = diff_fields(&item_fields_at_version(history, from_version)?,
+                                               &item_fields_at_version(history, to_version)?);
+    Some(old_values.into_iter()
+        .map(|(field, old_value)| FieldChange {
+            new_value: new_values.get(&field).cloned().unwrap_or(Value::Null),
+            field,
+            old_value
+        })
+        .collect())
+}
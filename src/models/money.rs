@@ -0,0 +1,206 @@
+use std::fmt;
+
+// ISO 4217 alphabetic code
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Currency([u8; 3]);
+
+pub const DEFAULT_CURRENCY: Currency = Currency(*b"USD");
+
+// Currencies whose minor unit is not the hundredth of the major unit
+const ZERO_DECIMAL_CURRENCIES: [&str; 8] = ["CLP", "ISK", "JPY", "KRW", "PYG", "UGX", "VND", "XAF"];
+const THREE_DECIMAL_CURRENCIES: [&str; 7] = ["BHD", "IQD", "JOD", "KWD", "LYD", "OMR", "TND"];
+
+impl Currency {
+    pub fn new(code: &str) -> Option<Self> {
+        let bytes: [u8; 3] = code.as_bytes().try_into().ok()?;
+        if bytes.iter().all(|byte| byte.is_ascii_uppercase()) {
+            Some(Currency(bytes))
+        } else {
+            None
+        }
+    }
+
+    pub fn code(&self) -> &str {
+        std::str::from_utf8(&self.0).unwrap_or_default()
+    }
+
+    // Number of decimal places of the minor unit, e.g. 2 for cents
+    pub fn decimal_places(&self) -> u32 {
+        if ZERO_DECIMAL_CURRENCIES.contains(&self.code()) {
+            0
+        } else if THREE_DECIMAL_CURRENCIES.contains(&self.code()) {
+            3
+        } else {
+            2
+        }
+    }
+
+    fn minor_units_per_major(&self) -> i64 {
+        10_i64.pow(self.decimal_places())
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+// An exact amount of money, counted in minor units of its currency
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Money {
+    minor_units: i64,
+    currency: Currency
+}
+
+impl Money {
+    pub fn new(minor_units: i64, currency: Currency) -> Self {
+        Money {
+            minor_units,
+            currency
+        }
+    }
+
+    pub fn zero(currency: Currency) -> Self {
+        Money::new(0, currency)
+    }
+
+    // Rounds half away from zero to the minor unit. The scaled value is first
+    // snapped to 6 decimal places so that 1.005 rounds to 1.01 although its
+    // binary value is slightly lower. Returns None for NaN, infinite or out
+    // of range values.
+    pub fn from_f64(value: f64, currency: Currency) -> Option<Self> {
+        let scaled = value * currency.minor_units_per_major() as f64;
+        let rounded = ((scaled * 1e6).round() / 1e6).round();
+        if !rounded.is_finite() || rounded.abs() >= i64::MAX as f64 {
+            return None;
+        }
+        Some(Money::new(rounded as i64, currency))
+    }
+
+    // Parses a decimal amount such as "-12.34" exactly. Amounts with more
+    // decimal places than the currency minor unit are rejected.
+    pub fn parse(text: &str, currency: Currency) -> Option<Self> {
+        let (negative, digits) = match text.trim().strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, text.trim())
+        };
+        let (major, minor) = digits.split_once('.').unwrap_or((digits, ""));
+        let decimal_places = currency.decimal_places() as usize;
+        if major.is_empty() || minor.len() > decimal_places || (digits.contains('.') && minor.is_empty())
+            || !major.chars().chain(minor.chars()).all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        let minor = format!("{:0<width$}", minor, width = decimal_places);
+        let minor_units = major.parse::<i64>().ok()?
+            .checked_mul(currency.minor_units_per_major())?
+            .checked_add(if minor.is_empty() { 0 } else { minor.parse::<i64>().ok()? })?;
+        Some(Money::new(if negative { -minor_units } else { minor_units }, currency))
+    }
+
+    pub fn minor_units(&self) -> i64 {
+        self.minor_units
+    }
+
+    pub fn currency(&self) -> Currency {
+        self.currency
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.minor_units < 0
+    }
+
+    // Nearest f64 of the amount, for callers and columns that still expect
+    // a floating point number
+    pub fn to_f64(&self) -> f64 {
+        self.minor_units as f64 / self.currency.minor_units_per_major() as f64
+    }
+
+    // Returns None when the currencies differ or the sum overflows.
+    pub fn checked_add(&self, other: Money) -> Option<Money> {
+        if self.currency != other.currency {
+            return None;
+        }
+        Some(Money::new(self.minor_units.checked_add(other.minor_units)?, self.currency))
+    }
+
+    // The amount as a decimal number without currency, e.g. "-12.34"
+    pub fn to_decimal_string(&self) -> String {
+        let per_major = self.currency.minor_units_per_major().unsigned_abs();
+        let sign = if self.is_negative() { "-" } else { "" };
+        let major = self.minor_units.unsigned_abs() / per_major;
+        let minor = self.minor_units.unsigned_abs() % per_major;
+        match self.currency.decimal_places() {
+            0 => format!("{}{}", sign, major),
+            decimal_places => format!("{}{}.{:0width$}", sign, major, minor, width = decimal_places as usize)
+        }
+    }
+}
+
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.to_decimal_string(), self.currency)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn currency(code: &str) -> Currency {
+        Currency::new(code).unwrap()
+    }
+
+    #[test]
+    fn currency_new_with_invalid_code_return_none() {
+        assert_eq!(None, Currency::new("usd"));
+        assert_eq!(None, Currency::new("EURO"));
+        assert_eq!("EUR", currency("EUR").code());
+    }
+
+    #[test]
+    fn money_from_f64_round_half_away_from_zero() {
+        assert_eq!(101, Money::from_f64(1.005, DEFAULT_CURRENCY).unwrap().minor_units());
+        assert_eq!(-235, Money::from_f64(-2.345, DEFAULT_CURRENCY).unwrap().minor_units());
+        assert_eq!(123, Money::from_f64(1.2345, DEFAULT_CURRENCY).unwrap().minor_units());
+        assert_eq!(2, Money::from_f64(1.5, currency("JPY")).unwrap().minor_units());
+    }
+
+    #[test]
+    fn money_from_f64_with_nan_or_infinite_return_none() {
+        assert_eq!(None, Money::from_f64(f64::NAN, DEFAULT_CURRENCY));
+        assert_eq!(None, Money::from_f64(f64::INFINITY, DEFAULT_CURRENCY));
+        assert_eq!(None, Money::from_f64(1e30, DEFAULT_CURRENCY));
+    }
+
+    #[test]
+    fn money_parse_return_exact_amount() {
+        assert_eq!(Some(Money::new(1234, DEFAULT_CURRENCY)), Money::parse("12.34", DEFAULT_CURRENCY));
+        assert_eq!(Some(Money::new(-50, DEFAULT_CURRENCY)), Money::parse("-0.5", DEFAULT_CURRENCY));
+        assert_eq!(Some(Money::new(12, currency("JPY"))), Money::parse("12", currency("JPY")));
+        assert_eq!(Some(Money::new(1500, currency("KWD"))), Money::parse("1.5", currency("KWD")));
+    }
+
+    #[test]
+    fn money_parse_with_invalid_text_return_none() {
+        assert_eq!(None, Money::parse("1.234", DEFAULT_CURRENCY));
+        assert_eq!(None, Money::parse("1.", DEFAULT_CURRENCY));
+        assert_eq!(None, Money::parse("abc", DEFAULT_CURRENCY));
+        assert_eq!(None, Money::parse("", DEFAULT_CURRENCY));
+        assert_eq!(None, Money::parse("1.5", currency("JPY")));
+    }
+
+    #[test]
+    fn money_display_return_amount_and_currency() {
+        assert_eq!("12.30 USD", Money::new(1230, DEFAULT_CURRENCY).to_string());
+        assert_eq!("-0.05 USD", Money::new(-5, DEFAULT_CURRENCY).to_string());
+        assert_eq!("1500 JPY", Money::new(1500, currency("JPY")).to_string());
+    }
+
+    #[test]
+    fn money_checked_add_with_other_currency_return_none() {
+        let sum = Money::new(10, DEFAULT_CURRENCY).checked_add(Money::new(20, DEFAULT_CURRENCY));
+        assert_eq!(Some(Money::new(30, DEFAULT_CURRENCY)), sum);
+        assert_eq!(None, Money::new(10, DEFAULT_CURRENCY).checked_add(Money::new(20, currency("EUR"))));
+    }
+}